@@ -0,0 +1,106 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use simplepir_rs::element::Element;
+use simplepir_rs::matrix::Matrix;
+
+/// The number of bytes of database scanned by a product with `db`
+fn db_bytes(db: &Matrix) -> u64 {
+    (db.num_vals() * std::mem::size_of::<u64>()) as u64
+}
+
+/// Matrix-vector products, which dominate the server's answer computation
+fn bench_mul_vec(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mul_vec");
+    for (name, q) in [("q=2^32", 1u64 << 32), ("q=3329", 3329)] {
+        let db = Matrix::gen_uniform_rand(q, 2048, 2048);
+        let query = Matrix::gen_uniform_rand(q, 1, 2048);
+        let mut out = vec![0u64; db.num_rows()];
+
+        group.throughput(Throughput::Bytes(db_bytes(&db)));
+        group.bench_function(BenchmarkId::new("2048x2048", name), |b| {
+            b.iter(|| db.mul_vec_into(query.data(), &mut out))
+        });
+    }
+    group.finish();
+}
+
+/// Matrix-matrix products, which dominate hint generation
+fn bench_mul(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mul");
+    group.sample_size(10);
+    for (name, q) in [("q=2^32", 1u64 << 32), ("q=3329", 3329)] {
+        let db = Matrix::gen_uniform_rand(q, 1024, 1024);
+        let a = Matrix::gen_uniform_rand(q, 1024, 64);
+        let mut out = Matrix::zeros(q, 1024, 64);
+
+        group.throughput(Throughput::Bytes(db_bytes(&db)));
+        group.bench_function(BenchmarkId::new("1024x1024 * 1024x64", name), |b| {
+            b.iter(|| db.mul_into(&a, &mut out))
+        });
+    }
+    group.finish();
+}
+
+/// A batch of queries, answered one at a time or as a single matrix-matrix product
+fn bench_batch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("batch");
+    let q = 1u64 << 32;
+    let db = Matrix::gen_uniform_rand(q, 2048, 2048);
+    for batch in [1, 8, 16, 32] {
+        let queries = Matrix::gen_uniform_rand(q, 2048, batch);
+        let separate: Vec<Vec<u64>> = (0..batch).map(|t| (0..2048).map(|k| queries[k][t]).collect()).collect();
+        let mut out = vec![0u64; db.num_rows()];
+        let mut batch_out = Matrix::zeros(q, 2048, batch);
+
+        group.throughput(Throughput::Bytes(db_bytes(&db) * batch as u64));
+        group.bench_function(BenchmarkId::new("separate", batch), |b| {
+            b.iter(|| {
+                for query in &separate {
+                    db.mul_vec_into(query, &mut out);
+                }
+            })
+        });
+        group.bench_function(BenchmarkId::new("batched", batch), |b| {
+            b.iter(|| db.mul_into(&queries, &mut batch_out))
+        });
+    }
+    group.finish();
+}
+
+/// The previous decomposition, which decomposes each entry into a newly allocated Vec
+fn decompose_per_element(m: &Matrix, base: u64, out: &mut Matrix) {
+    let num_digits = Element::num_digits(m.q(), base);
+    for i in 0..m.num_rows() {
+        for j in 0..m.num_cols() {
+            for (k, digit) in m.get(i, j).decomposed(base).into_iter().enumerate() {
+                out[i * num_digits + k][j] = digit;
+            }
+        }
+    }
+}
+
+/// Gadget decompositions, which DoublePIR runs over hint_s and every first-layer answer
+fn bench_decompose(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decompose");
+    let q = 1u64 << 32;
+    let m = Matrix::gen_uniform_rand(q, 1024, 256);
+    for (name, base) in [("base=2^8", 1u64 << 8), ("base=991", 991)] {
+        let mut out = Matrix::zeros(q, 1024 * Element::num_digits(q, base), 256);
+
+        group.throughput(Throughput::Bytes(db_bytes(&m)));
+        group.bench_function(BenchmarkId::new("per_element", name), |b| {
+            b.iter(|| decompose_per_element(&m, base, &mut out))
+        });
+        group.bench_function(BenchmarkId::new("decompose_into", name), |b| {
+            b.iter(|| m.decompose_into(base, &mut out))
+        });
+
+        let mut recomposed = Matrix::zeros(q, 1024, 256);
+        group.bench_function(BenchmarkId::new("recompose_into", name), |b| {
+            b.iter(|| out.recompose_into(base, &mut recomposed))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_mul_vec, bench_mul, bench_batch, bench_decompose);
+criterion_main!(benches);
@@ -0,0 +1,552 @@
+use crate::database::RecordLayout;
+use crate::error::{check_index, check_len, Error, Result};
+use crate::matrix::Matrix;
+use crate::params::{select_params, LweParams};
+use crate::seeded::{gen_seed_with_rng, Seed};
+use crate::simplepir::{
+    self, plan_shape, SimplePIRParams, SimplePirAnswer, SimplePirClient, SimplePirQuery, SimplePirQueryState,
+    SimplePirServer,
+};
+use rand::{rngs::StdRng, seq::index, CryptoRng, Rng, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+/// The number of buckets for each record of a batch
+pub const BUCKETS_PER_RECORD: f64 = 1.5;
+
+/// The number of candidate buckets of each record, as in the cuckoo hashing of most batch PIR
+/// schemes
+pub const DEFAULT_NUM_HASHES: usize = 3;
+
+/// The number of evictions after which cuckoo hashing a batch gives up
+const MAX_EVICTIONS: usize = 500;
+
+/// The parameters of batch PIR, which retrieves up to `batch_size` of `num_records` byte records
+/// at once.
+///
+/// Public hash functions choose `num_hashes` candidate buckets for each record, and the server
+/// stores a copy of the record in each of them. Every bucket is a SimplePIR database of its own.
+/// The client cuckoo hashes the records it wants so that each is in a different bucket, and sends
+/// one query to every bucket, so the server cannot tell which buckets hold them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchPirParams {
+    // The key of the hash functions which choose the buckets of each record
+    pub hash_seed: Seed,
+    pub num_records: usize,
+    // The size of each record in bytes
+    pub record_size: usize,
+    // The number of records retrieved at once, for which the number of buckets is chosen
+    pub batch_size: usize,
+    // The number of candidate buckets of each record
+    pub num_hashes: usize,
+    pub num_buckets: usize,
+    // The parameters shared by the SimplePIR database of every bucket, which are large enough for
+    // the fullest bucket
+    pub simplepir: SimplePIRParams,
+}
+
+impl BatchPirParams {
+    /// The distinct buckets which record `idx` is stored in
+    pub fn candidates(&self, idx: usize) -> Vec<usize> {
+        candidates(&self.hash_seed, self.num_buckets, self.num_hashes, idx)
+    }
+
+    /// The records stored in each bucket, in increasing order
+    pub fn buckets(&self) -> Vec<Vec<usize>> {
+        gen_buckets(&self.hash_seed, self.num_records, self.num_buckets, self.num_hashes)
+    }
+
+    /// The layout of the fullest bucket, or None if it does not fit in the database of a bucket,
+    /// which decoding checks so that clients cannot panic. The records are only hashed into
+    /// buckets once the databases could hold every copy of them, which bounds the work.
+    pub(crate) fn try_layout(&self) -> Option<RecordLayout> {
+        let SimplePIRParams { p, l, m, .. } = self.simplepir;
+        let records_per_col = RecordLayout::try_new(0, self.record_size, p, l)?.records_per_col;
+        let capacity = records_per_col.checked_mul(m)?.checked_mul(self.num_buckets)?;
+        if self.num_records.checked_mul(self.num_hashes)? > capacity {
+            return None;
+        }
+        try_bucket_layout(self, &self.buckets())
+    }
+}
+
+/// The number of buckets for batches of `batch_size` records, which is at least the number of
+/// candidate buckets of each record
+pub fn num_buckets(batch_size: usize, num_hashes: usize) -> usize {
+    ((batch_size as f64 * BUCKETS_PER_RECORD).ceil() as usize).max(num_hashes)
+}
+
+/// Generate parameters for retrieving batches of `batch_size` of `num_records` records of
+/// `record_size` bytes, with `num_hashes` candidate buckets for each record. Each bucket is a
+/// SimplePIR database with the LWE parameters `lwe`.
+pub fn gen_params_with(
+    num_records: usize,
+    record_size: usize,
+    batch_size: usize,
+    num_hashes: usize,
+    lwe: LweParams,
+) -> BatchPirParams {
+    gen_params_with_rng(&mut StdRng::from_entropy(), num_records, record_size, batch_size, num_hashes, lwe)
+}
+
+/// Generate parameters as in `gen_params_with`, drawing randomness from `rng`.
+pub fn gen_params_with_rng<R: RngCore + CryptoRng>(
+    rng: &mut R,
+    num_records: usize,
+    record_size: usize,
+    batch_size: usize,
+    num_hashes: usize,
+    lwe: LweParams,
+) -> BatchPirParams {
+    let (hash_seed, num_buckets, max_load) = gen_hashing(rng, num_records, batch_size, num_hashes);
+    let simplepir = gen_bucket_params(rng, max_load, record_size, lwe);
+
+    BatchPirParams { hash_seed, num_records, record_size, batch_size, num_hashes, num_buckets, simplepir }
+}
+
+/// Generate parameters as in `gen_params_with`, with at least `security_bits` bits of security and
+/// the largest plaintext modulus for which answers decrypt correctly. Returns None if the security
+/// level is not supported.
+pub fn gen_secure_params(
+    security_bits: u32,
+    num_records: usize,
+    record_size: usize,
+    batch_size: usize,
+    num_hashes: usize,
+) -> Option<BatchPirParams> {
+    gen_secure_params_with_rng(
+        &mut StdRng::from_entropy(),
+        security_bits,
+        num_records,
+        record_size,
+        batch_size,
+        num_hashes,
+    )
+}
+
+pub fn gen_secure_params_with_rng<R: RngCore + CryptoRng>(
+    rng: &mut R,
+    security_bits: u32,
+    num_records: usize,
+    record_size: usize,
+    batch_size: usize,
+    num_hashes: usize,
+) -> Option<BatchPirParams> {
+    let (hash_seed, num_buckets, max_load) = gen_hashing(rng, num_records, batch_size, num_hashes);
+    // A bucket has at most one column per record, so a plaintext modulus which is small enough
+    // for max_load columns is small enough for the planned shape
+    let lwe = select_params(security_bits, max_load)?;
+    let simplepir = gen_bucket_params(rng, max_load, record_size, lwe);
+
+    Some(BatchPirParams { hash_seed, num_records, record_size, batch_size, num_hashes, num_buckets, simplepir })
+}
+
+/// Choose the hash functions, and return their seed, the number of buckets and the number of
+/// records in the fullest bucket
+fn gen_hashing<R: RngCore + CryptoRng>(
+    rng: &mut R,
+    num_records: usize,
+    batch_size: usize,
+    num_hashes: usize,
+) -> (Seed, usize, usize) {
+    assert!(num_records > 0);
+    assert!(batch_size > 0);
+    assert!(num_hashes > 0);
+    let num_buckets = num_buckets(batch_size, num_hashes);
+    let hash_seed = gen_seed_with_rng(rng);
+    let buckets = gen_buckets(&hash_seed, num_records, num_buckets, num_hashes);
+    let max_load = buckets.iter().map(|b| b.len()).max().unwrap();
+    (hash_seed, num_buckets, max_load)
+}
+
+/// The SimplePIR parameters of a bucket of up to `max_load` records, shaped as `plan_shape` chooses
+fn gen_bucket_params<R: RngCore + CryptoRng>(
+    rng: &mut R,
+    max_load: usize,
+    record_size: usize,
+    lwe: LweParams,
+) -> SimplePIRParams {
+    let LweParams { n, q, p, std_dev } = lwe;
+    let shape = plan_shape(max_load, record_size, p, n, q);
+    simplepir::gen_params_with_rng(rng, shape.l, shape.m, n, q, p, std_dev)
+}
+
+fn candidates(hash_seed: &Seed, num_buckets: usize, num_hashes: usize, idx: usize) -> Vec<usize> {
+    // Each record has its own ChaCha20 stream, so its buckets can be found without hashing the
+    // others
+    let mut rng = ChaCha20Rng::from_seed(*hash_seed);
+    rng.set_stream(idx as u64);
+    let mut buckets = Vec::with_capacity(num_hashes);
+    while buckets.len() < num_hashes {
+        let b = rng.gen_range(0..num_buckets);
+        if !buckets.contains(&b) {
+            buckets.push(b);
+        }
+    }
+    buckets
+}
+
+fn gen_buckets(hash_seed: &Seed, num_records: usize, num_buckets: usize, num_hashes: usize) -> Vec<Vec<usize>> {
+    let mut buckets = vec![Vec::new(); num_buckets];
+    for idx in 0..num_records {
+        for b in candidates(hash_seed, num_buckets, num_hashes, idx) {
+            buckets[b].push(idx);
+        }
+    }
+    buckets
+}
+
+/// The layout of the records within each bucket, which is the same for every bucket
+fn bucket_layout(params: &BatchPirParams, buckets: &[Vec<usize>]) -> RecordLayout {
+    try_bucket_layout(params, buckets).expect("the fullest bucket must fit in the database of a bucket")
+}
+
+fn try_bucket_layout(params: &BatchPirParams, buckets: &[Vec<usize>]) -> Option<RecordLayout> {
+    let max_load = buckets.iter().map(|b| b.len()).max()?;
+    let layout = RecordLayout::try_new(max_load, params.record_size, params.simplepir.p, params.simplepir.l)?;
+    (layout.num_cols() <= params.simplepir.m).then_some(layout)
+}
+
+/// Place each of the distinct `items` in one of its candidate buckets, with at most one item per
+/// bucket, by cuckoo hashing. Returns the item in each bucket, or None if the items could not all
+/// be placed.
+fn cuckoo_insert<R: RngCore>(rng: &mut R, params: &BatchPirParams, items: &[usize]) -> Option<Vec<Option<usize>>> {
+    let mut table = vec![None; params.num_buckets];
+    'items: for &item in items {
+        let mut current = item;
+        let mut evicted_from = None;
+        for _ in 0..MAX_EVICTIONS {
+            let candidates = params.candidates(current);
+            if let Some(&b) = candidates.iter().find(|b| table[**b].is_none()) {
+                table[b] = Some(current);
+                continue 'items;
+            }
+            // Evict the item in a random candidate bucket, other than the one which current was
+            // just evicted from
+            let choices: Vec<usize> = candidates.into_iter().filter(|b| Some(*b) != evicted_from).collect();
+            if choices.is_empty() {
+                return None;
+            }
+            let b = choices[rng.gen_range(0..choices.len())];
+            current = table[b].replace(current).unwrap();
+            evicted_from = Some(b);
+        }
+        return None;
+    }
+    Some(table)
+}
+
+/// How likely retrieving a batch of `batch_size` records is to fail, either because the records
+/// cannot be placed into buckets or because an answer fails to decrypt
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BatchPirFailureReport {
+    // The number of random batches which were hashed into buckets
+    pub trials: usize,
+    // The number of those batches which could not be placed with one record per bucket
+    pub hashing_failures: usize,
+    // An upper bound on the probability that some record of a batch fails to decrypt
+    pub decryption: f64,
+}
+
+impl BatchPirFailureReport {
+    /// The fraction of trials in which hashing failed. If none did, the true probability is still
+    /// below 3 / trials with 95% confidence, not zero.
+    pub fn hashing(&self) -> f64 {
+        self.hashing_failures as f64 / self.trials as f64
+    }
+
+    /// The probability that retrieving a batch fails for either reason, by the union bound
+    pub fn total(&self) -> f64 {
+        (self.hashing() + self.decryption).min(1.0)
+    }
+}
+
+/// Estimate how likely retrieving a batch is to fail, by cuckoo hashing `trials` random batches of
+/// `batch_size` distinct records into buckets.
+pub fn failure_report(params: &BatchPirParams, trials: usize) -> BatchPirFailureReport {
+    failure_report_with_rng(&mut StdRng::from_entropy(), params, trials)
+}
+
+pub fn failure_report_with_rng<R: RngCore>(rng: &mut R, params: &BatchPirParams, trials: usize) -> BatchPirFailureReport {
+    assert!(trials > 0);
+    let batch_size = params.batch_size.min(params.num_records);
+    let hashing_failures = (0..trials)
+        .filter(|_| {
+            let batch = index::sample(rng, params.num_records, batch_size).into_vec();
+            cuckoo_insert(rng, params, &batch).is_none()
+        })
+        .count();
+
+    // Each record of a batch is recovered from its digits, each of which is one entry of an answer
+    let digits = RecordLayout::new(1, params.record_size, params.simplepir.p, params.simplepir.l).digits_per_record;
+    let entries = (params.batch_size * digits) as f64;
+    let decryption = (entries * simplepir::failure_probability(&params.simplepir)).min(1.0);
+
+    BatchPirFailureReport { trials, hashing_failures, decryption }
+}
+
+/// One SimplePIR query for every bucket
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchPirQuery(pub Vec<SimplePirQuery>);
+
+/// The answer of every bucket to a BatchPirQuery
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchPirAnswer(pub Vec<SimplePirAnswer>);
+
+/// What the client remembers about a batch query: which bucket serves each requested record, and
+/// the secret of the query sent to every bucket, real or dummy. Since it shows which records were
+/// requested, it must never leave the client.
+#[derive(Debug)]
+pub struct BatchPirState {
+    // The bucket from which each requested record is recovered, in the order requested
+    buckets: Vec<usize>,
+    // The state of the query to each bucket
+    queries: Vec<SimplePirQueryState>,
+}
+
+pub struct BatchPirServer {
+    params: BatchPirParams,
+    buckets: Vec<SimplePirServer>,
+}
+
+impl BatchPirServer {
+    /// Copy each record into each of its candidate buckets, and preprocess every bucket. There
+    /// must be `params.num_records` records of `params.record_size` bytes.
+    pub fn new(params: BatchPirParams, records: &[Vec<u8>]) -> Self {
+        assert_eq!(records.len(), params.num_records);
+        let contents = params.buckets();
+        let layout = bucket_layout(&params, &contents);
+        let SimplePIRParams { p, l, m, .. } = params.simplepir;
+
+        let buckets = contents
+            .iter()
+            .map(|bucket| {
+                let records: Vec<Vec<u8>> = bucket.iter().map(|idx| records[*idx].clone()).collect();
+                let db = RecordLayout { num_records: bucket.len(), ..layout }.pack(&records, p, l, m);
+                SimplePirServer::new(params.simplepir.clone(), &db)
+            })
+            .collect();
+
+        Self { params, buckets }
+    }
+
+    pub fn params(&self) -> &BatchPirParams {
+        &self.params
+    }
+
+    /// The hint of every bucket, which each client downloads once
+    pub fn hints(&self) -> Vec<Matrix> {
+        self.buckets.iter().map(|b| b.hint().clone()).collect()
+    }
+
+    pub fn answer(&self, query: &BatchPirQuery) -> BatchPirAnswer {
+        self.try_answer(query).unwrap()
+    }
+
+    /// Answer a query from an untrusted client, or reject it if it does not have a valid query for
+    /// every bucket
+    pub fn try_answer(&self, query: &BatchPirQuery) -> Result<BatchPirAnswer> {
+        check_len(self.buckets.len(), query.0.len())?;
+        let answers = self
+            .buckets
+            .iter()
+            .zip(&query.0)
+            .map(|(bucket, query)| bucket.try_answer(query))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(BatchPirAnswer(answers))
+    }
+}
+
+pub struct BatchPirClient {
+    params: BatchPirParams,
+    // The records in each bucket, in increasing order
+    contents: Vec<Vec<usize>>,
+    layout: RecordLayout,
+    buckets: Vec<SimplePirClient>,
+}
+
+impl BatchPirClient {
+    /// Create a client from the hint of every bucket. The client hashes every record to find which
+    /// buckets it is stored in, and where.
+    pub fn new(params: BatchPirParams, hints: Vec<Matrix>) -> Self {
+        assert_eq!(hints.len(), params.num_buckets);
+        let contents = params.buckets();
+        let layout = bucket_layout(&params, &contents);
+        let buckets = hints.into_iter().map(|hint| SimplePirClient::new(params.simplepir.clone(), hint)).collect();
+
+        Self { params, contents, layout, buckets }
+    }
+
+    pub fn params(&self) -> &BatchPirParams {
+        &self.params
+    }
+
+    /// Create a query for the records `indices`. Panics if the records cannot be placed into
+    /// buckets, which `failure_report` estimates the probability of; `try_query` returns an error
+    /// instead.
+    pub fn query(&self, indices: &[usize]) -> (BatchPirQuery, BatchPirState) {
+        self.query_with_rng(&mut StdRng::from_entropy(), indices)
+    }
+
+    pub fn query_with_rng<R: RngCore + CryptoRng>(&self, rng: &mut R, indices: &[usize]) -> (BatchPirQuery, BatchPirState) {
+        self.try_query_with_rng(rng, indices).unwrap()
+    }
+
+    /// Create a query as in `query`, or an error if any record is outside the database or the
+    /// records could not be placed into buckets
+    pub fn try_query(&self, indices: &[usize]) -> Result<(BatchPirQuery, BatchPirState)> {
+        self.try_query_with_rng(&mut StdRng::from_entropy(), indices)
+    }
+
+    fn try_query_with_rng<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        indices: &[usize],
+    ) -> Result<(BatchPirQuery, BatchPirState)> {
+        for idx in indices {
+            check_index(*idx, self.params.num_records)?;
+        }
+        // A record which is requested more than once only needs one bucket
+        let mut distinct = indices.to_vec();
+        distinct.sort_unstable();
+        distinct.dedup();
+        let table = cuckoo_insert(rng, &self.params, &distinct).ok_or(Error::HashingFailed)?;
+
+        // Query every bucket, including those holding no requested record, so that the server
+        // cannot tell them apart
+        let mut queries = Vec::with_capacity(self.params.num_buckets);
+        let mut states = Vec::with_capacity(self.params.num_buckets);
+        for (b, client) in self.buckets.iter().enumerate() {
+            let pos = table[b].map_or(0, |idx| self.contents[b].binary_search(&idx).unwrap());
+            let (query, state) = client.query_record_with_rng(rng, &self.layout, pos);
+            queries.push(query);
+            states.push(state);
+        }
+
+        let buckets = indices
+            .iter()
+            .map(|idx| table.iter().position(|item| *item == Some(*idx)).unwrap())
+            .collect();
+        Ok((BatchPirQuery(queries), BatchPirState { buckets, queries: states }))
+    }
+
+    /// Recover the records which were queried, in the order they were queried
+    pub fn recover(&self, state: &BatchPirState, answer: &BatchPirAnswer) -> Vec<Vec<u8>> {
+        self.try_recover(state, answer).unwrap()
+    }
+
+    /// Recover the records as in `recover`, or an error if the answer is malformed
+    pub fn try_recover(&self, state: &BatchPirState, answer: &BatchPirAnswer) -> Result<Vec<Vec<u8>>> {
+        check_len(state.queries.len(), answer.0.len())?;
+        state
+            .buckets
+            .iter()
+            .map(|b| self.buckets[*b].try_recover_record(&self.layout, &state.queries[*b], &answer.0[*b]))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{check_buckets, check_secure_params, gen_records, test_lwe};
+
+    fn gen_test_params(rng: &mut StdRng, num_records: usize, batch_size: usize, num_hashes: usize) -> BatchPirParams {
+        gen_params_with_rng(rng, num_records, 3, batch_size, num_hashes, test_lwe())
+    }
+
+    #[test]
+    fn test_buckets() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let params = gen_test_params(&mut rng, 100, 8, 3);
+        assert_eq!(params.num_buckets, 12);
+        assert_eq!(num_buckets(1, 3), 3);
+
+        let buckets = params.buckets();
+        assert_eq!(buckets.iter().map(|b| b.len()).sum::<usize>(), 100 * 3);
+        check_buckets(&buckets, |idx| params.candidates(idx));
+        // Every record is in all of its candidates
+        for idx in 0..100 {
+            let candidates = params.candidates(idx);
+            assert_eq!(candidates, params.candidates(idx));
+            assert_eq!(candidates.len(), 3);
+            assert!(candidates.iter().all(|b| buckets[*b].contains(&idx)));
+        }
+        // Every bucket fits in the database of the fullest one
+        let layout = bucket_layout(&params, &buckets);
+        assert!(layout.num_cols() <= params.simplepir.m);
+    }
+
+    #[test]
+    fn test_batch_pir() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let params = gen_test_params(&mut rng, 200, 8, 3);
+        let records = gen_records(200, 3);
+        let server = BatchPirServer::new(params.clone(), &records);
+        let client = BatchPirClient::new(params.clone(), server.hints());
+
+        // Full and partial batches, and records requested more than once
+        for indices in [vec![5, 17, 199, 0, 42, 8, 100, 63], vec![3], vec![], vec![9, 9, 10]] {
+            let (query, state) = client.query_with_rng(&mut rng, &indices);
+            assert_eq!(query.0.len(), params.num_buckets);
+            let answer = server.answer(&query);
+            let expected: Vec<Vec<u8>> = indices.iter().map(|idx| records[*idx].clone()).collect();
+            assert_eq!(client.recover(&state, &answer), expected);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let params = gen_test_params(&mut rng, 50, 4, 3);
+        let server = BatchPirServer::new(params.clone(), &gen_records(50, 3));
+        let client = BatchPirClient::new(params.clone(), server.hints());
+
+        assert_eq!(client.try_query(&[1, 50]).unwrap_err(), Error::IndexOutOfRange { index: 50, len: 50 });
+        // More distinct records than buckets can never be placed
+        let too_many: Vec<usize> = (0..=params.num_buckets).collect();
+        assert_eq!(client.try_query(&too_many).unwrap_err(), Error::HashingFailed);
+
+        // With one hash function, two records in the same bucket cannot be retrieved together
+        let params = gen_test_params(&mut rng, 50, 4, 1);
+        let server = BatchPirServer::new(params.clone(), &gen_records(50, 3));
+        let client = BatchPirClient::new(params.clone(), server.hints());
+        let bucket = params.buckets().into_iter().find(|b| b.len() > 1).unwrap();
+        assert_eq!(client.try_query(&bucket[..2]).unwrap_err(), Error::HashingFailed);
+
+        // Answers for the wrong number of buckets
+        let (query, state) = client.query(&[bucket[0]]);
+        assert!(server.try_answer(&BatchPirQuery(query.0[1..].to_vec())).is_err());
+        let mut answer = server.answer(&query);
+        answer.0.pop();
+        assert!(client.try_recover(&state, &answer).is_err());
+    }
+
+    #[test]
+    fn test_failure_report() {
+        let mut rng = StdRng::seed_from_u64(11);
+        let reports: Vec<BatchPirFailureReport> = [1, 2, 3]
+            .into_iter()
+            .map(|num_hashes| {
+                let params = gen_test_params(&mut rng, 500, 16, num_hashes);
+                failure_report_with_rng(&mut rng, &params, 200)
+            })
+            .collect();
+
+        // More hash functions make hashing fail less often
+        assert!(reports[0].hashing() > reports[1].hashing());
+        assert!(reports[1].hashing() >= reports[2].hashing());
+        for report in &reports {
+            assert_eq!(report.trials, 200);
+            assert!(report.decryption < 1e-9);
+            assert!(report.total() >= report.hashing());
+        }
+    }
+
+    #[test]
+    fn test_secure_params() {
+        let params = gen_secure_params(128, 1000, 32, 16, DEFAULT_NUM_HASHES).unwrap();
+        check_secure_params(&params.simplepir);
+        assert_eq!(params.num_buckets, 24);
+        assert!(gen_secure_params(512, 1000, 32, 16, DEFAULT_NUM_HASHES).is_none());
+    }
+}
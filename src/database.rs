@@ -0,0 +1,170 @@
+use crate::error::{Error, Result};
+use crate::matrix::Matrix;
+
+/// How fixed-size byte records are packed into a database of elements mod p.
+///
+/// Each record is split into digits of `bits_per_digit` bits, the largest number of whole bits
+/// which fit in an element mod p. The digits of a record are stored one below the other in one
+/// column of the database, i.e. at db[offset..offset + digits_per_record][col], so that a single
+/// query for that column retrieves the whole record. Each column holds `records_per_col` records,
+/// and as many columns are used as needed to hold every record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordLayout {
+    pub num_records: usize,
+    // The size of each record in bytes
+    pub record_size: usize,
+    pub bits_per_digit: usize,
+    pub digits_per_record: usize,
+    pub records_per_col: usize,
+}
+
+impl RecordLayout {
+    /// Lay out `num_records` records of `record_size` bytes in a database of elements mod p whose
+    /// columns have `col_len` entries.
+    pub fn new(num_records: usize, record_size: usize, p: u64, col_len: usize) -> Self {
+        assert!(p >= 2);
+        assert!(record_size > 0);
+        Self::try_new(num_records, record_size, p, col_len).unwrap_or_else(|| {
+            panic!("a record of {} bytes does not fit in a column of {} entries", record_size, col_len)
+        })
+    }
+
+    /// Lay out records as in `new`, or None if p < 2, the records are empty or a record does not
+    /// fit in a column, as may happen for parameters received from elsewhere.
+    pub fn try_new(num_records: usize, record_size: usize, p: u64, col_len: usize) -> Option<Self> {
+        if p < 2 || record_size == 0 {
+            return None;
+        }
+        let bits_per_digit = (u64::BITS - 1 - p.leading_zeros()) as usize;
+        let digits_per_record = record_size.checked_mul(8)?.div_ceil(bits_per_digit);
+        if digits_per_record > col_len {
+            return None;
+        }
+        let records_per_col = col_len / digits_per_record;
+
+        Some(Self { num_records, record_size, bits_per_digit, digits_per_record, records_per_col })
+    }
+
+    /// Check that this is the layout which `new` gives for columns of `col_len` entries mod p, so
+    /// that records can be recovered with it. Returns LengthMismatch with the digits per record
+    /// which were expected, or with `col_len` if a record does not fit in a column.
+    pub fn check(&self, p: u64, col_len: usize) -> Result<()> {
+        match Self::try_new(self.num_records, self.record_size, p, col_len) {
+            Some(expected) if expected == *self => Ok(()),
+            Some(expected) => {
+                Err(Error::LengthMismatch { expected: expected.digits_per_record, found: self.digits_per_record })
+            }
+            None => Err(Error::LengthMismatch { expected: col_len, found: self.digits_per_record }),
+        }
+    }
+
+    /// The number of columns which hold records
+    pub fn num_cols(&self) -> usize {
+        self.num_records.div_ceil(self.records_per_col)
+    }
+
+    /// The (offset, col) at which the first digit of record `idx` is stored
+    pub fn position(&self, idx: usize) -> (usize, usize) {
+        assert!(idx < self.num_records);
+        let offset = (idx % self.records_per_col) * self.digits_per_record;
+        (offset, idx / self.records_per_col)
+    }
+
+    /// Split a record into digits, least significant bits first
+    pub fn encode(&self, record: &[u8]) -> Vec<u64> {
+        assert_eq!(record.len(), self.record_size);
+        let mut digits = vec![0u64; self.digits_per_record];
+        for bit in 0..self.record_size * 8 {
+            let b = (record[bit / 8] >> (bit % 8)) & 1;
+            digits[bit / self.bits_per_digit] |= (b as u64) << (bit % self.bits_per_digit);
+        }
+        digits
+    }
+
+    /// Reassemble a record from its digits
+    pub fn decode(&self, digits: &[u64]) -> Vec<u8> {
+        assert_eq!(digits.len(), self.digits_per_record);
+        let mut record = vec![0u8; self.record_size];
+        for bit in 0..self.record_size * 8 {
+            let b = (digits[bit / self.bits_per_digit] >> (bit % self.bits_per_digit)) & 1;
+            record[bit / 8] |= (b as u8) << (bit % 8);
+        }
+        record
+    }
+
+    /// Pack records into a database of `rows` rows and `cols` columns of elements mod p. Unused
+    /// entries are zero.
+    pub fn pack(&self, records: &[Vec<u8>], p: u64, rows: usize, cols: usize) -> Matrix {
+        assert_eq!(records.len(), self.num_records);
+        assert!(self.records_per_col * self.digits_per_record <= rows);
+        assert!(self.num_cols() <= cols);
+
+        let mut db = Matrix::zeros(p, rows, cols);
+        for (idx, record) in records.iter().enumerate() {
+            let (offset, col) = self.position(idx);
+            for (k, digit) in self.encode(record).into_iter().enumerate() {
+                db[offset + k][col] = digit;
+            }
+        }
+        db
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::RecordLayout;
+
+    #[test]
+    fn test_layout() {
+        // 3 bits per digit, so 11 digits per record and 2 records per column
+        let layout = RecordLayout::new(5, 4, 11, 25);
+        assert_eq!(layout.bits_per_digit, 3);
+        assert_eq!(layout.digits_per_record, 11);
+        assert_eq!(layout.records_per_col, 2);
+        assert_eq!(layout.num_cols(), 3);
+        assert_eq!(layout.position(0), (0, 0));
+        assert_eq!(layout.position(1), (11, 0));
+        assert_eq!(layout.position(4), (0, 2));
+    }
+
+    #[test]
+    fn test_try_new() {
+        assert_eq!(RecordLayout::try_new(5, 4, 11, 25), Some(RecordLayout::new(5, 4, 11, 25)));
+        assert_eq!(RecordLayout::try_new(5, 4, 11, 10), None);
+        assert_eq!(RecordLayout::try_new(5, 0, 11, 25), None);
+        assert_eq!(RecordLayout::try_new(5, 4, 1, 25), None);
+        assert_eq!(RecordLayout::try_new(1, usize::MAX, 1 << 16, usize::MAX), None);
+    }
+
+    #[test]
+    fn test_check() {
+        use crate::error::Error;
+
+        let layout = RecordLayout::new(5, 4, 11, 25);
+        assert_eq!(layout.check(11, 25), Ok(()));
+        assert_eq!(layout.check(3, 40), Err(Error::LengthMismatch { expected: 32, found: 11 }));
+        assert_eq!(layout.check(11, 20), Err(Error::LengthMismatch { expected: 11, found: 11 }));
+        assert_eq!(layout.check(11, 10), Err(Error::LengthMismatch { expected: 10, found: 11 }));
+        let broken = RecordLayout { bits_per_digit: 0, ..layout };
+        assert_eq!(broken.check(11, 25), Err(Error::LengthMismatch { expected: 11, found: 11 }));
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        for p in [2u64, 3, 11, 256, 991, 1 << 16] {
+            let layout = RecordLayout::new(1, 7, p, 64);
+            let record = vec![0xff, 0x00, 0xa5, 0x5a, 0x01, 0x80, 0x37];
+            let digits = layout.encode(&record);
+            assert!(digits.iter().all(|d| *d < p));
+            assert_eq!(layout.decode(&digits), record);
+        }
+    }
+
+    #[test]
+    fn test_pack() {
+        let layout = RecordLayout::new(3, 1, 256, 2);
+        let records = vec![vec![7], vec![8], vec![9]];
+        let db = layout.pack(&records, 256, 2, 2);
+        assert_eq!(db.data(), &[7, 9, 8, 0]);
+    }
+}
@@ -1,11 +1,22 @@
+use crate::database::RecordLayout;
 use crate::matrix::Matrix;
 use crate::element::Element;
-use crate::regev::gen_error_vec;
+use crate::modulus::Modulus;
+use crate::error::{check_dimensions, check_elements, check_index, check_len, check_modulus, Error, Result};
+use crate::params::{self, select_params, DEFAULT_MAX_FAILURE};
+use crate::regev::{gen_error_vec_with_rng, gen_secret_with_rng};
+use crate::seeded::SeededMatrix;
+use crate::simplepir::bytes_mod_q;
+use rand::{rngs::StdRng, CryptoRng, RngCore, SeedableRng};
 
+/// The parameters of DoublePIR over an l x m database, which is indexed as in the paper: the item
+/// in row `row < l` and column `col < m` is `db[row][col]`, so each query selects one entry of
+/// `db.get_row(row)`.
+#[derive(Debug, Clone, PartialEq)]
 pub struct DoublePIRParams {
-    // Public A matrices
-    pub a_1: Matrix,
-    pub a_2: Matrix,
+    // Public A matrices, each expanded from a seed
+    pub a_1: SeededMatrix,
+    pub a_2: SeededMatrix,
 
     // The integer modulus
     pub q: u64,
@@ -13,13 +24,18 @@ pub struct DoublePIRParams {
     // The plaintext modulus
     pub p: u64,
 
+    // The base in which the first layer's answer is decomposed before the second layer, at most p.
+    // A smaller base adds less noise in the second layer, but needs more digits, so the hints
+    // and answers are larger.
+    pub base: u64,
+
     // The LWE secret length
     pub n: usize,
 
-    // The number of columns of the database
+    // The number of rows of the database
     pub l: usize,
 
-    // The number of rows of the database
+    // The number of columns of the database
     pub m: usize,
 
     // The standard deviation for sampling random elements
@@ -28,72 +44,280 @@ pub struct DoublePIRParams {
 
 pub fn gen_params() -> DoublePIRParams {
     // Database size: l x m
-    let l = 4;
-    let m = 8;
-    let n = 32;
-    let p = 2;
-    let q = 3329;
-    let std_dev = 6.4;
-    // rows, cols - TODO: change gen_uniform_rand
-    let a_1 = Matrix::gen_uniform_rand(q, m, n);
-    let a_2 = Matrix::gen_uniform_rand(q, l, n);
-    DoublePIRParams { a_1, a_2, q, l, p, n, m, std_dev }
+    gen_params_with(4, 8, 32, 3329, 2, 6.4)
+}
+
+/// Generate parameters for an l x m database with at least `security_bits` bits of security, using
+/// the largest plaintext modulus for which answers decrypt correctly. Returns None if the security
+/// level is not supported or the database is too large for any plaintext modulus.
+pub fn gen_secure_params(security_bits: u32, l: usize, m: usize) -> Option<DoublePIRParams> {
+    gen_secure_params_with_rng(&mut StdRng::from_entropy(), security_bits, l, m)
+}
+
+pub fn gen_secure_params_with_rng<R: RngCore + CryptoRng>(
+    rng: &mut R,
+    security_bits: u32,
+    l: usize,
+    m: usize,
+) -> Option<DoublePIRParams> {
+    // Each layer of DoublePIR sums up to max(l, m) ciphertexts scaled by values in [0, p), which
+    // accumulates as much error as four times as many additions of values in [-p/2, p/2)
+    let lwe = select_params(security_bits, 4 * l.max(m))?;
+
+    // The client decrypts many entries of the second layer, so the union bound may need a
+    // slightly smaller p
+    let p = (2..=lwe.p)
+        .rev()
+        .find(|p| failure_probability_with(l, m, lwe.n, lwe.q, *p, *p, lwe.std_dev) <= DEFAULT_MAX_FAILURE)?;
+    Some(gen_params_with_rng(rng, l, m, lwe.n, lwe.q, p, lwe.std_dev))
+}
+
+/// Generate parameters for an l x m database of elements mod p, with LWE secrets of length n mod
+/// q. Panics if an answer fails to decrypt with probability above DEFAULT_MAX_FAILURE.
+pub fn gen_params_with(l: usize, m: usize, n: usize, q: u64, p: u64, std_dev: f64) -> DoublePIRParams {
+    gen_params_with_rng(&mut StdRng::from_entropy(), l, m, n, q, p, std_dev)
+}
+
+/// Generate parameters as in `gen_params_with`, drawing randomness from `rng`.
+pub fn gen_params_with_rng<R: RngCore + CryptoRng>(
+    rng: &mut R,
+    l: usize,
+    m: usize,
+    n: usize,
+    q: u64,
+    p: u64,
+    std_dev: f64,
+) -> DoublePIRParams {
+    assert!(
+        failure_probability_with(l, m, n, q, p, p, std_dev) <= DEFAULT_MAX_FAILURE,
+        "answers fail to decrypt with too high a probability",
+    );
+    let a_1 = SeededMatrix::gen_with_rng(rng, q, m, n);
+    let a_2 = SeededMatrix::gen_with_rng(rng, q, l, n);
+    DoublePIRParams { a_1, a_2, q, l, p, base: p, n, m, std_dev }
+}
+
+/// Generate parameters as in `gen_params_with`, or None if an answer fails to decrypt with
+/// probability above `max_failure`.
+pub fn gen_params_with_max_failure(
+    l: usize,
+    m: usize,
+    n: usize,
+    q: u64,
+    p: u64,
+    std_dev: f64,
+    max_failure: f64,
+) -> Option<DoublePIRParams> {
+    gen_params_with_max_failure_with_rng(&mut StdRng::from_entropy(), l, m, n, q, p, std_dev, max_failure)
+}
+
+/// Generate parameters as in `gen_params_with_max_failure`, drawing randomness from `rng`.
+#[allow(clippy::too_many_arguments)]
+pub fn gen_params_with_max_failure_with_rng<R: RngCore + CryptoRng>(
+    rng: &mut R,
+    l: usize,
+    m: usize,
+    n: usize,
+    q: u64,
+    p: u64,
+    std_dev: f64,
+    max_failure: f64,
+) -> Option<DoublePIRParams> {
+    if failure_probability_with(l, m, n, q, p, p, std_dev) > max_failure {
+        return None;
+    }
+    let a_1 = SeededMatrix::gen_with_rng(rng, q, m, n);
+    let a_2 = SeededMatrix::gen_with_rng(rng, q, l, n);
+    Some(DoublePIRParams { a_1, a_2, q, l, p, base: p, n, m, std_dev })
+}
+
+/// The probability that the client fails to recover an entry from an answer.
+pub fn failure_probability(params: &DoublePIRParams) -> f64 {
+    let DoublePIRParams { l, m, n, q, p, base, std_dev, .. } = *params;
+    failure_probability_with(l, m, n, q, p, base, std_dev)
+}
+
+fn failure_probability_with(l: usize, m: usize, n: usize, q: u64, p: u64, base: u64, std_dev: f64) -> f64 {
+    // The client decrypts k(n + 1) digits from the second layer, each summing l ciphertexts
+    // scaled by digits in [0, base), then one entry from the first, summing m ciphertexts scaled
+    // by values in [0, p).
+    let k = Element::num_digits(q, base);
+    let second = params::failure_probability(q, p, std_dev, l, (base - 1) as f64);
+    let first = params::failure_probability(q, p, std_dev, m, (p - 1) as f64);
+    ((k * (n + 1)) as f64 * second + first).min(1.0)
+}
+
+impl DoublePIRParams {
+    /// Use a different base for the decomposition between the two layers, which must be between 2
+    /// and p. Panics if an answer would then fail to decrypt with probability above
+    /// DEFAULT_MAX_FAILURE.
+    pub fn with_base(self, base: u64) -> Self {
+        assert!((2..=self.p).contains(&base), "the base must be between 2 and p");
+        let params = Self { base, ..self };
+        assert!(
+            failure_probability(&params) <= DEFAULT_MAX_FAILURE,
+            "answers fail to decrypt with too high a probability",
+        );
+        params
+    }
+
+    /// The number of digits in the base needed for an element mod q
+    pub fn num_digits(&self) -> usize {
+        Element::num_digits(self.q, self.base)
+    }
 }
 
 pub fn gen_db(params: &DoublePIRParams) -> Matrix {
-    Matrix::gen_uniform_rand(
+    gen_db_with_rng(&mut StdRng::from_entropy(), params)
+}
+
+pub fn gen_db_with_rng<R: RngCore + CryptoRng>(rng: &mut R, params: &DoublePIRParams) -> Matrix {
+    Matrix::gen_uniform_rand_with_rng(
+        rng,
         params.p,
         params.l,
         params.m,
     )
 }
 
+/// Pack byte records, which must all have the same length, into a database of elements mod p.
+/// The digits of each record are stored in consecutive rows of a single column, so one query
+/// retrieves the whole record: the server selects the column once, and only repeats the much
+/// cheaper second layer for each row.
+pub fn gen_db_from_records(params: &DoublePIRParams, records: &[Vec<u8>]) -> (Matrix, RecordLayout) {
+    assert!(!records.is_empty());
+    let layout = RecordLayout::new(records.len(), records[0].len(), params.p, params.l);
+    assert!(layout.num_cols() <= params.m, "the records do not fit in the database");
+    let db = layout.pack(records, params.p, params.l, params.m);
+    (db, layout)
+}
+
+/// The number of bytes in the answer to a query for a single item
+pub fn answer_bytes(params: &DoublePIRParams) -> usize {
+    let k = params.num_digits();
+    // h, and then (ans_h || ans_2)
+    bytes_mod_q(k * params.n, params.q) + bytes_mod_q(k * (params.n + 1), params.q)
+}
+
+/// The number of bytes in the answer to a query for a record of the layout. The answer contains h
+/// once, and (ans_h || ans_2) for each digit of the record.
+pub fn record_answer_bytes(params: &DoublePIRParams, layout: &RecordLayout) -> usize {
+    let k = params.num_digits();
+    let per_digit = bytes_mod_q(k * (params.n + 1), params.q);
+    bytes_mod_q(k * params.n, params.q) + layout.digits_per_record * per_digit
+}
+
 pub fn gen_hints(params: &DoublePIRParams, db: &Matrix) -> (Matrix, Matrix) {
     let mut db_q = db.clone();
     db_q.change_q(params.q);
 
-    // hint_s = A transposed * db transposed
-    let mut hint_s =  params.a_1.to_owned().rotated() * db_q.to_owned().rotated();
-    hint_s = hint_s.decomposed(params.p);
+    // hint_s = A transposed * db transposed = (db * A) transposed, streaming A from its seed
+    let mut hint_s = params.a_1.left_mul(&db_q).rotated();
+    hint_s = hint_s.decomposed(params.base);
 
     // hint_c = hint_s * A_2
-    let hint_c = hint_s.to_owned() * params.a_2.to_owned();
+    let hint_c = params.a_2.left_mul(&hint_s);
 
     (hint_s, hint_c)
 }
 
 pub fn query(
     params: &DoublePIRParams,
-    col_i: usize,
-    row_i: usize,
-    s_1: &Vec<Element>,
-    s_2: &Vec<Element>,
+    row: usize,
+    col: usize,
+    s_1: &[Element],
+    s_2: &[Element],
 ) -> (Matrix, Matrix) {
-    assert!(row_i < params.m);
-    assert!(col_i < params.l);
+    query_with_rng(&mut StdRng::from_entropy(), params, row, col, s_1, s_2)
+}
 
-    // q / p
-    let floor = Element::from(params.q, params.q / params.p);
+pub fn query_with_rng<R: RngCore + CryptoRng>(
+    rng: &mut R,
+    params: &DoublePIRParams,
+    row: usize,
+    col: usize,
+    s_1: &[Element],
+    s_2: &[Element],
+) -> (Matrix, Matrix) {
+    try_query_with_rng(rng, params, row, col, s_1, s_2).unwrap()
+}
 
-    // Generate error vectors
-    let e_1 = Matrix::from_col(&gen_error_vec(params.q, params.m));
-    let e_2 = Matrix::from_col(&gen_error_vec(params.q, params.l));
+/// Generate a query as in `query`, or an error if the item is outside the database or the secrets
+/// do not match the parameters.
+pub fn try_query(
+    params: &DoublePIRParams,
+    row: usize,
+    col: usize,
+    s_1: &[Element],
+    s_2: &[Element],
+) -> Result<(Matrix, Matrix)> {
+    try_query_with_rng(&mut StdRng::from_entropy(), params, row, col, s_1, s_2)
+}
+
+pub fn try_query_with_rng<R: RngCore + CryptoRng>(
+    rng: &mut R,
+    params: &DoublePIRParams,
+    row: usize,
+    col: usize,
+    s_1: &[Element],
+    s_2: &[Element],
+) -> Result<(Matrix, Matrix)> {
+    check_index(row, params.l)?;
+    check_index(col, params.m)?;
+    check_secret(params, s_1)?;
+    check_secret(params, s_2)?;
 
-    // Compute c_1 = A_1 * s_1 + e_1 + floor * u_i_row
-    // NOTE: perhaps due to a bug in our Matrix implementation, row and col are reversed. As such,
-    // while the paper notes that c_1 contains floor at u_i_col, we instead use row_i.
-    let mut c_1 = params.a_1.to_owned().mul_vec(s_1) + e_1.rotated();
-    c_1[row_i][0] += floor.clone();
+    // Compute c_1 = A_1 * s_1 + e_1 + floor * u_col
+    let c_1 = encrypt_unit(rng, params, &params.a_1, col, s_1);
+    assert_eq!(c_1.num_rows(), params.m);
 
-    assert_eq!(c_1.num_cols(), params.m);
+    // Compute c_2 = A_2 * s_2 + e_2 + floor * u_row
+    let c_2 = encrypt_unit(rng, params, &params.a_2, row, s_2);
+    assert_eq!(c_2.num_rows(), params.l);
 
-    // Compute c_2 = A_2 * s_2 + e_2 + floor * u_i_col
-    // NOTE: same bug as above
-    let mut c_2 = params.a_2.to_owned().mul_vec(s_2) + e_2.rotated();
-    c_2[col_i][0] += floor;
-    assert_eq!(c_2.num_cols(), params.l);
+    Ok((c_1, c_2))
+}
 
-    (c_1, c_2)
+/// Compute a * s + e + floor(q / p) * u_idx, with a fresh error vector e
+fn encrypt_unit<R: RngCore + CryptoRng>(
+    rng: &mut R,
+    params: &DoublePIRParams,
+    a: &SeededMatrix,
+    idx: usize,
+    s: &[Element],
+) -> Matrix {
+    let floor = Element::from(params.q, params.q / params.p);
+    let e = Matrix::from_row(&gen_error_vec_with_rng(rng, params.q, params.std_dev, a.num_rows()));
+    let mut c = a.mul_vec(s) + e.rotated();
+    let with_floor = c.get(idx, 0) + floor;
+    c.set(idx, 0, with_floor);
+    c
+}
+
+fn check_secret(params: &DoublePIRParams, s: &[Element]) -> Result<()> {
+    check_len(params.n, s.len())?;
+    for e in s {
+        check_modulus(params.q, e.q)?;
+    }
+    Ok(())
+}
+
+fn check_matrix(q: u64, dimensions: (usize, usize), m: &Matrix) -> Result<()> {
+    check_modulus(q, m.q())?;
+    check_dimensions(dimensions, m.dimensions())
+}
+
+/// Check a query received from an untrusted client: `c_1` must have an entry for every column and
+/// `c_2` one for every row of the database, and every entry must be reduced mod the q of the
+/// params.
+pub fn validate_query(params: &DoublePIRParams, query: &(Matrix, Matrix)) -> Result<()> {
+    check_ciphertext(params.q, params.m, &query.0)?;
+    check_ciphertext(params.q, params.l, &query.1)
+}
+
+fn check_ciphertext(q: u64, len: usize, c: &Matrix) -> Result<()> {
+    check_matrix(q, (len, 1), c)?;
+    c.check_reduced()
 }
 
 pub fn answer(
@@ -102,77 +326,113 @@ pub fn answer(
     hint_s: &Matrix,
     query: &(Matrix, Matrix),
 ) -> (Matrix, Matrix) {
-    let k = ((params.q - 1) as f64).log(params.p as f64).ceil() as usize;
-    let c_1 = query.to_owned().0;
-    let c_2 = query.to_owned().1;
+    try_answer(params, db, hint_s, query).unwrap()
+}
 
-    let mut db_q = db.clone();
-    db_q.change_q(params.q);
-    let ans_1 = (c_1.rotated() * db_q.rotated()).decomposed(params.p);
+/// Answer a query as in `answer`, or return an error if the database, hint or query do not match
+/// the parameters.
+pub fn try_answer(
+    params: &DoublePIRParams,
+    db: &Matrix,
+    hint_s: &Matrix,
+    query: &(Matrix, Matrix),
+) -> Result<(Matrix, Matrix)> {
+    let k = params.num_digits();
+    check_matrix(params.p, (params.l, params.m), db)?;
+    check_matrix(params.q, (params.n * k, params.l), hint_s)?;
+    validate_query(params, query)?;
 
-    assert_eq!(ans_1.num_cols(), k);
-    assert_eq!(ans_1.num_rows(), params.l);
+    let (ans_1, h) = answer_first_layer(params, db, &query.0);
+    let ans_h_ans_2 = answer_second_layer(params, hint_s, &ans_1, &query.1);
+    Ok((h, ans_h_ans_2))
+}
 
-    let h = ans_1.to_owned() * params.a_2.to_owned();
+/// Compute the decomposed column selected by c_1, ans_1 = Decomp((db * c_1) transposed), along
+/// with h = ans_1 * A_2
+fn answer_first_layer(params: &DoublePIRParams, db: &Matrix, c_1: &Matrix) -> (Matrix, Matrix) {
+    let k = params.num_digits();
+    let mut db_q = db.clone();
+    db_q.change_q(params.q);
+    let ans_1 = (c_1.clone().rotated() * db_q.rotated()).decomposed(params.base);
 
-    // (ans_h || ans_2) = (hint_s || ans_1) * c_2
-    let mut hint_s_ans_1 = hint_s.clone();
-    for col in ans_1.data {
-        hint_s_ans_1.append_col(col.clone());
-    }
+    assert_eq!(ans_1.num_rows(), k);
+    assert_eq!(ans_1.num_cols(), params.l);
 
-    let ans_h_ans_2 = hint_s_ans_1 * c_2;
+    let h = params.a_2.left_mul(&ans_1);
+    assert_eq!(h.num_rows(), k);
+    assert_eq!(h.num_cols(), params.n);
 
-    // Check the dimensions of the answer
-    assert_eq!(h.num_cols(), k);
-    assert_eq!(h.num_rows(), params.n);
+    (ans_1, h)
+}
 
-    assert_eq!(ans_h_ans_2.num_cols(), k * (params.n + 1));
-    assert_eq!(ans_h_ans_2.num_rows(), 1);
+/// Compute (ans_h || ans_2) = (hint_s || ans_1) * c_2
+fn answer_second_layer(params: &DoublePIRParams, hint_s: &Matrix, ans_1: &Matrix, c_2: &Matrix) -> Matrix {
+    let k = ans_1.num_rows();
+    let mut ans_h_ans_2 = Matrix::zeros(params.q, hint_s.num_rows(), 1);
+    hint_s.mul_into(c_2, &mut ans_h_ans_2);
+    let mut ans_2 = Matrix::zeros(params.q, k, 1);
+    ans_1.mul_into(c_2, &mut ans_2);
+    for i in 0..k {
+        ans_h_ans_2.append_row(ans_2.get_row(i));
+    }
 
-    (h, ans_h_ans_2)
+    assert_eq!(ans_h_ans_2.num_rows(), k * (params.n + 1));
+    assert_eq!(ans_h_ans_2.num_cols(), 1);
+    ans_h_ans_2
 }
 
 pub fn recover(
     params: &DoublePIRParams,
     hint_c: &Matrix,
     answer: &(Matrix, Matrix),
-    s_1: &Vec<Element>,
-    s_2: &Vec<Element>,
+    s_1: &[Element],
+    s_2: &[Element],
 ) -> Element {
-    let k = ((params.q - 1) as f64).log(params.p as f64).ceil() as usize;
+    try_recover(params, hint_c, answer, s_1, s_2).unwrap()
+}
+
+/// Recover an item as in `recover`, or return an error if the hint, answer or secrets do not
+/// match the parameters.
+pub fn try_recover(
+    params: &DoublePIRParams,
+    hint_c: &Matrix,
+    answer: &(Matrix, Matrix),
+    s_1: &[Element],
+    s_2: &[Element],
+) -> Result<Element> {
+    let k = params.num_digits();
+    check_matrix(params.q, (k * params.n, params.n), hint_c)?;
+    check_matrix(params.q, (k, params.n), &answer.0)?;
+    check_matrix(params.q, (k * (params.n + 1), 1), &answer.1)?;
+    answer.0.check_reduced()?;
+    answer.1.check_reduced()?;
+    check_secret(params, s_1)?;
+    check_secret(params, s_2)?;
     let p = params.p;
-    let q = params.q as f64;
+    let q = Modulus::new(params.q);
     let h = answer.to_owned().0;
     let ans_h_ans_2 = answer.to_owned().1;
 
-    assert_eq!(hint_c.num_cols(), k * params.n);
-    assert_eq!(hint_c.num_rows(), params.n);
-
-    assert_eq!(h.num_cols(), k);
-    assert_eq!(h.num_rows(), params.n);
-
     // hint_c_h =  hint_c || h
     let mut hint_c_h = hint_c.to_owned();
-    for i in 0..h.num_cols() {
-        hint_c_h.append_col(h[i].to_owned());
+    for i in 0..h.num_rows() {
+        hint_c_h.append_row(h.get_row(i));
     }
 
     // hint_c_h * s_2
     let hhs = hint_c_h.mul_vec(s_2);
-    assert_eq!(hhs.num_cols(), k * (params.n + 1));
-    assert_eq!(hhs.num_rows(), 1);
+    assert_eq!(hhs.num_rows(), k * (params.n + 1));
+    assert_eq!(hhs.num_cols(), 1);
 
     // h1_hat_a1_hat = (ans_h || ans_2) - (hint_c || h) *  s_2
     let mut h1_hat_a1_hat = ans_h_ans_2 - hhs;
 
-    assert_eq!(h1_hat_a1_hat.num_cols(), k * (params.n + 1));
-    assert_eq!(h1_hat_a1_hat.num_rows(), 1);
+    assert_eq!(h1_hat_a1_hat.num_rows(), k * (params.n + 1));
+    assert_eq!(h1_hat_a1_hat.num_cols(), 1);
 
-    for i in 0..h1_hat_a1_hat.num_cols() {
-        for j in 0..h1_hat_a1_hat.num_rows() {
-            h1_hat_a1_hat[i][j].uint =
-                ((h1_hat_a1_hat[i][j].uint * p) as f64 / q).round() as u64 % p;
+    for i in 0..h1_hat_a1_hat.num_rows() {
+        for j in 0..h1_hat_a1_hat.num_cols() {
+            h1_hat_a1_hat[i][j] = q.round_to(p, h1_hat_a1_hat[i][j]);
         }
     }
 
@@ -184,24 +444,384 @@ pub fn recover(
     // hint_c_h: k(n+1) x n
     // s2: n x 1
     // h1_hat_a1_hat: (k(n+1) x 1) - (k(n+1) x 1)
-    let h1_a1 = h1_hat_a1_hat.recompose(p, params.q);
+    let h1_a1 = h1_hat_a1_hat.recompose(params.base, params.q);
 
     let mut h_1 = Vec::with_capacity(params.n);
     for i in 0..params.n {
-        h_1.push(h1_a1[i].clone());
+        h_1.push(h1_a1.get_row(i));
     }
     let h_1 = Matrix::from(&h_1);
-    let a_1 = Matrix::from_col(&h1_a1[params.n]);
+    let a_1 = Matrix::from_row(&h1_a1.get_row(params.n));
+
+    let d_hat = a_1 - Matrix::from_row(s_1) * h_1;
+
+    let d = q.round_to(p, d_hat[0][0]);
+    Ok(Element::from(params.p, d))
+}
+
+/// A query for one item of the database, as sent to the server
+#[derive(Debug, Clone, PartialEq)]
+pub struct DoublePirQuery {
+    /// Selects the column of the item, with an entry for each of the m columns
+    pub c_1: Matrix,
+    /// Selects the row of the item, with an entry for each of the l rows
+    pub c_2: Matrix,
+}
+
+/// The server's answer to a DoublePirQuery
+#[derive(Debug, Clone, PartialEq)]
+pub struct DoublePirAnswer {
+    pub h: Matrix,
+    pub ans_h_ans_2: Matrix,
+}
+
+/// What the client remembers about a query it has sent. This includes the query's LWE secrets, so
+/// it must never leave the client.
+#[derive(Debug)]
+pub struct DoublePirQueryState {
+    row: usize,
+    col: usize,
+    s_1: Vec<Element>,
+    s_2: Vec<Element>,
+}
+
+impl DoublePirQueryState {
+    /// The (row, col) index of the item which was queried
+    pub fn index(&self) -> (usize, usize) {
+        (self.row, self.col)
+    }
+}
+
+/// A query for a whole record of a database built by `gen_db_from_records`. It has a single c_1
+/// selecting the column of the record, and a c_2 under a separate secret for each of its rows.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DoublePirRecordQuery {
+    pub c_1: Matrix,
+    pub c_2: Vec<Matrix>,
+}
+
+/// The server's answer to a DoublePirRecordQuery, with an (ans_h || ans_2) for each c_2
+#[derive(Debug, Clone, PartialEq)]
+pub struct DoublePirRecordAnswer {
+    pub h: Matrix,
+    pub ans_h_ans_2: Vec<Matrix>,
+}
+
+/// What the client remembers about a record query it has sent. Like DoublePirQueryState, it must
+/// never leave the client.
+#[derive(Debug)]
+pub struct DoublePirRecordState {
+    idx: usize,
+    s_1: Vec<Element>,
+    s_2: Vec<Vec<Element>>,
+}
+
+impl DoublePirRecordState {
+    /// The index of the record which was queried
+    pub fn index(&self) -> usize {
+        self.idx
+    }
+}
+
+/// A change to the database held by a DoublePirServer
+#[derive(Debug, Clone, PartialEq)]
+pub enum DoublePirEdit {
+    /// Set the item at db[row][col]
+    Cell { row: usize, col: usize, value: Element },
+    /// Replace all m items of row `row`
+    Row { row: usize, values: Vec<Element> },
+}
+
+/// How the hints changed after the database was edited. Each edited row of the database changes
+/// one column of hint_s, and `hint_s_diff` holds the difference for each row in `rows`, with
+/// one column per row. The client adds `hint_s_diff` times those rows of A_2 to hint_c, so each
+/// delta must be applied exactly once, in order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DoublePirHintDelta {
+    pub rows: Vec<usize>,
+    pub hint_s_diff: Matrix,
+}
+
+/// Add the change to hint_c which corresponds to a change to hint_s
+fn apply_hint_s_diff(params: &DoublePIRParams, hint_c: &mut Matrix, delta: &DoublePirHintDelta) {
+    let a_2_rows = params.a_2.expand_rows(&delta.rows);
+    delta.hint_s_diff.mul_acc_block(0, &a_2_rows, hint_c);
+}
+
+/// The server side of DoublePIR. It holds the database and `hint_s`, which it needs to answer
+/// queries, and publishes `hint_c` for clients to download once during the offline phase.
+pub struct DoublePirServer {
+    params: DoublePIRParams,
+    db: Matrix,
+    hint_s: Matrix,
+    hint_c: Matrix,
+}
+
+impl DoublePirServer {
+    /// Set up a server for the database of elements mod p, generating its hints.
+    pub fn new(params: DoublePIRParams, db: &Matrix) -> Self {
+        assert_eq!(db.q(), params.p);
+        assert_eq!(db.dimensions(), (params.l, params.m));
+        let (hint_s, hint_c) = gen_hints(&params, db);
+        Self { params, db: db.clone(), hint_s, hint_c }
+    }
+
+    pub fn params(&self) -> &DoublePIRParams {
+        &self.params
+    }
+
+    /// The hint which every client downloads during the offline phase
+    pub fn hint(&self) -> &Matrix {
+        &self.hint_c
+    }
+
+    pub fn answer(&self, query: &DoublePirQuery) -> DoublePirAnswer {
+        self.try_answer(query).unwrap()
+    }
+
+    /// Answer a query from an untrusted client, or reject it if it does not pass
+    /// `validate_query`.
+    pub fn try_answer(&self, query: &DoublePirQuery) -> Result<DoublePirAnswer> {
+        let query = (query.c_1.clone(), query.c_2.clone());
+        let (h, ans_h_ans_2) = try_answer(&self.params, &self.db, &self.hint_s, &query)?;
+        Ok(DoublePirAnswer { h, ans_h_ans_2 })
+    }
+
+    pub fn answer_record(&self, query: &DoublePirRecordQuery) -> DoublePirRecordAnswer {
+        self.try_answer_record(query).unwrap()
+    }
+
+    /// Answer a record query from an untrusted client, or reject it if any of its parts does not
+    /// pass `validate_query` or it asks for more rows than the database has.
+    pub fn try_answer_record(&self, query: &DoublePirRecordQuery) -> Result<DoublePirRecordAnswer> {
+        if query.c_2.len() > self.params.l {
+            return Err(Error::LengthMismatch { expected: self.params.l, found: query.c_2.len() });
+        }
+        check_ciphertext(self.params.q, self.params.m, &query.c_1)?;
+        for c_2 in &query.c_2 {
+            check_ciphertext(self.params.q, self.params.l, c_2)?;
+        }
+
+        let (ans_1, h) = answer_first_layer(&self.params, &self.db, &query.c_1);
+        let ans_h_ans_2 = query
+            .c_2
+            .iter()
+            .map(|c_2| answer_second_layer(&self.params, &self.hint_s, &ans_1, c_2))
+            .collect();
+        Ok(DoublePirRecordAnswer { h, ans_h_ans_2 })
+    }
+
+    /// Apply a batch of edits to the database, and return the delta which brings a client's
+    /// hint_c up to date. Only the columns of hint_s for the rows which were edited are
+    /// recomputed.
+    pub fn update_rows(&mut self, edits: &[DoublePirEdit]) -> DoublePirHintDelta {
+        self.try_update_rows(edits).unwrap()
+    }
+
+    /// Apply edits as in `update_rows`, or return an error without changing the database if any
+    /// edit is outside the database or has values which are not mod p.
+    pub fn try_update_rows(&mut self, edits: &[DoublePirEdit]) -> Result<DoublePirHintDelta> {
+        let DoublePIRParams { l, m, p, q, base, .. } = self.params;
+        for edit in edits {
+            match edit {
+                DoublePirEdit::Cell { row, col, value } => {
+                    check_index(*row, l)?;
+                    check_index(*col, m)?;
+                    check_elements(p, std::slice::from_ref(value))?;
+                }
+                DoublePirEdit::Row { row, values } => {
+                    check_index(*row, l)?;
+                    check_len(m, values.len())?;
+                    check_elements(p, values)?;
+                }
+            }
+        }
+
+        let mut rows = Vec::new();
+        for edit in edits {
+            match edit {
+                DoublePirEdit::Cell { row, col, value } => {
+                    self.db.set(*row, *col, value.clone());
+                    rows.push(*row);
+                }
+                DoublePirEdit::Row { row, values } => {
+                    for (col, value) in values.iter().enumerate() {
+                        self.db.set(*row, col, value.clone());
+                    }
+                    rows.push(*row);
+                }
+            }
+        }
+        rows.sort_unstable();
+        rows.dedup();
+
+        // Column `row` of hint_s only depends on the same row of the database
+        let mut changed = Matrix::zeros(q, rows.len(), m);
+        for (t, row) in rows.iter().enumerate() {
+            changed[t].copy_from_slice(&self.db[*row]);
+        }
+        let digits = self.params.a_1.left_mul(&changed).rotated().decomposed(base);
+
+        let modulus = Modulus::new(q);
+        let mut hint_s_diff = Matrix::zeros(q, digits.num_rows(), rows.len());
+        for i in 0..digits.num_rows() {
+            for (t, row) in rows.iter().enumerate() {
+                hint_s_diff[i][t] = modulus.sub(digits[i][t], self.hint_s[i][*row]);
+                self.hint_s[i][*row] = digits[i][t];
+            }
+        }
+
+        let delta = DoublePirHintDelta { rows, hint_s_diff };
+        apply_hint_s_diff(&self.params, &mut self.hint_c, &delta);
+        Ok(delta)
+    }
+}
+
+/// The client side of DoublePIR. It holds `hint_c` downloaded from the server, creates queries
+/// with fresh secrets and decodes the answers to them.
+pub struct DoublePirClient {
+    params: DoublePIRParams,
+    hint_c: Matrix,
+}
+
+impl DoublePirClient {
+    pub fn new(params: DoublePIRParams, hint_c: Matrix) -> Self {
+        let k = params.num_digits();
+        assert_eq!(hint_c.dimensions(), (k * params.n, params.n));
+        Self { params, hint_c }
+    }
+
+    pub fn params(&self) -> &DoublePIRParams {
+        &self.params
+    }
+
+    /// Bring hint_c up to date after the server's database was edited
+    pub fn apply_hint_delta(&mut self, delta: &DoublePirHintDelta) {
+        self.try_apply_hint_delta(delta).unwrap()
+    }
+
+    /// Apply a delta as in `apply_hint_delta`, or return an error without changing the hint if
+    /// the delta does not match the parameters.
+    pub fn try_apply_hint_delta(&mut self, delta: &DoublePirHintDelta) -> Result<()> {
+        let k = self.params.num_digits();
+        check_matrix(self.params.q, (k * self.params.n, delta.rows.len()), &delta.hint_s_diff)?;
+        for row in &delta.rows {
+            check_index(*row, self.params.l)?;
+        }
+        delta.hint_s_diff.check_reduced()?;
+
+        apply_hint_s_diff(&self.params, &mut self.hint_c, delta);
+        Ok(())
+    }
+
+    /// Create a query for the item at db[row][col]. The returned state must be kept to recover
+    /// the answer.
+    pub fn query(&self, row: usize, col: usize) -> (DoublePirQuery, DoublePirQueryState) {
+        self.query_with_rng(&mut StdRng::from_entropy(), row, col)
+    }
+
+    /// Create a query as in `query`, drawing the secrets and errors from `rng`.
+    pub fn query_with_rng<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        row: usize,
+        col: usize,
+    ) -> (DoublePirQuery, DoublePirQueryState) {
+        self.try_query_with_rng(rng, row, col).unwrap()
+    }
+
+    /// Create a query as in `query`, or an error if the item is outside the database
+    pub fn try_query(&self, row: usize, col: usize) -> Result<(DoublePirQuery, DoublePirQueryState)> {
+        self.try_query_with_rng(&mut StdRng::from_entropy(), row, col)
+    }
+
+    fn try_query_with_rng<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        row: usize,
+        col: usize,
+    ) -> Result<(DoublePirQuery, DoublePirQueryState)> {
+        let s_1 = gen_secret_with_rng(rng, self.params.q, self.params.n);
+        let s_2 = gen_secret_with_rng(rng, self.params.q, self.params.n);
+        let (c_1, c_2) = try_query_with_rng(rng, &self.params, row, col, &s_1, &s_2)?;
+        Ok((DoublePirQuery { c_1, c_2 }, DoublePirQueryState { row, col, s_1, s_2 }))
+    }
+
+    /// Recover the item which was queried
+    pub fn recover(&self, state: &DoublePirQueryState, answer: &DoublePirAnswer) -> Element {
+        self.try_recover(state, answer).unwrap()
+    }
+
+    /// Recover the item which was queried, or an error if the answer is malformed
+    pub fn try_recover(&self, state: &DoublePirQueryState, answer: &DoublePirAnswer) -> Result<Element> {
+        let answer = (answer.h.clone(), answer.ans_h_ans_2.clone());
+        try_recover(&self.params, &self.hint_c, &answer, &state.s_1, &state.s_2)
+    }
+
+    /// Create a query for record `idx` of a database built by `gen_db_from_records`
+    pub fn query_record(&self, layout: &RecordLayout, idx: usize) -> (DoublePirRecordQuery, DoublePirRecordState) {
+        self.query_record_with_rng(&mut StdRng::from_entropy(), layout, idx)
+    }
 
-    let d_hat = a_1 - Matrix::from_col(s_1) * h_1;
+    pub fn query_record_with_rng<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        layout: &RecordLayout,
+        idx: usize,
+    ) -> (DoublePirRecordQuery, DoublePirRecordState) {
+        let (first_row, col) = layout.position(idx);
+        let s_1 = gen_secret_with_rng(rng, self.params.q, self.params.n);
+        let c_1 = encrypt_unit(rng, &self.params, &self.params.a_1, col, &s_1);
 
-    let d = ((d_hat[0][0].uint * p) as f64 / q).round() as u64 % p;
-    Element::from (params.p, d)
+        // Reusing s_2 for several rows would reveal which rows are selected
+        let mut c_2 = Vec::with_capacity(layout.digits_per_record);
+        let mut s_2 = Vec::with_capacity(layout.digits_per_record);
+        for row in first_row..first_row + layout.digits_per_record {
+            let s = gen_secret_with_rng(rng, self.params.q, self.params.n);
+            c_2.push(encrypt_unit(rng, &self.params, &self.params.a_2, row, &s));
+            s_2.push(s);
+        }
+        (DoublePirRecordQuery { c_1, c_2 }, DoublePirRecordState { idx, s_1, s_2 })
+    }
+
+    /// Recover the bytes of the record which was queried with `query_record`
+    pub fn recover_record(
+        &self,
+        layout: &RecordLayout,
+        state: &DoublePirRecordState,
+        answer: &DoublePirRecordAnswer,
+    ) -> Vec<u8> {
+        self.try_recover_record(layout, state, answer).unwrap()
+    }
+
+    /// Recover a record as in `recover_record`, or an error if the answer is malformed or the
+    /// layout does not match the params and the query
+    pub fn try_recover_record(
+        &self,
+        layout: &RecordLayout,
+        state: &DoublePirRecordState,
+        answer: &DoublePirRecordAnswer,
+    ) -> Result<Vec<u8>> {
+        layout.check(self.params.p, self.params.l)?;
+        check_len(layout.digits_per_record, state.s_2.len())?;
+        check_len(state.s_2.len(), answer.ans_h_ans_2.len())?;
+        let digits = answer
+            .ans_h_ans_2
+            .iter()
+            .zip(&state.s_2)
+            .map(|(ans, s_2)| {
+                let answer = (answer.h.clone(), ans.clone());
+                Ok(try_recover(&self.params, &self.hint_c, &answer, &state.s_1, s_2)?.uint)
+            })
+            .collect::<Result<Vec<u64>>>()?;
+        Ok(layout.decode(&digits))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::regev::gen_secret;
+    use crate::testing::gen_records;
     use super::*;
 
     #[test]
@@ -209,25 +829,357 @@ mod tests {
         let params = gen_params();
         let db = gen_db(&params);
 
-        for i in 0..db.num_cols() {
-            for j in 0..db.num_rows() {
+        for i in 0..db.num_rows() {
+            for j in 0..db.num_cols() {
                 test_doublepir_impl(&params, &db, i, j);
             }
         }
     }
 
-    pub fn test_doublepir_impl(params: &DoublePIRParams, db: &Matrix, col: usize, row: usize) {
+    #[test]
+    pub fn test_doublepir_shapes() {
+        // Square, non-power-of-two and highly rectangular databases, in both orientations
+        let shapes = [(1, 1), (1, 23), (23, 1), (3, 5), (5, 3), (2, 40), (40, 2), (6, 17)];
+        for (l, m) in shapes {
+            let params = gen_params_with(l, m, 32, 3329, 2, 6.4);
+            let db = gen_db(&params);
+            let server = DoublePirServer::new(params.clone(), &db);
+            let client = DoublePirClient::new(params, server.hint().clone());
+
+            for row in 0..l {
+                for col in 0..m {
+                    let (query, state) = client.query(row, col);
+                    let answer = server.answer(&query);
+                    assert_eq!(client.recover(&state, &answer), db.get(row, col), "{} x {} at ({}, {})", l, m, row, col);
+                }
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_large_modulus() {
+        // Entries times p overflow 64 bits, so decryption must round without forming the product
+        let params = gen_params_with(3, 4, 32, 1 << 62, 991, 6.4);
+        let db = gen_db(&params);
+        for row in 0..params.l {
+            for col in 0..params.m {
+                test_doublepir_impl(&params, &db, row, col);
+            }
+        }
+    }
+
+    fn test_records_impl(params: DoublePIRParams, num_records: usize, record_size: usize) {
+        let records = gen_records(num_records, record_size);
+        let (db, layout) = gen_db_from_records(&params, &records);
+        let server = DoublePirServer::new(params.clone(), &db);
+        let client = DoublePirClient::new(params.clone(), server.hint().clone());
+
+        for (idx, record) in records.iter().enumerate() {
+            let (query, state) = client.query_record(&layout, idx);
+            let answer = server.answer_record(&query);
+            assert_eq!(state.index(), idx);
+            assert_eq!(&client.recover_record(&layout, &state, &answer), record);
+
+            let size = bytes_mod_q(answer.h.data().len(), params.q)
+                + answer.ans_h_ans_2.iter().map(|a| bytes_mod_q(a.data().len(), params.q)).sum::<usize>();
+            assert_eq!(record_answer_bytes(&params, &layout), size);
+        }
+
+        // Answers with the wrong number of rows, or malformed rows, are rejected
+        let (query, state) = client.query_record(&layout, 0);
+        let mut answer = server.answer_record(&query);
+        answer.ans_h_ans_2.pop();
+        assert!(client.try_recover_record(&layout, &state, &answer).is_err());
+        let mut answer = server.answer_record(&query);
+        answer.h = Matrix::zeros(params.q, 1, 1);
+        assert!(client.try_recover_record(&layout, &state, &answer).is_err());
+        let mut answer = server.answer_record(&query);
+        answer.h.data_mut()[0] = params.q;
+        let expected = Err(Error::ValueOutOfRange { value: params.q, q: params.q });
+        assert_eq!(client.try_recover_record(&layout, &state, &answer), expected);
+
+        // Layouts which do not match the params, or the query
+        let answer = server.answer_record(&query);
+        let other = RecordLayout { bits_per_digit: layout.bits_per_digit + 1, ..layout };
+        assert!(client.try_recover_record(&other, &state, &answer).is_err());
+        let shorter = RecordLayout::new(layout.num_records, layout.record_size - 1, params.p, params.l);
+        let expected = Err(Error::LengthMismatch { expected: shorter.digits_per_record, found: state.s_2.len() });
+        assert_eq!(client.try_recover_record(&shorter, &state, &answer), expected);
+
+        // Too many rows are rejected, as are rows of the wrong length
+        let (mut query, _) = client.query_record(&layout, 0);
+        query.c_2.push(query.c_1.clone());
+        assert!(server.try_answer_record(&query).is_err());
+        query.c_2 = vec![query.c_2[0].clone(); params.l + 1];
+        assert!(server.try_answer_record(&query).is_err());
+    }
+
+    #[test]
+    pub fn test_records() {
+        // One bit per digit, so a 2-byte record spans 16 rows
+        test_records_impl(gen_params_with(16, 20, 32, 3329, 2, 6.4), 20, 2);
+        // 8 bits per digit, with a non-power-of-two number of rows
+        test_records_impl(gen_params_with(13, 6, 32, 1 << 32, 256, 6.4), 12, 3);
+        // 9 bits per digit from p = 991, so digits do not line up with bytes
+        test_records_impl(gen_params_with(7, 9, 32, 1 << 32, 991, 6.4), 9, 5);
+
+        // h is only sent once for all the digits of a record
+        let params = gen_params_with(8, 4, 32, 3329, 2, 6.4);
+        let layout = RecordLayout::new(1, 1, params.p, params.l);
+        assert_eq!(layout.digits_per_record, 8);
+        assert!(record_answer_bytes(&params, &layout) < 8 * answer_bytes(&params));
+    }
+
+    #[test]
+    pub fn test_bases() {
+        let params = gen_params_with(5, 7, 32, 1 << 32, 256, 6.4);
+        let db = gen_db(&params);
+
+        let mut last_size = 0;
+        for base in [256, 37, 16, 5, 2] {
+            let params = params.clone().with_base(base);
+            assert_eq!(params.num_digits(), Element::num_digits(params.q, base));
+            let server = DoublePirServer::new(params.clone(), &db);
+            let client = DoublePirClient::new(params.clone(), server.hint().clone());
+
+            for row in 0..params.l {
+                for col in 0..params.m {
+                    let (query, state) = client.query(row, col);
+                    let answer = server.answer(&query);
+                    assert_eq!(client.recover(&state, &answer), db.get(row, col), "base {}", base);
+                }
+            }
+
+            // Smaller bases need more digits, so answers grow
+            assert!(answer_bytes(&params) > last_size);
+            last_size = answer_bytes(&params);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    pub fn test_base_above_p() {
+        let params = gen_params_with(5, 7, 32, 1 << 32, 256, 6.4);
+        params.with_base(257);
+    }
+
+    #[test]
+    pub fn test_doublepir_secure_params() {
+        let params = gen_secure_params(128, 4, 8).unwrap();
+        assert_eq!((params.n, params.q), (1024, 1 << 32));
+        assert!(params.p > 2);
+
+        let db = gen_db(&params);
+        test_doublepir_impl(&params, &db, 3, 5);
+    }
+
+    #[test]
+    pub fn test_try_variants() {
+        use crate::error::Error;
+
+        let params = gen_params();
+        let db = gen_db(&params);
+        let (hint_s, hint_c) = gen_hints(&params, &db);
+        let s_1 = gen_secret(params.q, params.n);
+        let s_2 = gen_secret(params.q, params.n);
+
+        assert_eq!(
+            try_query(&params, params.l, 0, &s_1, &s_2),
+            Err(Error::IndexOutOfRange { index: params.l, len: params.l }),
+        );
+        assert_eq!(
+            try_query(&params, 0, params.m, &s_1, &s_2),
+            Err(Error::IndexOutOfRange { index: params.m, len: params.m }),
+        );
+
+        let query = try_query(&params, 1, 2, &s_1, &s_2).unwrap();
+        let swapped = (query.1.clone(), query.0.clone());
+        assert_eq!(
+            try_answer(&params, &db, &hint_s, &swapped),
+            Err(Error::DimensionMismatch { expected: (params.m, 1), found: (params.l, 1) }),
+        );
+
+        let answer = try_answer(&params, &db, &hint_s, &query).unwrap();
+        assert!(try_recover(&params, &hint_s, &answer, &s_1, &s_2).is_err());
+        assert_eq!(try_recover(&params, &hint_c, &answer, &s_1, &s_2), Ok(db.get(1, 2)));
+    }
+
+    #[test]
+    pub fn test_client_server() {
+        let params = gen_params();
+        let db = gen_db(&params);
+
+        let server = DoublePirServer::new(params, &db);
+        let client = DoublePirClient::new(server.params().clone(), server.hint().clone());
+
+        for i in 0..db.num_rows() {
+            for j in 0..db.num_cols() {
+                let (query, state) = client.query(i, j);
+                let answer = server.answer(&query);
+                assert_eq!(state.index(), (i, j));
+                assert_eq!(client.recover(&state, &answer), db.get(i, j));
+            }
+        }
+
+        assert!(client.try_query(db.num_rows(), 0).is_err());
+        let (query, state) = client.query(0, 0);
+        let swapped = DoublePirQuery { c_1: query.c_2, c_2: query.c_1 };
+        assert!(server.try_answer(&swapped).is_err());
+        let q = client.params().q;
+        let empty = DoublePirAnswer { h: Matrix::zeros(q, 0, 0), ans_h_ans_2: Matrix::zeros(q, 0, 0) };
+        assert!(client.try_recover(&state, &empty).is_err());
+    }
+
+    #[test]
+    pub fn test_update_rows() {
+        let params = gen_params_with(6, 5, 32, 1 << 32, 256, 6.4).with_base(16);
+        let p = params.p;
+        let mut db = gen_db(&params);
+        let mut server = DoublePirServer::new(params.clone(), &db);
+        let mut client = DoublePirClient::new(params.clone(), server.hint().clone());
+
+        let values: Vec<Element> = (0..params.m).map(|j| Element::from(p, 7 * j as u64)).collect();
+        let edits = vec![
+            DoublePirEdit::Cell { row: 4, col: 1, value: Element::from(p, 200) },
+            DoublePirEdit::Row { row: 1, values: values.clone() },
+            DoublePirEdit::Cell { row: 4, col: 3, value: Element::from(p, 0) },
+        ];
+        db.set(4, 1, Element::from(p, 200));
+        for (col, value) in values.into_iter().enumerate() {
+            db.set(1, col, value);
+        }
+        db.set(4, 3, Element::from(p, 0));
+
+        let delta = server.update_rows(&edits);
+        assert_eq!(delta.rows, vec![1, 4]);
+        client.apply_hint_delta(&delta);
+
+        let (hint_s, hint_c) = gen_hints(&params, &db);
+        assert_eq!(server.hint_s, hint_s);
+        assert_eq!(client.hint_c, hint_c);
+
+        for row in 0..params.l {
+            for col in 0..params.m {
+                let (query, state) = client.query(row, col);
+                assert_eq!(client.recover(&state, &server.answer(&query)), db.get(row, col));
+            }
+        }
+
+        // Invalid edits and deltas are rejected without changing anything
+        let bad = [
+            DoublePirEdit::Cell { row: 0, col: 0, value: Element::from(p, 1) },
+            DoublePirEdit::Row { row: 0, values: vec![Element::from(p, 1)] },
+        ];
+        assert!(server.try_update_rows(&bad).is_err());
+        assert_eq!(server.hint_s, hint_s);
+        let bad = DoublePirHintDelta { rows: vec![0], hint_s_diff: Matrix::zeros(params.q, 1, 1) };
+        assert!(client.try_apply_hint_delta(&bad).is_err());
+        assert_eq!(client.hint_c, hint_c);
+    }
+
+    #[test]
+    pub fn test_fuzz_queries() {
+        use crate::error::Error;
+        use rand::rngs::StdRng;
+        use rand::{RngCore, SeedableRng};
+
+        let params = gen_params();
+        let db = gen_db(&params);
+        let (hint_s, _) = gen_hints(&params, &db);
+        let mut rng = StdRng::seed_from_u64(15);
+
+        let mut unreduced = Matrix::zeros(params.q, params.m, 1);
+        unreduced.data_mut()[params.m - 1] = params.q;
+        let c_2 = Matrix::zeros(params.q, params.l, 1);
+        assert_eq!(
+            validate_query(&params, &(unreduced, c_2)),
+            Err(Error::ValueOutOfRange { value: params.q, q: params.q }),
+        );
+
+        for _ in 0..500 {
+            // Random shapes around the expected ones, with random moduli and values which are
+            // sometimes left unreduced
+            let random_matrix = |rng: &mut StdRng, len: usize| {
+                let q = match rng.next_u64() % 4 {
+                    0 => rng.next_u64() % (1 << 40) + 2,
+                    _ => params.q,
+                };
+                let rows = match rng.next_u64() % 4 {
+                    0 => rng.next_u64() as usize % (2 * len + 1),
+                    _ => len,
+                };
+                let cols = match rng.next_u64() % 4 {
+                    0 => rng.next_u64() as usize % 3,
+                    _ => 1,
+                };
+                let unreduced = rng.next_u64() % 4 < 1;
+                let mut m = Matrix::zeros(q, rows, cols);
+                for v in m.data_mut() {
+                    *v = if unreduced { rng.next_u64() } else { rng.next_u64() % q };
+                }
+                m
+            };
+            let query = (random_matrix(&mut rng, params.m), random_matrix(&mut rng, params.l));
+
+            let valid = [(&query.0, params.m), (&query.1, params.l)].iter().all(|(m, len)| {
+                m.q() == params.q && m.dimensions() == (*len, 1) && m.check_reduced().is_ok()
+            });
+            assert_eq!(try_answer(&params, &db, &hint_s, &query).is_ok(), valid);
+        }
+
+        // Random bytes never decode to a query which crashes the server, including bodies with a
+        // degenerate modulus and dimensions which could not be allocated
+        use crate::encoding::{Encode, VERSION};
+        let server = DoublePirServer::new(params.clone(), &db);
+        for _ in 0..2000 {
+            let mut bytes = vec![0u8; rng.next_u64() as usize % 200];
+            rng.fill_bytes(&mut bytes);
+            if rng.next_u64() % 2 < 1 && bytes.len() >= 2 {
+                bytes[0] = VERSION;
+                bytes[1] = DoublePirQuery::TAG;
+            }
+            if rng.next_u64() % 4 < 1 && bytes.len() >= 26 {
+                bytes[2..10].copy_from_slice(&(rng.next_u64() % 2).to_le_bytes());
+                bytes[10..18].copy_from_slice(&(rng.next_u64() | 1 << 31).to_le_bytes());
+                bytes[18..26].copy_from_slice(&(rng.next_u64() | 1 << 31).to_le_bytes());
+            }
+            if let Ok(query) = DoublePirQuery::decode(&bytes) {
+                let _ = server.try_answer(&query);
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_failure_probability() {
+        let params = gen_secure_params(128, 4, 8).unwrap();
+        assert!(failure_probability(&params) <= DEFAULT_MAX_FAILURE);
+
+        // Far too much error for a large plaintext modulus
+        assert!(gen_params_with_max_failure(4, 8, 32, 3329, 256, 6.4, DEFAULT_MAX_FAILURE).is_none());
+        // Unless any failure probability is acceptable
+        assert!(gen_params_with_max_failure(4, 8, 32, 3329, 256, 6.4, 1.0).is_some());
+
+        // A seeded rng gives the same A_1 and A_2 every time
+        let gen = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            gen_params_with_max_failure_with_rng(&mut rng, 4, 8, 32, 3329, 256, 6.4, 1.0).unwrap()
+        };
+        assert_eq!(gen(1), gen(1));
+        assert_ne!(gen(1), gen(2));
+    }
+
+    pub fn test_doublepir_impl(params: &DoublePIRParams, db: &Matrix, row: usize, col: usize) {
         // Generate (hint_s, hint_c)
-        let hints = gen_hints(&params, &db);
+        let hints = gen_hints(params, db);
 
         let s_1 = gen_secret(params.q, params.n);
         let s_2 = gen_secret(params.q, params.n);
 
-        let query = query(&params, col, row, &s_1, &s_2);
+        let query = query(params, row, col, &s_1, &s_2);
 
-        let answer = answer(&params, &db, &hints.0, &query);
+        let answer = answer(params, db, &hints.0, &query);
 
-        let recovered = recover(&params, &hints.1, &answer, &s_1, &s_2);
-        assert_eq!(recovered, db[col][row]);
+        let recovered = recover(params, &hints.1, &answer, &s_1, &s_2);
+        assert_eq!(recovered, db.get(row, col));
     }
 }
@@ -1,14 +1,21 @@
+use crate::error::{Error, Result};
+use crate::modulus::Modulus;
 use rand_distr::num_traits::Zero;
 use std::fmt::{Display, Formatter};
 use std::ops::{Add, AddAssign, Mul, MulAssign, Sub, SubAssign};
-use rand_distr::{Normal, Distribution};
 use std::cmp::{Ordering, PartialOrd};
 use rand::{
+    CryptoRng,
+    Rng,
     RngCore,
     rngs::StdRng,
     SeedableRng,
 };
 
+/// The number of standard deviations at which discrete Gaussian samples are cut off. Samples are
+/// this far from 0 with probability below 2^-45.
+pub const GAUSSIAN_TAIL_CUT: f64 = 8.0;
+
 #[derive(Debug, PartialEq)]
 pub struct Element {
     pub(crate) q: u64,
@@ -30,6 +37,18 @@ impl Element {
         Self { q, uint }
     }
 
+    /// Create an element as in `from`, or an error if q is not a valid modulus or uint is not
+    /// reduced mod q.
+    pub fn try_from(q: u64, uint: u64) -> Result<Self> {
+        if q == 0 || q == u64::MAX {
+            return Err(Error::InvalidModulus(q));
+        }
+        if uint >= q {
+            return Err(Error::ValueOutOfRange { value: uint, q });
+        }
+        Ok(Self { q, uint })
+    }
+
     pub fn zero(q: u64) -> Self {
         Element {
             q,
@@ -41,28 +60,40 @@ impl Element {
         self.uint == 0u64
     }
 
-    /// Generate a random Element following a normal (Gaussian) distribution.
+    /// Generate a random Element following a discrete Gaussian distribution centred at 0, reduced
+    /// mod q. Samples further than GAUSSIAN_TAIL_CUT standard deviations from 0 are never drawn.
     ///
     /// # Parameters 
     ///
     /// - `q`: The element modulus
     /// - `std_dev`: The standard deviation of the distribution.
     pub fn gen_normal_rand(q: u64, std_dev: f64) -> Self {
-        assert!(std_dev < q as f64);
-        let mean = (&q / 2u64) as f64;
-        let normal = Normal::new(mean, std_dev).unwrap();
-
-        let mut rng = StdRng::from_entropy();
-        let v = normal.sample(&mut rng) as u64;
+        Self::gen_normal_rand_with_rng(&mut StdRng::from_entropy(), q, std_dev)
+    }
 
-        Self::from(q, v)
+    /// Generate a random Element as in `gen_normal_rand`, drawing randomness from `rng`.
+    pub fn gen_normal_rand_with_rng<R: RngCore + CryptoRng>(rng: &mut R, q: u64, std_dev: f64) -> Self {
+        assert!(std_dev > 0.0);
+        assert!(GAUSSIAN_TAIL_CUT * std_dev < q as f64);
+        let x = sample_discrete_gaussian(rng, std_dev);
+        if x < 0 {
+            Self::from(q, q - x.unsigned_abs())
+        } else {
+            Self::from(q, x as u64)
+        }
     }
 
     /// Generate a random element using a uniform distribution.
     /// The value will be an Element mod q.
     pub fn gen_uniform_rand(q: u64) -> Self  {
-        let mut rng = StdRng::from_entropy();
-        let min = (u64::MAX - q) % q;
+        Self::gen_uniform_rand_with_rng(&mut StdRng::from_entropy(), q)
+    }
+
+    /// Generate a random element mod q using a uniform distribution, drawing randomness from
+    /// `rng`.
+    pub fn gen_uniform_rand_with_rng<R: RngCore + CryptoRng>(rng: &mut R, q: u64) -> Self {
+        // Reject the 2^64 mod q smallest values, so that every residue is equally likely
+        let min = q.wrapping_neg() % q;
         let mut r;
         loop {
             r = rng.next_u64();
@@ -73,18 +104,31 @@ impl Element {
         Self::from(q, r % q)
     }
 
-    pub fn recompose(p: u64, q: u64, vals: &Vec<u64>) -> Self {
+    pub fn recompose(p: u64, q: u64, vals: &[u64]) -> Self {
+        let modulus = Modulus::new(q);
         let mut result = 0u64;
-        let mut r = 1;
+        let mut r = modulus.reduce(1);
         for digit in vals {
-            result += r * digit;
-            r *= p;
+            result = modulus.add(result, modulus.mul(r, modulus.reduce(*digit)));
+            r = modulus.mul(r, p);
         }
         Element::from(q, result)
     }
 
+    /// The number of base-`p` digits needed to represent any element mod `q`.
+    pub fn num_digits(q: u64, p: u64) -> usize {
+        assert!(p >= 2);
+        let mut num_digits = 0;
+        let mut n = q - 1;
+        while n > 0 {
+            n /= p;
+            num_digits += 1;
+        }
+        num_digits
+    }
+
     pub fn decomposed(self, p: u64) -> Vec<u64> {
-        let num_digits = ((self.q - 1) as f64).log(p as f64).ceil() as usize;
+        let num_digits = Self::num_digits(self.q, p);
         let mut digits = vec![0; num_digits];
         let mut n = self.uint;
 
@@ -124,7 +168,7 @@ impl Mul for Element {
         assert_eq!(self.q, rhs.q);
         Self {
             q: self.q,
-            uint: (self.uint * rhs.uint) % self.q,
+            uint: Modulus::new(self.q).mul(self.uint, rhs.uint),
         }
     }
 }
@@ -134,7 +178,7 @@ impl MulAssign for Element {
         assert_eq!(self.q, rhs.q);
         *self = Self {
             q: self.q,
-            uint: (self.uint * rhs.uint) % self.q,
+            uint: Modulus::new(self.q).mul(self.uint, rhs.uint),
         }
     }
 }
@@ -145,7 +189,7 @@ impl Add for Element {
         assert_eq!(self.q, rhs.q);
         Self {
             q: self.q,
-            uint: (self.uint + rhs.uint) % self.q,
+            uint: Modulus::new(self.q).add(self.uint, rhs.uint),
         }
     }
 }
@@ -155,7 +199,7 @@ impl AddAssign for Element {
         assert_eq!(self.q, rhs.q);
         *self = Self {
             q: self.q,
-            uint: (self.uint + rhs.uint) % self.q,
+            uint: Modulus::new(self.q).add(self.uint, rhs.uint),
         }
     }
 }
@@ -203,12 +247,31 @@ impl Display for Element {
     }
 }
 
+/// Sample an integer x from the discrete Gaussian distribution centred at 0, i.e. with probability
+/// proportional to exp(-x^2 / (2 std_dev^2)), by rejection sampling from the integers within
+/// GAUSSIAN_TAIL_CUT standard deviations of 0.
+fn sample_discrete_gaussian<R: Rng + ?Sized>(rng: &mut R, std_dev: f64) -> i64 {
+    let bound = (GAUSSIAN_TAIL_CUT * std_dev).ceil() as i64;
+    loop {
+        let x = rng.gen_range(-bound..=bound);
+        let rho = (-((x * x) as f64) / (2.0 * std_dev * std_dev)).exp();
+        if rng.gen::<f64>() < rho {
+            return x;
+        }
+    }
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::Element;
 
     fn gen_q() -> u64 {
-        return 101u64;
+        101u64
+    }
+
+    // The largest prime below 2^64
+    fn gen_large_q() -> u64 {
+        u64::MAX - 58
     }
 
     #[test]
@@ -298,14 +361,115 @@ pub mod tests {
         assert_eq!(Element::from(q, 100u64).decomposed(2), vec![0, 0, 1, 0, 0, 1, 1]);
     }
 
-    /*
     #[test]
-    fn test_gen_normal_rand() {
+    fn test_large_q_arithmetic() {
+        let q = gen_large_q();
+
+        let f = Element::from(q, q - 1);
+        let g = Element::from(q, q - 1);
+        assert_eq!((f.clone() * g.clone()).uint, 1u64);
+        assert_eq!((f.clone() + g.clone()).uint, q - 2);
+
+        let mut h = f.clone();
+        h *= Element::from(q, 2u64);
+        assert_eq!(h.uint, q - 2);
+
+        let mut h = f;
+        h += Element::from(q, 3u64);
+        assert_eq!(h.uint, 2u64);
+
+        // 2^32 * 2^32 = 2^64 = 59 mod q
+        let f = Element::from(q, 1u64 << 32);
+        assert_eq!((f.clone() * f).uint, 59u64);
+    }
+
+    #[test]
+    fn test_large_q_recompose() {
+        let q = gen_large_q();
+        for p in [2u64, 3, 1 << 16, 991] {
+            for v in [0u64, 1, p, q / 2, q - 2, q - 1] {
+                let e = Element::from(q, v);
+                let d = e.to_owned().decomposed(p);
+                assert_eq!(d.len(), Element::num_digits(q, p));
+                assert_eq!(Element::recompose(p, q, &d), e);
+            }
+        }
+    }
+
+    #[test]
+    fn test_power_of_two_q() {
+        let q = 1u64 << 32;
+        let f = Element::from(q, q - 1);
+        assert_eq!((f.clone() * f.clone()).uint, 1u64);
+        assert_eq!((f.clone() + Element::from(q, 2u64)).uint, 1u64);
+        assert_eq!((Element::from(q, 1u64) - Element::from(q, 2u64)).uint, q - 1);
+
+        for v in [0u64, 1, q / 3, q - 1] {
+            let e = Element::from(q, v);
+            let d = e.to_owned().decomposed(1 << 8);
+            assert_eq!(Element::recompose(1 << 8, q, &d), e);
+        }
+    }
+
+    #[test]
+    fn test_num_digits() {
+        assert_eq!(Element::num_digits(101, 2), 7);
+        assert_eq!(Element::num_digits(3329, 2), 12);
+        assert_eq!(Element::num_digits(5, 2), 3);
+        assert_eq!(Element::num_digits(1 << 32, 1 << 8), 4);
+        assert_eq!(Element::num_digits(gen_large_q(), 2), 64);
+    }
+
+    #[test]
+    fn test_try_from() {
+        use crate::error::Error;
+
+        assert_eq!(Element::try_from(101, 100), Ok(Element::from(101, 100)));
+        assert_eq!(Element::try_from(101, 101), Err(Error::ValueOutOfRange { value: 101, q: 101 }));
+        assert_eq!(Element::try_from(0, 0), Err(Error::InvalidModulus(0)));
+        assert_eq!(Element::try_from(u64::MAX, 0), Err(Error::InvalidModulus(u64::MAX)));
+    }
+
+    #[test]
+    fn test_gen_with_rng() {
+        use rand::{rngs::StdRng, SeedableRng};
+
         let q = gen_q();
-        for i in 0..100 {
-            let e = Element::gen_normal_rand(q.clone(), 6.4 as f64);
-            println!("{}", e);
+        let mut rng_0 = StdRng::seed_from_u64(7);
+        let mut rng_1 = StdRng::seed_from_u64(7);
+        for _ in 0..100 {
+            assert_eq!(
+                Element::gen_uniform_rand_with_rng(&mut rng_0, q),
+                Element::gen_uniform_rand_with_rng(&mut rng_1, q),
+            );
+            assert_eq!(
+                Element::gen_normal_rand_with_rng(&mut rng_0, q, 3.2),
+                Element::gen_normal_rand_with_rng(&mut rng_1, q, 3.2),
+            );
         }
     }
-    */
+
+    #[test]
+    fn test_gen_normal_rand() {
+        let q = 3329;
+        let std_dev = 6.4;
+        let num_samples = 20000;
+
+        let mut sum = 0f64;
+        let mut sum_sq = 0f64;
+        for _ in 0..num_samples {
+            let e = Element::gen_normal_rand(q, std_dev);
+            assert_eq!(e.q, q);
+            // Centred at 0, so negative samples wrap around to q - |x|
+            let x = if e.uint > q / 2 { e.uint as f64 - q as f64 } else { e.uint as f64 };
+            assert!(x.abs() <= super::GAUSSIAN_TAIL_CUT * std_dev);
+            sum += x;
+            sum_sq += x * x;
+        }
+
+        let mean = sum / num_samples as f64;
+        let sample_std_dev = (sum_sq / num_samples as f64 - mean * mean).sqrt();
+        assert!(mean.abs() < 0.3);
+        assert!((sample_std_dev - std_dev).abs() < 0.3);
+    }
 }
@@ -0,0 +1,1111 @@
+use crate::batchpir::{BatchPirAnswer, BatchPirParams, BatchPirQuery};
+use crate::database::RecordLayout;
+use crate::doublepir::{
+    DoublePIRParams, DoublePirAnswer, DoublePirHintDelta, DoublePirQuery, DoublePirRecordAnswer, DoublePirRecordQuery,
+};
+use crate::element::Element;
+use crate::keywordpir::KeywordPirParams;
+use crate::matrix::Matrix;
+use crate::params::LweParams;
+use crate::regev::Params;
+use crate::seeded::{Seed, SeededMatrix};
+use crate::simplepir::{
+    SimplePIRParams, SimplePirAnswer, SimplePirBatchAnswer, SimplePirBatchQuery, SimplePirGrowth, SimplePirHintDelta,
+    SimplePirQuery,
+};
+use std::fmt::{Display, Formatter};
+
+/// The version of the encoding, which is the first byte of every message
+pub const VERSION: u8 = 1;
+
+/// Why a message could not be decoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The message was encoded with a version this crate cannot read
+    UnsupportedVersion(u8),
+    /// The message holds a different type than the one being decoded
+    WrongTag { expected: u8, found: u8 },
+    /// The message ended early
+    UnexpectedEnd,
+    /// The message has bytes left over after its contents
+    TrailingBytes,
+    /// A modulus, dimension or value is out of range
+    InvalidValue,
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            DecodeError::UnsupportedVersion(v) => write!(f, "unsupported encoding version {}", v),
+            DecodeError::WrongTag { expected, found } => {
+                write!(f, "expected a message with tag {}, found tag {}", expected, found)
+            }
+            DecodeError::UnexpectedEnd => write!(f, "the message ended early"),
+            DecodeError::TrailingBytes => write!(f, "the message has trailing bytes"),
+            DecodeError::InvalidValue => write!(f, "the message contains an invalid value"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// The number of bits needed to store any value mod q, i.e. ceil(log2 q)
+pub fn bits_per_entry(q: u64) -> u32 {
+    u64::BITS - (q - 1).leading_zeros()
+}
+
+/// Builds the body of a message
+#[derive(Debug, Default)]
+pub struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    pub fn u8(&mut self, v: u8) {
+        self.bytes.push(v);
+    }
+
+    pub fn u64(&mut self, v: u64) {
+        self.bytes.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn usize(&mut self, v: usize) {
+        self.u64(v as u64);
+    }
+
+    pub fn f64(&mut self, v: f64) {
+        self.u64(v.to_bits());
+    }
+
+    pub fn seed(&mut self, seed: &Seed) {
+        self.bytes.extend_from_slice(seed);
+    }
+
+    /// Write values mod q using `bits_per_entry(q)` bits each, least significant bits first. The
+    /// last byte is padded with zeros.
+    pub fn packed(&mut self, q: u64, values: &[u64]) {
+        let bits = bits_per_entry(q);
+        let mut acc = 0u128;
+        let mut num_bits = 0;
+        for v in values {
+            acc |= (*v as u128) << num_bits;
+            num_bits += bits;
+            while num_bits >= 8 {
+                self.bytes.push(acc as u8);
+                acc >>= 8;
+                num_bits -= 8;
+            }
+        }
+        if num_bits > 0 {
+            self.bytes.push(acc as u8);
+        }
+    }
+}
+
+/// Reads the body of a message
+#[derive(Debug)]
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if self.bytes.len() < len {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u64(&mut self) -> Result<u64, DecodeError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn usize(&mut self) -> Result<usize, DecodeError> {
+        self.u64()?.try_into().map_err(|_| DecodeError::InvalidValue)
+    }
+
+    pub fn f64(&mut self) -> Result<f64, DecodeError> {
+        Ok(f64::from_bits(self.u64()?))
+    }
+
+    pub fn seed(&mut self) -> Result<Seed, DecodeError> {
+        Ok(self.take(32)?.try_into().unwrap())
+    }
+
+    /// A modulus which elements can be created with, and which needs at least one bit per value,
+    /// so that the length of packed values is bounded by the length of the message
+    pub fn modulus(&mut self) -> Result<u64, DecodeError> {
+        let q = self.u64()?;
+        if q < 2 || q == u64::MAX {
+            return Err(DecodeError::InvalidValue);
+        }
+        Ok(q)
+    }
+
+    /// Read `count` values written by `Writer::packed`, checking that each is below q
+    pub fn packed(&mut self, q: u64, count: usize) -> Result<Vec<u64>, DecodeError> {
+        let bits = bits_per_entry(q);
+        if bits == 0 {
+            return Err(DecodeError::InvalidValue);
+        }
+        // Check the length before allocating, since count is untrusted: each value takes at
+        // least one bit
+        if count > self.bytes.len().saturating_mul(8) {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        let total_bits = count.checked_mul(bits as usize).ok_or(DecodeError::InvalidValue)?;
+        let bytes = self.take(total_bits.div_ceil(8))?;
+
+        let mask = u64::MAX >> (u64::BITS - bits);
+        let mut values = Vec::with_capacity(count);
+        let mut bytes = bytes.iter();
+        let mut acc = 0u128;
+        let mut num_bits = 0;
+        for _ in 0..count {
+            while num_bits < bits {
+                acc |= (*bytes.next().unwrap() as u128) << num_bits;
+                num_bits += 8;
+            }
+            let v = acc as u64 & mask;
+            if v >= q {
+                return Err(DecodeError::InvalidValue);
+            }
+            values.push(v);
+            acc >>= bits;
+            num_bits -= bits;
+        }
+        // The padding must be zero, so that every message has a single encoding
+        if acc != 0 {
+            return Err(DecodeError::InvalidValue);
+        }
+        Ok(values)
+    }
+}
+
+/// A message which can be put on the wire.
+///
+/// Every message starts with a two-byte header: the encoding VERSION and the TAG of its type.
+/// Integers are little-endian u64s, and entries mod q are bit-packed after the modulus q.
+pub trait Encode: Sized {
+    /// Identifies the type of the message
+    const TAG: u8;
+
+    fn write_body(&self, w: &mut Writer);
+
+    fn read_body(r: &mut Reader) -> Result<Self, DecodeError>;
+
+    fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::default();
+        w.u8(VERSION);
+        w.u8(Self::TAG);
+        self.write_body(&mut w);
+        w.bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut r = Reader { bytes };
+        let version = r.u8()?;
+        if version != VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        let tag = r.u8()?;
+        if tag != Self::TAG {
+            return Err(DecodeError::WrongTag { expected: Self::TAG, found: tag });
+        }
+        let decoded = Self::read_body(&mut r)?;
+        if !r.bytes.is_empty() {
+            return Err(DecodeError::TrailingBytes);
+        }
+        Ok(decoded)
+    }
+}
+
+impl Encode for Element {
+    const TAG: u8 = 1;
+
+    fn write_body(&self, w: &mut Writer) {
+        w.u64(self.q);
+        w.packed(self.q, &[self.uint]);
+    }
+
+    fn read_body(r: &mut Reader) -> Result<Self, DecodeError> {
+        let q = r.modulus()?;
+        let uint = r.packed(q, 1)?[0];
+        Ok(Element::from(q, uint))
+    }
+}
+
+impl Encode for Matrix {
+    const TAG: u8 = 2;
+
+    fn write_body(&self, w: &mut Writer) {
+        w.u64(self.q());
+        w.usize(self.num_rows());
+        w.usize(self.num_cols());
+        w.packed(self.q(), self.data());
+    }
+
+    fn read_body(r: &mut Reader) -> Result<Self, DecodeError> {
+        let q = r.modulus()?;
+        let rows = r.usize()?;
+        let cols = r.usize()?;
+        let count = rows.checked_mul(cols).ok_or(DecodeError::InvalidValue)?;
+        let data = r.packed(q, count)?;
+        Ok(Matrix::from_raw(q, rows, cols, data))
+    }
+}
+
+impl Encode for SeededMatrix {
+    const TAG: u8 = 3;
+
+    fn write_body(&self, w: &mut Writer) {
+        w.seed(self.seed());
+        w.u64(self.q());
+        w.usize(self.num_rows());
+        w.usize(self.num_cols());
+    }
+
+    fn read_body(r: &mut Reader) -> Result<Self, DecodeError> {
+        let seed = r.seed()?;
+        let q = r.modulus()?;
+        let rows = r.usize()?;
+        let cols = r.usize()?;
+        Ok(SeededMatrix::new(seed, q, rows, cols))
+    }
+}
+
+/// A vector of elements which share a modulus, such as a query
+impl Encode for Vec<Element> {
+    const TAG: u8 = 4;
+
+    fn write_body(&self, w: &mut Writer) {
+        // An empty vector has no modulus, so any valid one is written
+        let q = self.first().map_or(2, |e| e.q);
+        assert!(self.iter().all(|e| e.q == q), "the elements must share a modulus");
+        w.u64(q);
+        w.usize(self.len());
+        w.packed(q, &self.iter().map(|e| e.uint).collect::<Vec<_>>());
+    }
+
+    fn read_body(r: &mut Reader) -> Result<Self, DecodeError> {
+        let q = r.modulus()?;
+        let len = r.usize()?;
+        let values = r.packed(q, len)?;
+        Ok(values.into_iter().map(|v| Element::from(q, v)).collect())
+    }
+}
+
+/// Pairs of messages, such as DoublePIR queries and answers
+impl<A: Encode, B: Encode> Encode for (A, B) {
+    const TAG: u8 = 5;
+
+    fn write_body(&self, w: &mut Writer) {
+        self.0.write_body(w);
+        self.1.write_body(w);
+    }
+
+    fn read_body(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok((A::read_body(r)?, B::read_body(r)?))
+    }
+}
+
+impl Encode for SimplePIRParams {
+    const TAG: u8 = 6;
+
+    fn write_body(&self, w: &mut Writer) {
+        self.a.write_body(w);
+        w.u64(self.q);
+        w.u64(self.p);
+        w.usize(self.n);
+        w.usize(self.l);
+        w.usize(self.m);
+        w.f64(self.std_dev);
+    }
+
+    fn read_body(r: &mut Reader) -> Result<Self, DecodeError> {
+        let a = SeededMatrix::read_body(r)?;
+        let (q, p) = (r.modulus()?, r.modulus()?);
+        let (n, l, m) = (r.usize()?, r.usize()?, r.usize()?);
+        let std_dev = r.f64()?;
+        if a.dimensions() != (m, n) || a.q() != q || !(LweParams { n, q, p, std_dev }).is_valid() {
+            return Err(DecodeError::InvalidValue);
+        }
+        Ok(SimplePIRParams { a, q, p, n, l, m, std_dev })
+    }
+}
+
+impl Encode for DoublePIRParams {
+    const TAG: u8 = 7;
+
+    fn write_body(&self, w: &mut Writer) {
+        self.a_1.write_body(w);
+        self.a_2.write_body(w);
+        w.u64(self.q);
+        w.u64(self.p);
+        w.u64(self.base);
+        w.usize(self.n);
+        w.usize(self.l);
+        w.usize(self.m);
+        w.f64(self.std_dev);
+    }
+
+    fn read_body(r: &mut Reader) -> Result<Self, DecodeError> {
+        let a_1 = SeededMatrix::read_body(r)?;
+        let a_2 = SeededMatrix::read_body(r)?;
+        let (q, p, base) = (r.modulus()?, r.modulus()?, r.u64()?);
+        let (n, l, m) = (r.usize()?, r.usize()?, r.usize()?);
+        let std_dev = r.f64()?;
+        if a_1.dimensions() != (m, n) || a_2.dimensions() != (l, n) || a_1.q() != q || a_2.q() != q {
+            return Err(DecodeError::InvalidValue);
+        }
+        if !(2..=p).contains(&base) || !(LweParams { n, q, p, std_dev }).is_valid() {
+            return Err(DecodeError::InvalidValue);
+        }
+        Ok(DoublePIRParams { a_1, a_2, q, p, base, n, l, m, std_dev })
+    }
+}
+
+/// The largest public matrix which decoding `Params` expands, which is far larger than any
+/// parameters this crate generates
+pub const MAX_EXPANDED_ENTRIES: usize = 1 << 24;
+
+/// Regev parameters are sent as the seed of A, which the receiver expands again
+impl Encode for Params {
+    const TAG: u8 = 8;
+
+    fn write_body(&self, w: &mut Writer) {
+        w.seed(&self.seed);
+        w.u64(self.q);
+        w.u64(self.p);
+        w.usize(self.n);
+        w.usize(self.m);
+        w.f64(self.std_dev);
+    }
+
+    fn read_body(r: &mut Reader) -> Result<Self, DecodeError> {
+        let seed = r.seed()?;
+        let (q, p) = (r.modulus()?, r.modulus()?);
+        let (n, m) = (r.usize()?, r.usize()?);
+        let std_dev = r.f64()?;
+        // A is expanded from its seed while decoding, so its size must be bounded
+        if n.checked_mul(m).is_none_or(|entries| entries > MAX_EXPANDED_ENTRIES) {
+            return Err(DecodeError::InvalidValue);
+        }
+        if !(LweParams { n, q, p, std_dev }).is_valid() {
+            return Err(DecodeError::InvalidValue);
+        }
+        Ok(Params::from_seed(seed, q, p, n, m, std_dev))
+    }
+}
+
+impl Encode for SimplePirQuery {
+    const TAG: u8 = 9;
+
+    fn write_body(&self, w: &mut Writer) {
+        self.0.write_body(w);
+    }
+
+    fn read_body(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(SimplePirQuery(Vec::read_body(r)?))
+    }
+}
+
+impl Encode for SimplePirAnswer {
+    const TAG: u8 = 10;
+
+    fn write_body(&self, w: &mut Writer) {
+        self.0.write_body(w);
+    }
+
+    fn read_body(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(SimplePirAnswer(Matrix::read_body(r)?))
+    }
+}
+
+impl Encode for DoublePirQuery {
+    const TAG: u8 = 12;
+
+    fn write_body(&self, w: &mut Writer) {
+        self.c_1.write_body(w);
+        self.c_2.write_body(w);
+    }
+
+    fn read_body(r: &mut Reader) -> Result<Self, DecodeError> {
+        let c_1 = Matrix::read_body(r)?;
+        let c_2 = Matrix::read_body(r)?;
+        Ok(DoublePirQuery { c_1, c_2 })
+    }
+}
+
+impl Encode for DoublePirAnswer {
+    const TAG: u8 = 13;
+
+    fn write_body(&self, w: &mut Writer) {
+        self.h.write_body(w);
+        self.ans_h_ans_2.write_body(w);
+    }
+
+    fn read_body(r: &mut Reader) -> Result<Self, DecodeError> {
+        let h = Matrix::read_body(r)?;
+        let ans_h_ans_2 = Matrix::read_body(r)?;
+        Ok(DoublePirAnswer { h, ans_h_ans_2 })
+    }
+}
+
+/// Write a list of matrices, preceded by their number
+fn write_matrices(w: &mut Writer, matrices: &[Matrix]) {
+    w.usize(matrices.len());
+    for m in matrices {
+        m.write_body(w);
+    }
+}
+
+fn read_matrices(r: &mut Reader) -> Result<Vec<Matrix>, DecodeError> {
+    // Not preallocated, since the count is untrusted
+    let count = r.usize()?;
+    let mut matrices = Vec::new();
+    for _ in 0..count {
+        matrices.push(Matrix::read_body(r)?);
+    }
+    Ok(matrices)
+}
+
+impl Encode for DoublePirRecordQuery {
+    const TAG: u8 = 14;
+
+    fn write_body(&self, w: &mut Writer) {
+        self.c_1.write_body(w);
+        write_matrices(w, &self.c_2);
+    }
+
+    fn read_body(r: &mut Reader) -> Result<Self, DecodeError> {
+        let c_1 = Matrix::read_body(r)?;
+        let c_2 = read_matrices(r)?;
+        Ok(DoublePirRecordQuery { c_1, c_2 })
+    }
+}
+
+impl Encode for DoublePirRecordAnswer {
+    const TAG: u8 = 15;
+
+    fn write_body(&self, w: &mut Writer) {
+        self.h.write_body(w);
+        write_matrices(w, &self.ans_h_ans_2);
+    }
+
+    fn read_body(r: &mut Reader) -> Result<Self, DecodeError> {
+        let h = Matrix::read_body(r)?;
+        let ans_h_ans_2 = read_matrices(r)?;
+        Ok(DoublePirRecordAnswer { h, ans_h_ans_2 })
+    }
+}
+
+/// Write a list of indices, preceded by their number
+fn write_indices(w: &mut Writer, indices: &[usize]) {
+    w.usize(indices.len());
+    for i in indices {
+        w.usize(*i);
+    }
+}
+
+fn read_indices(r: &mut Reader) -> Result<Vec<usize>, DecodeError> {
+    let count = r.usize()?;
+    let mut indices = Vec::new();
+    for _ in 0..count {
+        indices.push(r.usize()?);
+    }
+    Ok(indices)
+}
+
+impl Encode for SimplePirHintDelta {
+    const TAG: u8 = 16;
+
+    fn write_body(&self, w: &mut Writer) {
+        write_indices(w, &self.rows);
+        write_indices(w, &self.cols);
+        self.diffs.write_body(w);
+    }
+
+    fn read_body(r: &mut Reader) -> Result<Self, DecodeError> {
+        let rows = read_indices(r)?;
+        let cols = read_indices(r)?;
+        let diffs = Vec::<Element>::read_body(r)?;
+        Ok(SimplePirHintDelta { rows, cols, diffs })
+    }
+}
+
+impl Encode for DoublePirHintDelta {
+    const TAG: u8 = 17;
+
+    fn write_body(&self, w: &mut Writer) {
+        write_indices(w, &self.rows);
+        self.hint_s_diff.write_body(w);
+    }
+
+    fn read_body(r: &mut Reader) -> Result<Self, DecodeError> {
+        let rows = read_indices(r)?;
+        let hint_s_diff = Matrix::read_body(r)?;
+        Ok(DoublePirHintDelta { rows, hint_s_diff })
+    }
+}
+
+impl Encode for SimplePirGrowth {
+    const TAG: u8 = 18;
+
+    fn write_body(&self, w: &mut Writer) {
+        match self {
+            SimplePirGrowth::Rows(hint_rows) => {
+                w.u8(0);
+                hint_rows.write_body(w);
+            }
+            SimplePirGrowth::Cols { cols, hint_diff } => {
+                w.u8(1);
+                w.usize(*cols);
+                hint_diff.write_body(w);
+            }
+        }
+    }
+
+    fn read_body(r: &mut Reader) -> Result<Self, DecodeError> {
+        match r.u8()? {
+            0 => Ok(SimplePirGrowth::Rows(Matrix::read_body(r)?)),
+            1 => {
+                let cols = r.usize()?;
+                let hint_diff = Matrix::read_body(r)?;
+                Ok(SimplePirGrowth::Cols { cols, hint_diff })
+            }
+            _ => Err(DecodeError::InvalidValue),
+        }
+    }
+}
+
+impl Encode for SimplePirBatchQuery {
+    const TAG: u8 = 19;
+
+    fn write_body(&self, w: &mut Writer) {
+        self.0.write_body(w);
+    }
+
+    fn read_body(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(SimplePirBatchQuery(Matrix::read_body(r)?))
+    }
+}
+
+impl Encode for SimplePirBatchAnswer {
+    const TAG: u8 = 20;
+
+    fn write_body(&self, w: &mut Writer) {
+        self.0.write_body(w);
+    }
+
+    fn read_body(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(SimplePirBatchAnswer(Matrix::read_body(r)?))
+    }
+}
+
+impl Encode for BatchPirParams {
+    const TAG: u8 = 21;
+
+    fn write_body(&self, w: &mut Writer) {
+        w.seed(&self.hash_seed);
+        w.usize(self.num_records);
+        w.usize(self.record_size);
+        w.usize(self.batch_size);
+        w.usize(self.num_hashes);
+        w.usize(self.num_buckets);
+        self.simplepir.write_body(w);
+    }
+
+    fn read_body(r: &mut Reader) -> Result<Self, DecodeError> {
+        let hash_seed = r.seed()?;
+        let (num_records, record_size, batch_size) = (r.usize()?, r.usize()?, r.usize()?);
+        let (num_hashes, num_buckets) = (r.usize()?, r.usize()?);
+        let simplepir = SimplePIRParams::read_body(r)?;
+        if num_records == 0 || record_size == 0 || num_hashes == 0 || num_buckets < num_hashes {
+            return Err(DecodeError::InvalidValue);
+        }
+        let params =
+            BatchPirParams { hash_seed, num_records, record_size, batch_size, num_hashes, num_buckets, simplepir };
+        params.try_layout().ok_or(DecodeError::InvalidValue)?;
+        Ok(params)
+    }
+}
+
+impl Encode for BatchPirQuery {
+    const TAG: u8 = 22;
+
+    fn write_body(&self, w: &mut Writer) {
+        w.usize(self.0.len());
+        for query in &self.0 {
+            query.write_body(w);
+        }
+    }
+
+    fn read_body(r: &mut Reader) -> Result<Self, DecodeError> {
+        // Not preallocated, since the count is untrusted
+        let count = r.usize()?;
+        let mut queries = Vec::new();
+        for _ in 0..count {
+            queries.push(SimplePirQuery::read_body(r)?);
+        }
+        Ok(BatchPirQuery(queries))
+    }
+}
+
+impl Encode for BatchPirAnswer {
+    const TAG: u8 = 23;
+
+    fn write_body(&self, w: &mut Writer) {
+        let answers: Vec<Matrix> = self.0.iter().map(|a| a.0.clone()).collect();
+        write_matrices(w, &answers);
+    }
+
+    fn read_body(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(BatchPirAnswer(read_matrices(r)?.into_iter().map(SimplePirAnswer).collect()))
+    }
+}
+
+impl Encode for KeywordPirParams {
+    const TAG: u8 = 24;
+
+    fn write_body(&self, w: &mut Writer) {
+        w.seed(&self.hash_seed);
+        w.usize(self.num_buckets);
+        w.usize(self.bucket_size);
+        w.usize(self.value_size);
+        self.simplepir.write_body(w);
+    }
+
+    fn read_body(r: &mut Reader) -> Result<Self, DecodeError> {
+        let hash_seed = r.seed()?;
+        let (num_buckets, bucket_size, value_size) = (r.usize()?, r.usize()?, r.usize()?);
+        let simplepir = SimplePIRParams::read_body(r)?;
+        if num_buckets == 0 || num_buckets != simplepir.m {
+            return Err(DecodeError::InvalidValue);
+        }
+        let params = KeywordPirParams { hash_seed, num_buckets, bucket_size, value_size, simplepir };
+        params.try_layout().ok_or(DecodeError::InvalidValue)?;
+        Ok(params)
+    }
+}
+
+impl Encode for RecordLayout {
+    const TAG: u8 = 11;
+
+    fn write_body(&self, w: &mut Writer) {
+        w.usize(self.num_records);
+        w.usize(self.record_size);
+        w.usize(self.bits_per_digit);
+        w.usize(self.digits_per_record);
+        w.usize(self.records_per_col);
+    }
+
+    fn read_body(r: &mut Reader) -> Result<Self, DecodeError> {
+        let layout = RecordLayout {
+            num_records: r.usize()?,
+            record_size: r.usize()?,
+            bits_per_digit: r.usize()?,
+            digits_per_record: r.usize()?,
+            records_per_col: r.usize()?,
+        };
+        if layout.bits_per_digit == 0
+            || layout.bits_per_digit >= 64
+            || layout.records_per_col == 0
+            || layout.record_size.checked_mul(8).map(|bits| bits.div_ceil(layout.bits_per_digit))
+                != Some(layout.digits_per_record)
+        {
+            return Err(DecodeError::InvalidValue);
+        }
+        Ok(layout)
+    }
+}
+
+/// Serialize messages as their binary encoding, so that serde formats stay compact
+#[cfg(feature = "serde")]
+mod serde_impls {
+    use super::*;
+    use serde::de::{Error, SeqAccess, Visitor};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut Formatter) -> std::fmt::Result {
+            write!(f, "an encoded message")
+        }
+
+        fn visit_bytes<E: Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+            Ok(v.to_vec())
+        }
+
+        fn visit_byte_buf<E: Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+            Ok(v)
+        }
+
+        fn visit_seq<S: SeqAccess<'de>>(self, mut seq: S) -> Result<Self::Value, S::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(1 << 20));
+            while let Some(b) = seq.next_element()? {
+                bytes.push(b);
+            }
+            Ok(bytes)
+        }
+    }
+
+    macro_rules! impl_serde {
+        ($($t:ty),*) => {
+            $(
+                impl Serialize for $t {
+                    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                        serializer.serialize_bytes(&Encode::encode(self))
+                    }
+                }
+
+                impl<'de> Deserialize<'de> for $t {
+                    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                        let bytes = deserializer.deserialize_bytes(BytesVisitor)?;
+                        <$t as Encode>::decode(&bytes).map_err(D::Error::custom)
+                    }
+                }
+            )*
+        };
+    }
+
+    impl_serde!(
+        Element,
+        Matrix,
+        SeededMatrix,
+        SimplePIRParams,
+        DoublePIRParams,
+        Params,
+        SimplePirQuery,
+        SimplePirAnswer,
+        DoublePirQuery,
+        DoublePirAnswer,
+        DoublePirRecordQuery,
+        DoublePirRecordAnswer,
+        SimplePirHintDelta,
+        DoublePirHintDelta,
+        SimplePirGrowth,
+        SimplePirBatchQuery,
+        SimplePirBatchAnswer,
+        BatchPirParams,
+        BatchPirQuery,
+        BatchPirAnswer,
+        KeywordPirParams,
+        RecordLayout
+    );
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::testing::test_lwe;
+    use crate::{batchpir, doublepir, keywordpir, regev, simplepir};
+
+    fn round_trip<T: Encode + PartialEq + std::fmt::Debug>(value: &T) {
+        assert_eq!(&T::decode(&value.encode()).unwrap(), value);
+    }
+
+    #[test]
+    fn test_bits_per_entry() {
+        assert_eq!(bits_per_entry(1), 0);
+        assert_eq!(bits_per_entry(2), 1);
+        assert_eq!(bits_per_entry(3329), 12);
+        assert_eq!(bits_per_entry(1 << 32), 32);
+        assert_eq!(bits_per_entry(u64::MAX - 58), 64);
+    }
+
+    #[test]
+    fn test_matrix() {
+        for q in [2u64, 3, 991, 3329, 1 << 32, u64::MAX - 58] {
+            let m = Matrix::gen_uniform_rand(q, 7, 13);
+            round_trip(&m);
+
+            // Bit-packed after a 26-byte header
+            let bits = bits_per_entry(q) as usize;
+            assert_eq!(m.encode().len(), 2 + 24 + (7 * 13 * bits).div_ceil(8));
+        }
+        round_trip(&Matrix::zeros(3329, 0, 5));
+        round_trip(&Element::from(3329, 3328));
+    }
+
+    #[test]
+    fn test_simplepir_messages() {
+        let params = simplepir::gen_params();
+        round_trip(&params);
+        round_trip(&params.a);
+
+        let db = simplepir::gen_db(&params);
+        let server = simplepir::SimplePirServer::new(params.clone(), &db);
+        let hint = Matrix::decode(&server.hint().encode()).unwrap();
+        let params = SimplePIRParams::decode(&params.encode()).unwrap();
+
+        // The client only sees decoded messages
+        let client = simplepir::SimplePirClient::new(params, hint);
+        let (query, state) = client.query(3, 5);
+        round_trip(&query);
+        let query = SimplePirQuery::decode(&query.encode()).unwrap();
+        let answer = SimplePirAnswer::decode(&server.answer(&query).encode()).unwrap();
+        assert_eq!(client.recover(&state, &answer), db.get(3, 5));
+
+        let (query, state) = client.query_batch(&[(3, 5), (0, 1)]);
+        round_trip(&query);
+        let query = SimplePirBatchQuery::decode(&query.encode()).unwrap();
+        let answer = SimplePirBatchAnswer::decode(&server.answer_batch(&query).encode()).unwrap();
+        assert_eq!(client.recover_batch(&state, &answer), vec![db.get(3, 5), db.get(0, 1)]);
+    }
+
+    #[test]
+    fn test_doublepir_messages() {
+        let params = doublepir::gen_params();
+        round_trip(&params);
+        round_trip(&doublepir::gen_params_with(4, 8, 32, 1 << 32, 256, 6.4).with_base(16));
+
+        let db = doublepir::gen_db(&params);
+        let hints = doublepir::gen_hints(&params, &db);
+        let s_1 = regev::gen_secret(params.q, params.n);
+        let s_2 = regev::gen_secret(params.q, params.n);
+        let query = doublepir::query(&params, 1, 2, &s_1, &s_2);
+        round_trip(&query);
+        let answer = doublepir::answer(&params, &db, &hints.0, &query);
+        round_trip(&answer);
+
+        let answer = <(Matrix, Matrix)>::decode(&answer.encode()).unwrap();
+        let hint_c = Matrix::decode(&hints.1.encode()).unwrap();
+        assert_eq!(doublepir::recover(&params, &hint_c, &answer, &s_1, &s_2), db.get(1, 2));
+
+        // The same exchange through the client and server
+        let server = doublepir::DoublePirServer::new(params.clone(), &db);
+        let hint_c = Matrix::decode(&server.hint().encode()).unwrap();
+        let client = doublepir::DoublePirClient::new(params, hint_c);
+        let (query, state) = client.query(3, 5);
+        round_trip(&query);
+        let query = DoublePirQuery::decode(&query.encode()).unwrap();
+        let answer = server.answer(&query);
+        round_trip(&answer);
+        let answer = DoublePirAnswer::decode(&answer.encode()).unwrap();
+        assert_eq!(client.recover(&state, &answer), db.get(3, 5));
+
+        // And for a record spread over several rows
+        let records = vec![vec![0x5a], vec![0xc3], vec![0x0f], vec![0xf0]];
+        let params = doublepir::gen_params_with(8, 4, 32, 3329, 2, 6.4);
+        let (db, layout) = doublepir::gen_db_from_records(&params, &records);
+        let server = doublepir::DoublePirServer::new(params.clone(), &db);
+        let client = doublepir::DoublePirClient::new(params, server.hint().clone());
+        let (query, state) = client.query_record(&layout, 2);
+        round_trip(&query);
+        let query = DoublePirRecordQuery::decode(&query.encode()).unwrap();
+        let answer = server.answer_record(&query);
+        round_trip(&answer);
+        let answer = DoublePirRecordAnswer::decode(&answer.encode()).unwrap();
+        assert_eq!(client.recover_record(&layout, &state, &answer), records[2]);
+    }
+
+    #[test]
+    fn test_batchpir_messages() {
+        let params = batchpir::gen_params_with(40, 2, 4, 3, test_lwe());
+        round_trip(&params);
+
+        let records: Vec<Vec<u8>> = (0..40u8).map(|i| vec![i, 255 - i]).collect();
+        let server = batchpir::BatchPirServer::new(params.clone(), &records);
+        let params = BatchPirParams::decode(&params.encode()).unwrap();
+        let hints = server.hints().iter().map(|h| Matrix::decode(&h.encode()).unwrap()).collect();
+        let client = batchpir::BatchPirClient::new(params, hints);
+
+        let (query, state) = client.query(&[7, 30]);
+        round_trip(&query);
+        let query = BatchPirQuery::decode(&query.encode()).unwrap();
+        let answer = server.answer(&query);
+        round_trip(&answer);
+        let answer = BatchPirAnswer::decode(&answer.encode()).unwrap();
+        assert_eq!(client.recover(&state, &answer), vec![records[7].clone(), records[30].clone()]);
+
+        // Records which do not fit in a bucket's database, or too many of them to hash, are
+        // rejected before a client is built from the params
+        let params = server.params();
+        let entries = params.simplepir.l * params.simplepir.m * params.num_buckets;
+        for (num_records, record_size) in [(40, params.simplepir.l), (entries, 2), (usize::MAX, 2)] {
+            let bad = BatchPirParams { num_records, record_size, ..params.clone() };
+            assert_eq!(BatchPirParams::decode(&bad.encode()), Err(DecodeError::InvalidValue));
+        }
+    }
+
+    #[test]
+    fn test_keywordpir_messages() {
+        let store = vec![("alice", vec![1, 2, 3]), ("bob", vec![]), ("carol", vec![9])];
+        let params = keywordpir::gen_params_with(&store, test_lwe());
+        round_trip(&params);
+
+        let server = keywordpir::KeywordPirServer::new(params.clone(), &store);
+        let params = KeywordPirParams::decode(&params.encode()).unwrap();
+        let hint = Matrix::decode(&server.hint().encode()).unwrap();
+        let client = keywordpir::KeywordPirClient::new(params.clone(), hint);
+        let (query, state) = client.query(b"alice");
+        let query = SimplePirBatchQuery::decode(&query.encode()).unwrap();
+        let answer = SimplePirBatchAnswer::decode(&server.answer(&query).encode()).unwrap();
+        assert_eq!(client.recover(&state, &answer), Some(vec![1, 2, 3]));
+
+        // Columns which do not hold exactly one bucket, including sizes which overflow, are
+        // rejected, so that the layout of decoded params never panics
+        for (bucket_size, value_size) in [
+            (params.bucket_size + 1, params.value_size),
+            (0, params.value_size),
+            (usize::MAX, params.value_size),
+            (params.bucket_size, usize::MAX),
+            (params.bucket_size, usize::MAX / 8),
+        ] {
+            let bad = KeywordPirParams { bucket_size, value_size, ..params.clone() };
+            assert_eq!(KeywordPirParams::decode(&bad.encode()), Err(DecodeError::InvalidValue));
+        }
+    }
+
+    #[test]
+    fn test_other_messages() {
+        round_trip(&regev::simple_params());
+        round_trip(&RecordLayout::new(5, 4, 11, 25));
+        round_trip(&(Matrix::gen_uniform_rand(5, 2, 2), Element::from(7, 6)));
+        round_trip(&Vec::<Element>::new());
+        round_trip(&SimplePirHintDelta {
+            rows: vec![3, 1],
+            cols: vec![0, 7],
+            diffs: vec![Element::from(1 << 32, 5), Element::from(1 << 32, (1 << 32) - 5)],
+        });
+        round_trip(&SimplePirGrowth::Rows(Matrix::gen_uniform_rand(3329, 2, 8)));
+        round_trip(&SimplePirGrowth::Cols { cols: 4, hint_diff: Matrix::gen_uniform_rand(3329, 8, 8) });
+        round_trip(&DoublePirHintDelta { rows: vec![], hint_s_diff: Matrix::zeros(1 << 32, 12, 0) });
+    }
+
+    #[test]
+    fn test_invalid() {
+        let m = Matrix::gen_uniform_rand(3329, 4, 4);
+        let bytes = m.encode();
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[0] = VERSION + 1;
+        assert_eq!(Matrix::decode(&wrong_version), Err(DecodeError::UnsupportedVersion(VERSION + 1)));
+
+        assert_eq!(
+            Element::decode(&bytes),
+            Err(DecodeError::WrongTag { expected: Element::TAG, found: Matrix::TAG }),
+        );
+        assert_eq!(Matrix::decode(&bytes[..bytes.len() - 1]), Err(DecodeError::UnexpectedEnd));
+        assert_eq!(Matrix::decode(&[bytes.clone(), vec![0]].concat()), Err(DecodeError::TrailingBytes));
+        assert_eq!(Matrix::decode(&[]), Err(DecodeError::UnexpectedEnd));
+
+        // An entry of 4095 >= q
+        let mut too_large = Matrix::zeros(3329, 1, 1).encode();
+        too_large[26] = 0xff;
+        too_large[27] = 0x0f;
+        assert_eq!(Matrix::decode(&too_large), Err(DecodeError::InvalidValue));
+
+        // Non-zero padding
+        let mut padding = Matrix::zeros(3329, 1, 1).encode();
+        padding[27] = 0x10;
+        assert_eq!(Matrix::decode(&padding), Err(DecodeError::InvalidValue));
+
+        // Dimensions which would need far more data than was sent
+        let mut huge = Matrix::zeros(3329, 1, 1).encode();
+        huge[10..18].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(Matrix::decode(&huge).is_err());
+
+        // A modulus of 1 would pack values into no bits, so no length would bound the count
+        let mut degenerate = vec![VERSION, SimplePirQuery::TAG];
+        degenerate.extend_from_slice(&1u64.to_le_bytes());
+        degenerate.extend_from_slice(&(1u64 << 61).to_le_bytes());
+        assert_eq!(SimplePirQuery::decode(&degenerate), Err(DecodeError::InvalidValue));
+        let mut packed = Reader { bytes: &[0; 4] };
+        assert_eq!(packed.packed(2, 33), Err(DecodeError::UnexpectedEnd));
+        assert_eq!(packed.packed(1, 1), Err(DecodeError::InvalidValue));
+
+        // Parameters whose public matrix is too large to expand
+        let mut params = regev::simple_params();
+        params.n = 1 << 20;
+        params.m = 1 << 20;
+        assert_eq!(Params::decode(&params.encode()), Err(DecodeError::InvalidValue));
+
+        // Parameters whose errors cannot be sampled, or whose plaintexts do not fit mod q
+        for (p, std_dev) in [(2, f64::NAN), (2, f64::INFINITY), (2, -6.4), (2, 0.0), (1 << 33, 6.4)] {
+            let mut params = simplepir::gen_params_with(4, 4, 16, 1 << 32, 2, 6.4);
+            (params.p, params.std_dev) = (p, std_dev);
+            assert_eq!(SimplePIRParams::decode(&params.encode()), Err(DecodeError::InvalidValue));
+
+            let mut params = doublepir::gen_params_with(4, 4, 16, 1 << 32, 2, 6.4);
+            (params.p, params.std_dev) = (p, std_dev);
+            assert_eq!(DoublePIRParams::decode(&params.encode()), Err(DecodeError::InvalidValue));
+
+            let mut params = regev::simple_params();
+            (params.p, params.std_dev) = (p.min(params.q + 1), std_dev);
+            assert_eq!(Params::decode(&params.encode()), Err(DecodeError::InvalidValue));
+        }
+    }
+
+    // Decoding `body` as a T must fail without panicking
+    fn assert_rejected<T: Encode + std::fmt::Debug>(body: &[u8]) {
+        let bytes = [&[VERSION, T::TAG][..], body].concat();
+        assert!(T::decode(&bytes).is_err(), "decoded a {} from {:?}", std::any::type_name::<T>(), body);
+    }
+
+    #[test]
+    fn test_degenerate_moduli() {
+        let u64s = |values: &[u64]| values.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>();
+        let valid_matrix = Matrix::zeros(3329, 1, 1).encode()[2..].to_vec();
+
+        for q in [0, 1] {
+            // A matrix of 2^62 entries, and a vector of 2^61, with nothing after them
+            let matrix = u64s(&[q, 1 << 31, 1 << 31]);
+            let vector = u64s(&[q, 1 << 61]);
+
+            assert_rejected::<Element>(&u64s(&[q, 0]));
+            assert_rejected::<Matrix>(&matrix);
+            assert_rejected::<Vec<Element>>(&vector);
+            assert_rejected::<(Matrix, Element)>(&matrix);
+            assert_rejected::<(Matrix, Matrix)>(&[valid_matrix.clone(), matrix.clone()].concat());
+            assert_rejected::<SimplePirQuery>(&vector);
+            assert_rejected::<SimplePirAnswer>(&matrix);
+            assert_rejected::<SimplePirBatchQuery>(&matrix);
+            assert_rejected::<SimplePirBatchAnswer>(&matrix);
+            assert_rejected::<SimplePirHintDelta>(&[u64s(&[0, 0]), vector.clone()].concat());
+            assert_rejected::<SimplePirGrowth>(&[&[0][..], &matrix].concat());
+            assert_rejected::<SimplePirGrowth>(&[&[1][..], &u64s(&[4]), &matrix].concat());
+            for first in [true, false] {
+                let pair = if first {
+                    [matrix.clone(), valid_matrix.clone()].concat()
+                } else {
+                    [valid_matrix.clone(), matrix.clone()].concat()
+                };
+                assert_rejected::<DoublePirQuery>(&pair);
+                assert_rejected::<DoublePirAnswer>(&pair);
+            }
+            for record in [
+                [matrix.clone(), u64s(&[0])].concat(),
+                [valid_matrix.clone(), u64s(&[1]), matrix.clone()].concat(),
+            ] {
+                assert_rejected::<DoublePirRecordQuery>(&record);
+                assert_rejected::<DoublePirRecordAnswer>(&record);
+            }
+            assert_rejected::<DoublePirHintDelta>(&[u64s(&[0]), matrix.clone()].concat());
+            assert_rejected::<BatchPirQuery>(&[u64s(&[1]), vector.clone()].concat());
+            assert_rejected::<BatchPirAnswer>(&[u64s(&[1]), matrix.clone()].concat());
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let params = simplepir::gen_params();
+        let json = serde_json::to_string(&params).unwrap();
+        assert_eq!(serde_json::from_str::<SimplePIRParams>(&json).unwrap(), params);
+
+        let m = Matrix::gen_uniform_rand(3329, 3, 3);
+        let json = serde_json::to_string(&(m.clone(), Element::from(5, 4))).unwrap();
+        assert_eq!(serde_json::from_str::<(Matrix, Element)>(&json).unwrap(), (m, Element::from(5, 4)));
+    }
+}
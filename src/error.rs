@@ -0,0 +1,120 @@
+use crate::element::Element;
+use crate::encoding::DecodeError;
+use std::fmt::{Display, Formatter};
+
+/// The errors returned by the fallible `try_` variants of the public APIs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// A matrix does not have the (rows, columns) needed
+    DimensionMismatch { expected: (usize, usize), found: (usize, usize) },
+    /// A vector does not have the number of entries needed
+    LengthMismatch { expected: usize, found: usize },
+    /// Values mod different moduli were combined
+    ModulusMismatch { expected: u64, found: u64 },
+    /// A modulus which elements cannot be created with
+    InvalidModulus(u64),
+    /// An index past the end of a database or vector
+    IndexOutOfRange { index: usize, len: usize },
+    /// A value which is not reduced mod its modulus
+    ValueOutOfRange { value: u64, q: u64 },
+    /// A message could not be decoded
+    Decode(DecodeError),
+    /// Answers would fail to decrypt with too high a probability
+    FailureProbabilityTooHigh,
+    /// The records of a batch could not be placed into buckets with one record per bucket
+    HashingFailed,
+    /// A bucket has more entries than fit in it
+    BucketOverflow { bucket: usize },
+    /// Entry `index` of a key-value store has the same key as an earlier entry
+    DuplicateKey { index: usize },
+    /// A value is longer than the parameters allow
+    ValueTooLong { len: usize, max: usize },
+    /// A database would grow by no rows or columns, or by more than it can hold
+    InvalidGrowth(usize),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Error::DimensionMismatch { expected, found } => {
+                write!(f, "expected a {} x {} matrix, found {} x {}", expected.0, expected.1, found.0, found.1)
+            }
+            Error::LengthMismatch { expected, found } => {
+                write!(f, "expected {} entries, found {}", expected, found)
+            }
+            Error::ModulusMismatch { expected, found } => {
+                write!(f, "expected values mod {}, found values mod {}", expected, found)
+            }
+            Error::InvalidModulus(q) => write!(f, "invalid modulus {}", q),
+            Error::IndexOutOfRange { index, len } => {
+                write!(f, "index {} is out of range for length {}", index, len)
+            }
+            Error::ValueOutOfRange { value, q } => write!(f, "value {} is not reduced mod {}", value, q),
+            Error::Decode(e) => write!(f, "{}", e),
+            Error::FailureProbabilityTooHigh => {
+                write!(f, "answers would fail to decrypt with too high a probability")
+            }
+            Error::HashingFailed => write!(f, "the batch could not be placed into buckets"),
+            Error::BucketOverflow { bucket } => write!(f, "bucket {} has more entries than fit in it", bucket),
+            Error::DuplicateKey { index } => write!(f, "entry {} has the same key as an earlier entry", index),
+            Error::ValueTooLong { len, max } => write!(f, "a value of {} bytes is longer than {} bytes", len, max),
+            Error::InvalidGrowth(count) => write!(f, "a database cannot grow by {} rows or columns", count),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<DecodeError> for Error {
+    fn from(e: DecodeError) -> Self {
+        Error::Decode(e)
+    }
+}
+
+pub(crate) fn check_modulus(expected: u64, found: u64) -> Result<()> {
+    if expected != found {
+        return Err(Error::ModulusMismatch { expected, found });
+    }
+    Ok(())
+}
+
+pub(crate) fn check_len(expected: usize, found: usize) -> Result<()> {
+    if expected != found {
+        return Err(Error::LengthMismatch { expected, found });
+    }
+    Ok(())
+}
+
+pub(crate) fn check_dimensions(expected: (usize, usize), found: (usize, usize)) -> Result<()> {
+    if expected != found {
+        return Err(Error::DimensionMismatch { expected, found });
+    }
+    Ok(())
+}
+
+/// Check that every element is mod q and reduced
+pub(crate) fn check_elements(q: u64, elements: &[Element]) -> Result<()> {
+    for e in elements {
+        check_modulus(q, e.q)?;
+        if e.uint >= e.q {
+            return Err(Error::ValueOutOfRange { value: e.uint, q });
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn check_index(index: usize, len: usize) -> Result<()> {
+    if index >= len {
+        return Err(Error::IndexOutOfRange { index, len });
+    }
+    Ok(())
+}
@@ -0,0 +1,428 @@
+use crate::database::RecordLayout;
+use crate::error::{Error, Result};
+use crate::matrix::Matrix;
+use crate::params::{select_params, LweParams};
+use crate::seeded::{gen_seed_with_rng, Seed};
+use crate::simplepir::{
+    self, plan_shape, SimplePIRParams, SimplePirBatchAnswer, SimplePirBatchQuery, SimplePirBatchState,
+    SimplePirClient, SimplePirServer,
+};
+use rand::{rngs::StdRng, CryptoRng, RngCore, SeedableRng};
+use std::collections::HashSet;
+
+/// The bytes of an entry before its value: an 8-byte fingerprint of the key, and 4 bytes holding
+/// one more than the length of the value, so that an empty slot reads as length 0
+pub const ENTRY_HEADER: usize = 12;
+
+/// The parameters of keyword PIR, which looks values up by key rather than by index.
+///
+/// Public hash functions choose two candidate buckets for each key, and the server stores the
+/// entry in whichever of them holds fewer, which keeps the fullest bucket close to the average.
+/// Each bucket is a column of a SimplePIR database holding `bucket_size` entries, and each entry
+/// holds a fingerprint of its key alongside the value. The client fetches both candidate columns
+/// and looks for its key's fingerprint.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeywordPirParams {
+    // The key of the hash functions which choose the buckets and fingerprint of each key
+    pub hash_seed: Seed,
+    pub num_buckets: usize,
+    // The number of entries which fit in each bucket
+    pub bucket_size: usize,
+    // The size of the largest value in bytes
+    pub value_size: usize,
+    // The parameters of the SimplePIR database, which has one column per bucket
+    pub simplepir: SimplePIRParams,
+}
+
+impl KeywordPirParams {
+    /// The buckets which `key` may be stored in, which are different unless there is only one
+    pub fn candidates(&self, key: &[u8]) -> [usize; 2] {
+        candidates(&self.hash_seed, self.num_buckets, key)
+    }
+
+    /// The fingerprint stored with the value of `key`
+    pub fn fingerprint(&self, key: &[u8]) -> u64 {
+        let (k0, k1) = hash_keys(&self.hash_seed, 1);
+        siphash(k0, k1, key)
+    }
+
+    /// The layout of the entries in the database: bucket b is column b, and holds `bucket_size`
+    /// entries
+    pub fn layout(&self) -> RecordLayout {
+        self.try_layout().expect("each column of the database must hold exactly one bucket")
+    }
+
+    /// The layout as in `layout`, or None if the columns of the database do not each hold exactly
+    /// `bucket_size` entries, which decoding checks so that `layout` cannot panic
+    pub(crate) fn try_layout(&self) -> Option<RecordLayout> {
+        let layout = RecordLayout::try_new(
+            self.num_buckets.checked_mul(self.bucket_size)?,
+            self.value_size.checked_add(ENTRY_HEADER)?,
+            self.simplepir.p,
+            self.simplepir.l,
+        )?;
+        (layout.records_per_col == self.bucket_size).then_some(layout)
+    }
+}
+
+/// Generate parameters for the key-value store `store`, with the LWE parameters `lwe`. The
+/// buckets are made large enough for its fullest bucket, so every entry fits.
+pub fn gen_params_with<K: AsRef<[u8]>, V: AsRef<[u8]>>(store: &[(K, V)], lwe: LweParams) -> KeywordPirParams {
+    gen_params_with_rng(&mut StdRng::from_entropy(), store, lwe)
+}
+
+/// Generate parameters as in `gen_params_with`, drawing randomness from `rng`.
+pub fn gen_params_with_rng<R: RngCore + CryptoRng, K: AsRef<[u8]>, V: AsRef<[u8]>>(
+    rng: &mut R,
+    store: &[(K, V)],
+    lwe: LweParams,
+) -> KeywordPirParams {
+    let value_size = store.iter().map(|(_, v)| v.as_ref().len()).max().unwrap_or(0);
+    let LweParams { n, q, p, std_dev } = lwe;
+
+    // Shape the database as if each bucket held exactly the average number of entries
+    let shape = plan_shape(store.len().max(1), ENTRY_HEADER + value_size, p, n, q);
+    let num_buckets = shape.m;
+    let hash_seed = gen_seed_with_rng(rng);
+    let buckets = place(&hash_seed, num_buckets, usize::MAX, store).unwrap();
+    let bucket_size = buckets.iter().map(|b| b.len()).max().unwrap().max(1);
+
+    let l = bucket_size * shape.layout.digits_per_record;
+    let simplepir = simplepir::gen_params_with_rng(rng, l, num_buckets, n, q, p, std_dev);
+    KeywordPirParams { hash_seed, num_buckets, bucket_size, value_size, simplepir }
+}
+
+/// Generate parameters as in `gen_params_with`, with at least `security_bits` bits of security and
+/// the largest plaintext modulus for which answers decrypt correctly. Returns None if the security
+/// level is not supported.
+pub fn gen_secure_params<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+    security_bits: u32,
+    store: &[(K, V)],
+) -> Option<KeywordPirParams> {
+    // There are at most as many buckets as entries, so a plaintext modulus which is small enough
+    // for that many columns is small enough for the database
+    let lwe = select_params(security_bits, store.len().max(1))?;
+    Some(gen_params_with(store, lwe))
+}
+
+/// Assign each entry of `store` to the candidate bucket which holds fewer entries. Returns the
+/// indices of the entries in each bucket, or an error if both candidates of an entry already
+/// hold `capacity` entries.
+fn place<K: AsRef<[u8]>, V>(
+    hash_seed: &Seed,
+    num_buckets: usize,
+    capacity: usize,
+    store: &[(K, V)],
+) -> Result<Vec<Vec<usize>>> {
+    let mut buckets = vec![Vec::new(); num_buckets];
+    for (i, (key, _)) in store.iter().enumerate() {
+        let [b1, b2] = candidates(hash_seed, num_buckets, key.as_ref());
+        let b = if buckets[b2].len() < buckets[b1].len() { b2 } else { b1 };
+        if buckets[b].len() >= capacity {
+            return Err(Error::BucketOverflow { bucket: b });
+        }
+        buckets[b].push(i);
+    }
+    Ok(buckets)
+}
+
+fn candidates(hash_seed: &Seed, num_buckets: usize, key: &[u8]) -> [usize; 2] {
+    let (k0, k1) = hash_keys(hash_seed, 0);
+    let h = siphash(k0, k1, key);
+    let b1 = (h as u32 as usize) % num_buckets;
+    if num_buckets < 2 {
+        return [b1, b1];
+    }
+    // Offset the second bucket from the first, so that they differ
+    let b2 = (b1 + 1 + ((h >> 32) as usize) % (num_buckets - 1)) % num_buckets;
+    [b1, b2]
+}
+
+/// The 128-bit SipHash key for hash function `i`, taken from the seed
+fn hash_keys(hash_seed: &Seed, i: usize) -> (u64, u64) {
+    let word = |j: usize| u64::from_le_bytes(hash_seed[8 * j..8 * j + 8].try_into().unwrap());
+    (word(2 * i), word(2 * i + 1))
+}
+
+/// SipHash-2-4 of `data` with the key (k0, k1). The hashers in std may change between Rust
+/// releases, but clients and servers must agree on every key's buckets.
+fn siphash(k0: u64, k1: u64, data: &[u8]) -> u64 {
+    fn round(v: &mut [u64; 4]) {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    }
+    fn compress(v: &mut [u64; 4], m: u64) {
+        v[3] ^= m;
+        round(v);
+        round(v);
+        v[0] ^= m;
+    }
+
+    let mut v = [
+        k0 ^ 0x736f6d6570736575,
+        k1 ^ 0x646f72616e646f6d,
+        k0 ^ 0x6c7967656e657261,
+        k1 ^ 0x7465646279746573,
+    ];
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        compress(&mut v, u64::from_le_bytes(chunk.try_into().unwrap()));
+    }
+    // The last block holds the remaining bytes, and the length of the data in its top byte
+    let mut last = [0u8; 8];
+    last[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+    last[7] = data.len() as u8;
+    compress(&mut v, u64::from_le_bytes(last));
+
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+/// What the client remembers about a query: the fingerprint of the key it looks for, and the
+/// secrets of the queries to the key's two candidate buckets. The fingerprint identifies the key,
+/// so the state must never leave the client.
+#[derive(Debug)]
+pub struct KeywordPirState {
+    fingerprint: u64,
+    batch: SimplePirBatchState,
+}
+
+pub struct KeywordPirServer {
+    params: KeywordPirParams,
+    server: SimplePirServer,
+}
+
+impl KeywordPirServer {
+    /// Lay the entries of `store` out in buckets, and preprocess the database. Panics if a key
+    /// appears more than once, a value is longer than `params.value_size`, or the entries do not
+    /// fit in the buckets.
+    pub fn new<K: AsRef<[u8]>, V: AsRef<[u8]>>(params: KeywordPirParams, store: &[(K, V)]) -> Self {
+        Self::try_new(params, store).unwrap()
+    }
+
+    /// Create a server as in `new`, or an error if a key appears more than once, a value is too
+    /// long, or a bucket overflows, which happens when `params` were generated for a smaller store
+    pub fn try_new<K: AsRef<[u8]>, V: AsRef<[u8]>>(params: KeywordPirParams, store: &[(K, V)]) -> Result<Self> {
+        let mut keys = HashSet::new();
+        for (index, (key, value)) in store.iter().enumerate() {
+            if !keys.insert(key.as_ref()) {
+                return Err(Error::DuplicateKey { index });
+            }
+            let len = value.as_ref().len();
+            if len > params.value_size {
+                return Err(Error::ValueTooLong { len, max: params.value_size });
+            }
+        }
+        let buckets = place(&params.hash_seed, params.num_buckets, params.bucket_size, store)?;
+
+        let layout = params.layout();
+        let mut entries = vec![vec![0u8; layout.record_size]; layout.num_records];
+        for (b, bucket) in buckets.iter().enumerate() {
+            for (j, i) in bucket.iter().enumerate() {
+                let (key, value) = &store[*i];
+                let (key, value) = (key.as_ref(), value.as_ref());
+                let entry = &mut entries[b * params.bucket_size + j];
+                entry[..8].copy_from_slice(&params.fingerprint(key).to_le_bytes());
+                entry[8..ENTRY_HEADER].copy_from_slice(&(value.len() as u32 + 1).to_le_bytes());
+                entry[ENTRY_HEADER..ENTRY_HEADER + value.len()].copy_from_slice(value);
+            }
+        }
+
+        let SimplePIRParams { p, l, m, .. } = params.simplepir;
+        let db = layout.pack(&entries, p, l, m);
+        let server = SimplePirServer::new(params.simplepir.clone(), &db);
+        Ok(Self { params, server })
+    }
+
+    pub fn params(&self) -> &KeywordPirParams {
+        &self.params
+    }
+
+    pub fn hint(&self) -> &Matrix {
+        self.server.hint()
+    }
+
+    /// Answer a query for both candidate buckets of a key with one pass over the database
+    pub fn answer(&self, query: &SimplePirBatchQuery) -> SimplePirBatchAnswer {
+        self.server.answer_batch(query)
+    }
+
+    /// Answer a query from an untrusted client, or reject it if it is malformed
+    pub fn try_answer(&self, query: &SimplePirBatchQuery) -> Result<SimplePirBatchAnswer> {
+        self.server.try_answer_batch(query)
+    }
+}
+
+pub struct KeywordPirClient {
+    params: KeywordPirParams,
+    client: SimplePirClient,
+}
+
+impl KeywordPirClient {
+    pub fn new(params: KeywordPirParams, hint: Matrix) -> Self {
+        let client = SimplePirClient::new(params.simplepir.clone(), hint);
+        Self { params, client }
+    }
+
+    pub fn params(&self) -> &KeywordPirParams {
+        &self.params
+    }
+
+    /// Create a query for the value of `key`, which fetches both of its candidate buckets whether
+    /// or not the key is in the store
+    pub fn query(&self, key: &[u8]) -> (SimplePirBatchQuery, KeywordPirState) {
+        self.query_with_rng(&mut StdRng::from_entropy(), key)
+    }
+
+    pub fn query_with_rng<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        key: &[u8],
+    ) -> (SimplePirBatchQuery, KeywordPirState) {
+        let [b1, b2] = self.params.candidates(key);
+        let (query, batch) = self.client.query_batch_with_rng(rng, &[(0, b1), (0, b2)]);
+        (query, KeywordPirState { fingerprint: self.params.fingerprint(key), batch })
+    }
+
+    /// Recover the value of the key which was queried, or None if it is not in the store
+    pub fn recover(&self, state: &KeywordPirState, answer: &SimplePirBatchAnswer) -> Option<Vec<u8>> {
+        self.try_recover(state, answer).unwrap()
+    }
+
+    /// Recover the value as in `recover`, or an error if the answer is malformed
+    pub fn try_recover(&self, state: &KeywordPirState, answer: &SimplePirBatchAnswer) -> Result<Option<Vec<u8>>> {
+        let layout = self.params.layout();
+        let cols = self.client.try_recover_batch_cols(&state.batch, answer)?;
+        for col in cols {
+            for digits in col.chunks_exact(layout.digits_per_record) {
+                let digits: Vec<u64> = digits.iter().map(|e| e.uint).collect();
+                let entry = layout.decode(&digits);
+                let fingerprint = u64::from_le_bytes(entry[..8].try_into().unwrap());
+                let len = u32::from_le_bytes(entry[8..ENTRY_HEADER].try_into().unwrap()) as usize;
+                if fingerprint == state.fingerprint && (1..=self.params.value_size + 1).contains(&len) {
+                    return Ok(Some(entry[ENTRY_HEADER..ENTRY_HEADER + len - 1].to_vec()));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{check_buckets, check_secure_params, gen_record, test_lwe};
+
+    fn gen_store(num_entries: usize) -> Vec<(String, Vec<u8>)> {
+        // Values of different lengths, including empty ones
+        (0..num_entries)
+            .map(|i| (format!("user-{}", i), gen_record(i, i % 7)))
+            .collect()
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_siphash() {
+        use std::hash::{Hasher, SipHasher};
+
+        // The reference test vector for the empty message
+        let (k0, k1) = (0x0706050403020100, 0x0f0e0d0c0b0a0908);
+        assert_eq!(siphash(k0, k1, &[]), 0x726fdb47dd0e0e31);
+
+        // And the same hashes as std's SipHash-2-4 for every length of a final block
+        for len in 0..20 {
+            let data: Vec<u8> = (0..len as u8).collect();
+            let mut hasher = SipHasher::new_with_keys(k0, k1);
+            hasher.write(&data);
+            assert_eq!(siphash(k0, k1, &data), hasher.finish());
+        }
+    }
+
+    #[test]
+    fn test_keyword_pir() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let store = gen_store(300);
+        let params = gen_params_with_rng(&mut rng, &store, test_lwe());
+        assert_eq!(params.value_size, 6);
+        let server = KeywordPirServer::new(params.clone(), &store);
+        let client = KeywordPirClient::new(params.clone(), server.hint().clone());
+
+        for (key, value) in store.iter().step_by(13) {
+            let (query, state) = client.query_with_rng(&mut rng, key.as_bytes());
+            assert_eq!(client.recover(&state, &server.answer(&query)), Some(value.clone()));
+        }
+        for key in ["user-300", "", "user-1 "] {
+            let (query, state) = client.query(key.as_bytes());
+            assert_eq!(client.recover(&state, &server.answer(&query)), None);
+        }
+    }
+
+    #[test]
+    fn test_buckets() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let store = gen_store(1000);
+        let params = gen_params_with_rng(&mut rng, &store, test_lwe());
+
+        let buckets = place(&params.hash_seed, params.num_buckets, params.bucket_size, &store).unwrap();
+        assert_eq!(buckets.iter().map(|b| b.len()).sum::<usize>(), 1000);
+        assert_eq!(buckets.iter().map(|b| b.len()).max(), Some(params.bucket_size));
+        check_buckets(&buckets, |i| params.candidates(store[i].0.as_bytes()).to_vec());
+        // Two choices keep the fullest bucket close to the average
+        assert!(params.bucket_size <= 1000usize.div_ceil(params.num_buckets) + 3);
+        assert_ne!(params.fingerprint(b"user-1"), params.fingerprint(b"user-2"));
+        assert_ne!(params.candidates(b"user-1")[0], params.candidates(b"user-1")[1]);
+    }
+
+    #[test]
+    fn test_overflow() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let store = gen_store(100);
+        let params = gen_params_with_rng(&mut rng, &store[..50], test_lwe());
+
+        // The buckets were sized for half of the store
+        let result = KeywordPirServer::try_new(params.clone(), &store);
+        assert!(matches!(result, Err(Error::BucketOverflow { .. })));
+        assert!(KeywordPirServer::try_new(params.clone(), &store[..50]).is_ok());
+
+        // Repeated keys and values which do not fit in an entry are rejected rather than stored
+        let mut repeated = store[..50].to_vec();
+        repeated[20].0 = repeated[10].0.clone();
+        assert_eq!(KeywordPirServer::try_new(params.clone(), &repeated).err(), Some(Error::DuplicateKey { index: 20 }));
+        let mut long = store[..50].to_vec();
+        long[5].1 = vec![0; params.value_size + 1];
+        let expected = Error::ValueTooLong { len: params.value_size + 1, max: params.value_size };
+        assert_eq!(KeywordPirServer::try_new(params, &long).err(), Some(expected));
+    }
+
+    #[test]
+    fn test_empty_store() {
+        let store: Vec<(String, Vec<u8>)> = vec![];
+        let params = gen_params_with(&store, test_lwe());
+        let server = KeywordPirServer::new(params.clone(), &store);
+        let client = KeywordPirClient::new(params, server.hint().clone());
+        let (query, state) = client.query(b"missing");
+        assert_eq!(client.recover(&state, &server.answer(&query)), None);
+
+        let (_, state) = client.query(b"missing");
+        let malformed = SimplePirBatchAnswer(Matrix::zeros(1 << 32, 1, 1));
+        assert!(client.try_recover(&state, &malformed).is_err());
+    }
+
+    #[test]
+    fn test_secure_params() {
+        let store = gen_store(1000);
+        let params = gen_secure_params(128, &store).unwrap();
+        check_secure_params(&params.simplepir);
+    }
+}
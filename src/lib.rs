@@ -1,6 +1,17 @@
+pub mod database;
 pub mod element;
+pub mod encoding;
+pub mod error;
 pub mod matrix;
+pub mod modulus;
+pub mod params;
 pub mod regev;
+pub mod seeded;
 pub mod toypir;
 pub mod simplepir;
 pub mod doublepir;
+pub mod batchpir;
+pub mod keywordpir;
+
+#[cfg(test)]
+mod testing;
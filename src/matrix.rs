@@ -1,265 +1,591 @@
 use crate::element::Element;
+use crate::error::{check_dimensions, check_index, check_len, check_modulus, Error, Result};
+use crate::modulus::Modulus;
 use std::clone::Clone;
 use std::default::Default;
 use std::fmt::{Display, Formatter};
-use std::ops::{Add, AddAssign, Sub, SubAssign, Index, IndexMut, Mul, MulAssign};
-
-#[derive(Debug, PartialEq)]
+use std::ops::{Add, AddAssign, Sub, SubAssign, Index, IndexMut, Mul, MulAssign, Range};
+use rand::{rngs::StdRng, CryptoRng, RngCore, SeedableRng};
+
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+/// A matrix of elements mod q, stored in a single contiguous buffer.
+///
+/// The matrix has `num_rows()` rows of `num_cols()` entries each, and `m[i][j]` is the entry in
+/// row i and column j, as in the usual mathematical notation which products also follow. Row i is
+/// stored at `data[i * cols..(i + 1) * cols]`, and all entries share the modulus q.
+#[derive(Debug, Clone, PartialEq, Default)]
 pub struct Matrix {
-    pub data: Vec<Vec<Element>>,
+    q: u64,
+    rows: usize,
+    cols: usize,
+    data: Vec<u64>,
 }
 
 impl Index<usize> for Matrix {
-    type Output = Vec<Element>;
+    type Output = [u64];
     fn index(&self, i: usize) -> &Self::Output {
-        &self.data[i]
+        &self.data[i * self.cols..(i + 1) * self.cols]
     }
 }
 
 impl IndexMut<usize> for Matrix {
-    fn index_mut(&mut self, i: usize) -> &mut Vec<Element> {
-        &mut self.data[i]
+    fn index_mut(&mut self, i: usize) -> &mut [u64] {
+        &mut self.data[i * self.cols..(i + 1) * self.cols]
     }
 }
 
-impl Clone for Matrix {
-    fn clone(&self) -> Self {
-        if self.data.is_empty() {
-            return Matrix { data: vec![] };
-        }
-        let mut d: Vec<Vec<Element>> = Vec::with_capacity(self.data.len());
-        for i in 0..self.data.len() {
-            let mut e = Vec::<Element>::with_capacity(self.data[i].len());
-            for j in 0..self.data[i].len() {
-                e.push(self.data[i][j].clone());
-            }
-            d.push(e);
-        }
-        Matrix { data: d }
+impl Matrix {
+    /// Initialise an empty matrix (0 by 0)
+    pub fn new() -> Matrix {
+        Matrix { q: 0, rows: 0, cols: 0, data: vec![] }
     }
 
-    fn clone_from(&mut self, source: &Self) {
-        let c = source.clone();
-        *self = c;
+    /// Initialise a matrix of zeros mod q
+    pub fn zeros(q: u64, rows: usize, cols: usize) -> Self {
+        Matrix { q, rows, cols, data: vec![0; rows * cols] }
     }
-}
 
-impl Default for Matrix {
-    fn default() -> Matrix {
-        Matrix::new()
-    }
-}
-
-impl Matrix {
-    /// Initialise an empty matrix (0 by 0)
-    pub fn new() -> Matrix {
-        Matrix { data: vec![] }
+    /// Initialise a matrix from raw values mod q, row by row
+    pub fn from_raw(q: u64, rows: usize, cols: usize, data: Vec<u64>) -> Self {
+        assert_eq!(data.len(), rows * cols);
+        debug_assert!(data.iter().all(|v| *v < q));
+        Matrix { q, rows, cols, data }
     }
 
     /// Initialise a matrix from Vectors of Vectors of elements
-    pub fn from(data: &Vec<Vec<Element>>) -> Self {
-        let mut matrix = Self::new();
-        matrix.data = data.to_owned();
+    pub fn from(data: &[Vec<Element>]) -> Self {
+        if data.is_empty() || data[0].is_empty() {
+            return Self::new();
+        }
+        let q = data[0][0].q;
+        let cols = data[0].len();
+        let mut matrix = Self::zeros(q, 0, cols);
+        matrix.data.reserve(data.len() * cols);
+        for row in data {
+            matrix.append_row(row.to_owned());
+        }
         matrix
     }
 
     pub fn from_single(elem: &Element) -> Self {
-        Self::from(&vec![vec![elem.clone()]])
+        Self::from_raw(elem.q, 1, 1, vec![elem.uint])
     }
 
-    pub fn from_col(col: &Vec<Element>) -> Self {
-        Self::from(&vec![col.to_owned()])
+    pub fn from_row(row: &[Element]) -> Self {
+        Self::from(&[row.to_owned()])
     }
 
     pub fn from_val(rows: usize, cols: usize, val: Element) -> Self {
-        let row = vec![val; cols];
-        let cols = vec![row; rows];
-        Matrix::from(&cols)
-    }
-
-    // TODO: change all (rows, cols) to (cols, rows) for consistency with the paper
-    pub fn gen_uniform_rand(q: u64, cols: usize, rows: usize) -> Self  {
-        let mut a = Vec::with_capacity(cols);
-        for _ in 0..cols {
-            let mut row = Vec::with_capacity(rows);
-            for _ in 0..rows {
-                row.push(Element::gen_uniform_rand(q));
-            }
-            a.push(row);
+        Self::from_raw(val.q, rows, cols, vec![val.uint; rows * cols])
+    }
+
+    pub fn gen_uniform_rand(q: u64, rows: usize, cols: usize) -> Self  {
+        Self::gen_uniform_rand_with_rng(&mut StdRng::from_entropy(), q, rows, cols)
+    }
+
+    /// Generate a matrix of uniformly random elements mod q, drawing randomness from `rng`.
+    pub fn gen_uniform_rand_with_rng<R: RngCore + CryptoRng>(
+        rng: &mut R,
+        q: u64,
+        rows: usize,
+        cols: usize,
+    ) -> Self {
+        let mut data = Vec::with_capacity(rows * cols);
+        for _ in 0..rows * cols {
+            data.push(Element::gen_uniform_rand_with_rng(rng, q).uint);
         }
-        Matrix::from(&a)
+        Matrix::from_raw(q, rows, cols, data)
     }
 
-    pub fn append_col(&mut self, col: Vec<Element>) {
-        assert_eq!(self.num_rows(), col.len());
-        self.data.push(col);
+    /// The modulus shared by every entry
+    pub fn q(&self) -> u64 {
+        self.q
     }
 
-    pub fn change_q(&mut self, new_q: u64) {
-        for i in 0..self.num_cols() {
-            for j in 0..self.num_rows() {
-                self[i][j].q = new_q;
+    /// The raw entries, row by row
+    pub fn data(&self) -> &[u64] {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u64] {
+        &mut self.data
+    }
+
+    pub fn get(&self, i: usize, j: usize) -> Element {
+        Element::from(self.q, self[i][j])
+    }
+
+    pub fn set(&mut self, i: usize, j: usize, val: Element) {
+        assert_eq!(val.q, self.q);
+        self[i][j] = val.uint;
+    }
+
+    pub fn try_set(&mut self, i: usize, j: usize, val: Element) -> Result<()> {
+        check_index(i, self.rows)?;
+        check_index(j, self.cols)?;
+        check_modulus(self.q, val.q)?;
+        self.set(i, j, val);
+        Ok(())
+    }
+
+    /// The i-th row as a vector of elements
+    pub fn get_row(&self, i: usize) -> Vec<Element> {
+        self[i].iter().map(|v| Element::from(self.q, *v)).collect()
+    }
+
+    pub fn append_row(&mut self, row: Vec<Element>) {
+        if self.rows == 0 && self.cols == 0 && !row.is_empty() {
+            self.q = row[0].q;
+            self.cols = row.len();
+        }
+        assert_eq!(self.num_cols(), row.len());
+        for e in row {
+            assert_eq!(e.q, self.q);
+            self.data.push(e.uint);
+        }
+        self.rows += 1;
+    }
+
+    /// Append the rows of `other`, which must have as many columns and the same modulus
+    pub fn append_rows(&mut self, other: &Matrix) {
+        assert_eq!(self.q, other.q);
+        assert_eq!(self.cols, other.cols);
+        self.data.extend_from_slice(&other.data);
+        self.rows += other.rows;
+    }
+
+    /// Append the columns of `other` to the right of this matrix, so that each row is extended
+    /// with the same row of `other`
+    pub fn append_cols(&mut self, other: &Matrix) {
+        assert_eq!(self.q, other.q);
+        assert_eq!(self.rows, other.rows);
+        let cols = self.cols + other.cols;
+        let mut data = Vec::with_capacity(self.rows * cols);
+        for i in 0..self.rows {
+            data.extend_from_slice(&self[i]);
+            data.extend_from_slice(&other[i]);
+        }
+        self.data = data;
+        self.cols = cols;
+    }
+
+    pub fn try_append_row(&mut self, row: Vec<Element>) -> Result<()> {
+        if !(self.rows == 0 && self.cols == 0) {
+            check_len(self.num_cols(), row.len())?;
+        }
+        if let Some(first) = row.first() {
+            let q = if self.rows == 0 && self.cols == 0 { first.q } else { self.q };
+            for e in &row {
+                check_modulus(q, e.q)?;
             }
         }
+        self.append_row(row);
+        Ok(())
+    }
+
+    pub fn change_q(&mut self, new_q: u64) {
+        self.q = new_q;
     }
 
     pub fn num_vals(&self) -> usize {
-        if !self.data.is_empty() {
-            return self.data.len() * self.data[0].len();
-        }
-        0
+        self.data.len()
     }
 
-    #[allow(clippy::needless_range_loop)]
     pub fn rotated(self) -> Self {
-        let zero = Element::zero(self.data[0][0].q);
-        let new_row = vec![zero; self.num_cols()];
-        let mut rotated = vec![new_row; self.num_rows()];
-        for i in 0..self.num_cols() {
-            for j in 0..self.num_rows() {
-                rotated[j][i] = self.data[i][j].clone();
+        let mut rotated = Self::zeros(self.q, self.cols, self.rows);
+        for i in 0..self.rows {
+            for j in 0..self.cols {
+                rotated[j][i] = self[i][j];
             }
         }
-        Self::from(&rotated)
+        rotated
     }
 
     pub fn mul_elem(self, rhs: &Element) -> Self {
-        let mut r = self.clone();
-        for i in 0..self.num_cols() {
-            for j in 0..self.num_rows() {
-                r[i][j] *= rhs.to_owned();
-            }
+        assert_eq!(self.q, rhs.q);
+        let modulus = Modulus::new(self.q);
+        let mut r = self;
+        for v in r.data.iter_mut() {
+            *v = modulus.mul(*v, rhs.uint);
         }
-
         r
     }
 
-    pub fn mul_vec(self, rhs: &Vec<Element>) -> Self {
-        let rhs_matrix = Self::from(&vec![rhs.to_owned()]).rotated();
-        self.mul(rhs_matrix)
+    pub fn mul_vec(self, rhs: &[Element]) -> Self {
+        let rhs: Vec<u64> = rhs.iter().map(|e| {
+            assert_eq!(e.q, self.q);
+            e.uint
+        }).collect();
+
+        let mut result = Self::zeros(self.q, self.rows, 1);
+        self.mul_vec_into(&rhs, &mut result.data);
+        result
     }
 
-    fn log_q_div_log_p_ceil(q: u64, p: u64) -> usize {
-        ((q - 1) as f64).log(p as f64).ceil() as usize
+    pub fn try_mul_vec(self, rhs: &[Element]) -> Result<Self> {
+        check_len(self.cols, rhs.len())?;
+        for e in rhs {
+            check_modulus(self.q, e.q)?;
+        }
+        Ok(self.mul_vec(rhs))
     }
 
-    pub fn recompose(self, p: u64, q: u64) -> Self {
-        let num_digits = Self::log_q_div_log_p_ceil(q, p);
-        assert_eq!(self.num_cols() % num_digits, 0);
+    /// Compute `self + rhs`, or an error if the matrices have different dimensions or moduli
+    pub fn try_add(self, rhs: Matrix) -> Result<Self> {
+        self.check_same_shape(&rhs)?;
+        Ok(self + rhs)
+    }
 
-        let each_col = vec![Element::from(q, 0); self.num_rows()];
-        let mut all_cols = vec![each_col.clone(); self.num_cols() / num_digits];
+    /// Compute `self - rhs`, or an error if the matrices have different dimensions or moduli
+    pub fn try_sub(self, rhs: Matrix) -> Result<Self> {
+        self.check_same_shape(&rhs)?;
+        Ok(self - rhs)
+    }
 
-        for i in 0..all_cols.len() {
-            for j in 0..each_col.len() {
-                let mut d = Vec::with_capacity(num_digits);
-                for k in 0..num_digits {
-                    d.push(self.data[i * num_digits + k][j].uint);
+    /// Compute `self * rhs`, or an error if the dimensions or moduli do not match
+    pub fn try_mul(self, rhs: Matrix) -> Result<Self> {
+        check_modulus(self.q, rhs.q)?;
+        check_dimensions((self.num_cols(), rhs.num_cols()), rhs.dimensions())?;
+        Ok(self * rhs)
+    }
+
+    /// Check that every entry is reduced mod q, as it may not be in a matrix built from untrusted
+    /// input
+    pub fn check_reduced(&self) -> Result<()> {
+        match self.data.iter().find(|v| **v >= self.q) {
+            Some(v) => Err(Error::ValueOutOfRange { value: *v, q: self.q }),
+            None => Ok(()),
+        }
+    }
+
+    fn check_same_shape(&self, rhs: &Matrix) -> Result<()> {
+        check_modulus(self.q, rhs.q)?;
+        check_dimensions(self.dimensions(), rhs.dimensions())
+    }
+
+    /// Multiply this matrix by the vector of raw values `rhs`, writing the i-th entry of the
+    /// product into `out[i]`. Nothing is allocated, so this can be used to answer many queries
+    /// with the same output buffer.
+    pub fn mul_vec_into(&self, rhs: &[u64], out: &mut [u64]) {
+        assert_eq!(self.cols, rhs.len());
+        assert_eq!(self.rows, out.len());
+        if self.cols == 0 {
+            out.fill(0);
+            return;
+        }
+        let modulus = Modulus::new(self.q);
+
+        #[cfg(feature = "rayon")]
+        out.par_iter_mut()
+            .zip(self.data.par_chunks_exact(self.cols))
+            .for_each(|(o, row)| *o = dot(&modulus, row, rhs));
+
+        #[cfg(not(feature = "rayon"))]
+        for (o, row) in out.iter_mut().zip(self.data.chunks_exact(self.cols)) {
+            *o = dot(&modulus, row, rhs);
+        }
+    }
+
+    /// Compute `self * rhs` into `out`, which must already have the dimensions of the product.
+    /// Nothing is allocated.
+    pub fn mul_into(&self, rhs: &Matrix, out: &mut Matrix) {
+        // Ensure that the rhs matrix has the correct dimensions
+        assert_eq!(self.num_cols(), rhs.num_rows());
+        assert_eq!(self.q, rhs.q);
+        assert_eq!(out.dimensions(), (self.num_rows(), rhs.num_cols()));
+
+        out.q = self.q;
+        let stride = self.num_cols();
+        let p = rhs.num_cols();
+        if (1..NARROW_RHS).contains(&p) && stride > 0 {
+            // A narrow rhs (such as a small batch of queries) has rows too short to vectorise
+            // well, so instead take dot products with its columns, as in mul_vec_into
+            let modulus = Modulus::new(self.q);
+
+            #[cfg(feature = "rayon")]
+            out.data.par_chunks_exact_mut(p)
+                .zip(self.data.par_chunks_exact(stride))
+                .for_each(|(out, lhs)| dot_all(&modulus, lhs, &rhs.data, out));
+
+            #[cfg(not(feature = "rayon"))]
+            for (out, lhs) in out.data.chunks_exact_mut(p).zip(self.data.chunks_exact(stride)) {
+                dot_all(&modulus, lhs, &rhs.data, out);
+            }
+            return;
+        }
+        out.data.fill(0);
+        self.mul_acc_block(0, rhs, out);
+    }
+
+    /// Accumulate `out += self[.., offset..offset + k] * rhs` for the k x p matrix `rhs`, i.e. add
+    /// the product of entries offset..offset + k of each row of self with rhs. This computes a
+    /// product with a large rhs one block of rhs rows at a time.
+    pub fn mul_acc_block(&self, offset: usize, rhs: &Matrix, out: &mut Matrix) {
+        let k = rhs.num_rows();
+        assert!(offset + k <= self.num_cols());
+        assert_eq!(self.q, rhs.q);
+        assert_eq!(out.q, self.q);
+        assert_eq!(out.dimensions(), (self.num_rows(), rhs.num_cols()));
+
+        let stride = self.num_cols();
+        let p = rhs.num_cols();
+        if k == 0 || p == 0 {
+            return;
+        }
+        let modulus = Modulus::new(self.q);
+        let inner = offset..offset + k;
+
+        // Each task computes TILE_ROWS rows of the result
+        #[cfg(feature = "rayon")]
+        out.data.par_chunks_mut(TILE_ROWS * p)
+            .zip(self.data.par_chunks(TILE_ROWS * stride))
+            .for_each(|(out, lhs)| mul_tile(&modulus, lhs, stride, inner.clone(), &rhs.data, p, out));
+
+        #[cfg(not(feature = "rayon"))]
+        for (out, lhs) in out.data.chunks_mut(TILE_ROWS * p).zip(self.data.chunks(TILE_ROWS * stride)) {
+            mul_tile(&modulus, lhs, stride, inner.clone(), &rhs.data, p, out);
+        }
+    }
+
+    /// Undo `decomposed`, recombining each run of digits in the given base into an element mod q
+    pub fn recompose(self, base: u64, q: u64) -> Self {
+        let num_digits = Element::num_digits(q, base);
+        assert_eq!(self.num_rows() % num_digits, 0);
+
+        let mut result = Self::zeros(q, self.rows / num_digits, self.cols);
+        self.recompose_into(base, &mut result);
+        result
+    }
+
+    /// Recompose as in `recompose` into `out`, whose modulus is the q to recompose mod and which
+    /// must have one row for every `Element::num_digits(q, base)` rows of digits.
+    pub fn recompose_into(&self, base: u64, out: &mut Matrix) {
+        let num_digits = Element::num_digits(out.q, base);
+        assert_eq!(self.dimensions(), (out.rows * num_digits, out.cols));
+        if self.data.is_empty() {
+            return;
+        }
+
+        let modulus = Modulus::new(out.q);
+        let base = modulus.reduce(base);
+        let cols = self.cols;
+        for (digits, row) in self.data.chunks_exact(num_digits * cols).zip(out.data.chunks_exact_mut(cols)) {
+            row.fill(0);
+            let mut r = modulus.reduce(1);
+            for digit_row in digits.chunks_exact(cols) {
+                for (v, digit) in row.iter_mut().zip(digit_row) {
+                    *v = modulus.add(*v, modulus.mul(r, modulus.reduce(*digit)));
                 }
-                let d = Element::recompose(p, q, &d);
-                all_cols[i][j] = d;
+                r = modulus.mul(r, base);
             }
         }
-        Matrix::from(&all_cols)
     }
 
-    pub fn decomposed(self, p: u64) -> Self {
-        if self.num_cols() == 0 {
+    /// Replace each row with the rows of its digits in the given base, least significant
+    /// first
+    pub fn decomposed(self, base: u64) -> Self {
+        if self.num_rows() == 0 {
             return self;
         }
 
-        let q = self.data[0][0].q;
-
-        let num_digits = Self::log_q_div_log_p_ceil(q, p);
+        let num_digits = Element::num_digits(self.q, base);
+        let mut result = Self::zeros(self.q, num_digits * self.rows, self.cols);
+        self.decompose_into(base, &mut result);
+        result
+    }
 
-        let each_col = vec![Element::from(q, 0); self.num_rows()];
-        let mut all_cols = vec![each_col; num_digits * self.num_cols()];
+    /// Decompose as in `decomposed` into `out`, which must have `Element::num_digits(q, base)`
+    /// rows for every row of this matrix. This does not allocate, and a power-of-two base
+    /// only needs shifts and masks.
+    pub fn decompose_into(&self, base: u64, out: &mut Matrix) {
+        let num_digits = Element::num_digits(self.q, base);
+        assert_eq!(out.dimensions(), (self.rows * num_digits, self.cols));
+        assert_eq!(out.q, self.q);
+        if self.data.is_empty() {
+            return;
+        }
 
-        // NOTE: this is slow!
-        for i in 0..self.num_cols() {
-            for j in 0..self.num_rows() {
-                let d = self.data[i][j].to_owned().decomposed(p);
+        let cols = self.cols;
+        for (row, digits) in self.data.chunks_exact(cols).zip(out.data.chunks_exact_mut(num_digits * cols)) {
+            if base.is_power_of_two() {
+                let bits = base.trailing_zeros();
+                for (k, digit_row) in digits.chunks_exact_mut(cols).enumerate() {
+                    let shift = k as u32 * bits;
+                    for (digit, v) in digit_row.iter_mut().zip(row) {
+                        *digit = (v >> shift) & (base - 1);
+                    }
+                }
+            } else {
+                // Peel off one digit at a time, leaving the quotient in the next digit's row
+                digits[..cols].copy_from_slice(row);
                 for k in 0..num_digits {
-                    all_cols[i * num_digits + k][j].uint = d[k];
+                    let (digit_row, rest) = digits[k * cols..].split_at_mut(cols);
+                    if rest.is_empty() {
+                        digit_row.iter_mut().for_each(|v| *v %= base);
+                    } else {
+                        for (v, next) in digit_row.iter_mut().zip(rest) {
+                            *next = *v / base;
+                            *v %= base;
+                        }
+                    }
                 }
             }
         }
-        Self::from(&all_cols)
     }
 
     pub fn num_rows(&self) -> usize {
-        if self.num_cols() == 0 {
-                return 0;
-        }
-
-        self.data[0].len()
+        self.rows
     }
 
     pub fn num_cols(&self) -> usize {
-        self.data.len()
+        self.cols
     }
 
     pub fn dimensions(&self) -> (usize, usize) {
-        (self.num_cols(), self.num_rows())
+        (self.num_rows(), self.num_cols())
     }
 }
 
-impl Add for Matrix {
-    type Output = Matrix;
-    #[allow(clippy::needless_range_loop)]
-    fn add(self, rhs: Matrix) -> Self::Output {
-        assert_eq!(self.num_rows(), rhs.num_rows());
-        assert_eq!(self.num_cols(), rhs.num_cols());
-
-        let mut s = self.clone();
+/// The number of rows of the left-hand side processed together by `mul_tile`
+const TILE_ROWS: usize = 16;
+
+/// Products with a rhs with fewer columns than this are computed as dot products by `mul_into`
+const NARROW_RHS: usize = 16;
+
+/// The block of the right-hand side which is kept in cache while it is multiplied by every row
+/// of a tile: BLOCK_INNER rows, each truncated to BLOCK_COLS entries
+const BLOCK_INNER: usize = 64;
+const BLOCK_COLS: usize = 256;
+
+/// Add the product of the rows in `lhs` (each of length `stride`), restricted to the entries in
+/// `inner`, with the inner.len() x p matrix `rhs` to `out`.
+fn mul_tile(
+    modulus: &Modulus,
+    lhs: &[u64],
+    stride: usize,
+    inner: Range<usize>,
+    rhs: &[u64],
+    p: usize,
+    out: &mut [u64],
+) {
+    let m = inner.len();
+    for kk in (0..m).step_by(BLOCK_INNER) {
+        let k_end = (kk + BLOCK_INNER).min(m);
+        for jj in (0..p).step_by(BLOCK_COLS) {
+            let j_end = (jj + BLOCK_COLS).min(p);
+            for (out_row, lhs_row) in out.chunks_exact_mut(p).zip(lhs.chunks_exact(stride)) {
+                let lhs_row = &lhs_row[inner.clone()];
+                // Accumulate out[i] += lhs[i][k] * rhs[k], so that both rhs and the result are
+                // read contiguously
+                let out_block = &mut out_row[jj..j_end];
+                for k in kk..k_end {
+                    let a = lhs_row[k];
+                    let rhs_block = &rhs[k * p + jj..k * p + j_end];
+                    if modulus.is_power_of_two() {
+                        // Wrapping arithmetic only needs to be reduced once at the end
+                        for (o, b) in out_block.iter_mut().zip(rhs_block) {
+                            *o = o.wrapping_add(a.wrapping_mul(*b));
+                        }
+                    } else {
+                        for (o, b) in out_block.iter_mut().zip(rhs_block) {
+                            *o = modulus.add(*o, modulus.mul(a, *b));
+                        }
+                    }
+                }
+            }
+        }
+    }
+    if modulus.is_power_of_two() {
+        for o in out.iter_mut() {
+            *o = modulus.reduce(*o);
+        }
+    }
+}
 
-        for i in 0..self.num_cols() {
-            for j in 0..self.num_rows() {
-                s.data[i][j] = s.data[i][j].clone() + rhs.data[i][j].clone();
+/// Write the dot product of `a` with each column of the a.len() x out.len() matrix `rhs` to `out`.
+/// `rhs` is read by rows, so each entry of `a` and each row of `rhs` is loaded once for all of the
+/// columns, of which there must be fewer than NARROW_RHS.
+fn dot_all(modulus: &Modulus, a: &[u64], rhs: &[u64], out: &mut [u64]) {
+    let p = out.len();
+    let mut sums = [0u64; NARROW_RHS];
+    let sums = &mut sums[..p];
+    if modulus.is_power_of_two() {
+        // Wrapping arithmetic only needs to be reduced once at the end
+        for (x, row) in a.iter().zip(rhs.chunks_exact(p)) {
+            for (sum, v) in sums.iter_mut().zip(row) {
+                *sum = sum.wrapping_add(x.wrapping_mul(*v));
             }
         }
+    } else {
+        for (x, row) in a.iter().zip(rhs.chunks_exact(p)) {
+            for (sum, v) in sums.iter_mut().zip(row) {
+                *sum = modulus.add(*sum, modulus.mul(*x, *v));
+            }
+        }
+    }
+    for (o, sum) in out.iter_mut().zip(sums) {
+        *o = modulus.reduce(*sum);
+    }
+}
+
+/// The inner product of two vectors of values mod q
+fn dot(modulus: &Modulus, a: &[u64], b: &[u64]) -> u64 {
+    if modulus.is_power_of_two() {
+        // Wrapping arithmetic only needs to be reduced once at the end
+        let mut sum = 0u64;
+        for (x, y) in a.iter().zip(b) {
+            sum = sum.wrapping_add(x.wrapping_mul(*y));
+        }
+        modulus.reduce(sum)
+    } else {
+        let mut sum = 0u64;
+        for (x, y) in a.iter().zip(b) {
+            sum = modulus.add(sum, modulus.mul(*x, *y));
+        }
+        sum
+    }
+}
+
+impl Add for Matrix {
+    type Output = Matrix;
+    fn add(self, rhs: Matrix) -> Self::Output {
+        let mut s = self;
+        s += rhs;
         s
     }
 }
 
 impl AddAssign for Matrix {
     fn add_assign(&mut self, rhs: Matrix) {
-        assert_eq!(self.num_rows(), rhs.num_rows());
         assert_eq!(self.num_cols(), rhs.num_cols());
-        for i in 0..self.num_cols() {
-            for j in 0..self.num_rows() {
-                self.data[i][j] += rhs.data[i][j].clone();
-            }
+        assert_eq!(self.num_rows(), rhs.num_rows());
+        assert_eq!(self.q, rhs.q);
+        let modulus = Modulus::new(self.q);
+        for (a, b) in self.data.iter_mut().zip(rhs.data) {
+            *a = modulus.add(*a, b);
         }
     }
 }
 
 impl Sub for Matrix {
     type Output = Matrix;
-    #[allow(clippy::needless_range_loop)]
     fn sub(self, rhs: Matrix) -> Self::Output {
-        assert_eq!(self.num_rows(), rhs.num_rows());
-        assert_eq!(self.num_cols(), rhs.num_cols());
-
-        let mut s = self.clone();
-
-        for i in 0..self.num_cols() {
-            for j in 0..self.num_rows() {
-                s.data[i][j] = s.data[i][j].clone() - rhs.data[i][j].clone();
-            }
-        }
+        let mut s = self;
+        s -= rhs;
         s
     }
 }
 
 impl SubAssign for Matrix {
     fn sub_assign(&mut self, rhs: Matrix) {
-        assert_eq!(self.num_rows(), rhs.num_rows());
         assert_eq!(self.num_cols(), rhs.num_cols());
-        for i in 0..self.num_cols() {
-            for j in 0..self.num_rows() {
-                self.data[i][j] = self.data[i][j].clone() - rhs.data[i][j].clone();
-            }
+        assert_eq!(self.num_rows(), rhs.num_rows());
+        assert_eq!(self.q, rhs.q);
+        let modulus = Modulus::new(self.q);
+        for (a, b) in self.data.iter_mut().zip(rhs.data) {
+            *a = modulus.sub(*a, b);
         }
     }
 }
@@ -271,31 +597,11 @@ impl Mul for Matrix {
      *                 [b20, b21]
      */
     type Output = Matrix;
-    #[allow(clippy::needless_range_loop)]
     fn mul(self, rhs: Matrix) -> Self::Output {
-        // Ensure that the rhs matrix has the correct dimensions
-        assert_eq!(self.num_rows(), rhs.num_cols());
-
         // Assign a result matrix of the required dimensions with 0s in each cell
-        let zero = Element::zero(self.data[0][0].q);
-        let n = self.num_cols();
-        let m = self.num_rows(); // = rhs.num_cols()
-        let p = rhs.num_rows();
-
-        let each_result_row = vec![zero.clone(); p];
-        let mut result: Vec<Vec<Element>> = vec![each_result_row; n];
-
-        for i in 0..n {
-            for j in 0..p {
-                let mut sum = zero.clone();
-                for k in 0..m {
-                    sum += self.data[i][k].clone() * rhs[k][j].clone();
-                }
-                result[i][j] = sum;
-            }
-        }
-
-        Self::from(&result)
+        let mut result = Self::zeros(self.q, self.num_rows(), rhs.num_cols());
+        self.mul_into(&rhs, &mut result);
+        result
     }
 }
 
@@ -309,17 +615,17 @@ impl MulAssign for Matrix {
 
 impl Display for Matrix {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        for row in self.data.iter() {
+        for i in 0..self.rows {
             write!(f, "[")?;
-            for (j, val) in row.iter().enumerate() {
+            for (j, val) in self[i].iter().enumerate() {
                 write!(f, "{}", val)?;
-                if j != row.len() - 1 {
+                if j != self.cols - 1 {
                     write!(f, ", ")?;
                 }
             }
             write!(f, "]")?;
         }
-        write!(f, "\n")?;
+        writeln!(f)?;
         Ok(())
     }
 }
@@ -330,30 +636,26 @@ pub mod tests {
     use super::Element;
 
     fn gen_q() -> u64 {
-        return 101u64;
+        101u64
     }
 
     // Tests for matrix.rs
-    fn gen_matrix_3_2() -> Matrix {
+    fn gen_matrix_2_3() -> Matrix {
         let q = gen_q();
-        // 3 rows, 2 cols
+        // 2 rows, 3 cols
         Matrix::from(
-            &vec![
-                vec![Element::from(q, 1u64), Element::from(q, 2u64), Element::from(q, 3u64)],
-                vec![Element::from(q, 4u64), Element::from(q, 5u64), Element::from(q, 6u64)],
-            ]
+            &[vec![Element::from(q, 1u64), Element::from(q, 2u64), Element::from(q, 3u64)],
+                vec![Element::from(q, 4u64), Element::from(q, 5u64), Element::from(q, 6u64)]]
         )
     }
 
-    fn gen_matrix_2_3() -> Matrix {
+    fn gen_matrix_3_2() -> Matrix {
         let q = gen_q();
-        // 2 rows, 3 cols
+        // 3 rows, 2 cols
         Matrix::from(
-            &vec![
-                vec![Element::from(q, 1u64), Element::from(q, 4u64)],
+            &[vec![Element::from(q, 1u64), Element::from(q, 4u64)],
                 vec![Element::from(q, 2u64), Element::from(q, 5u64)],
-                vec![Element::from(q, 3u64), Element::from(q, 6u64)],
-            ]
+                vec![Element::from(q, 3u64), Element::from(q, 6u64)]]
         )
     }
 
@@ -361,20 +663,16 @@ pub mod tests {
         let q = gen_q();
         // 2 rows, 2 cols
         Matrix::from(
-            &vec![
-                vec![Element::from(q, 14u64), Element::from(q, 32u64)],
-                vec![Element::from(q, 32u64), Element::from(q, 77u64)],
-            ]
+            &[vec![Element::from(q, 14u64), Element::from(q, 32u64)],
+                vec![Element::from(q, 32u64), Element::from(q, 77u64)]]
         )
     }
 
-    fn gen_matrix_1_2() -> Matrix {
+    fn gen_matrix_2_1() -> Matrix {
         let q = gen_q();
-        // 1 rows, 2 cols
+        // 2 rows, 1 col
         Matrix::from(
-            &vec![
-                vec![Element::from(q, 14u64)], vec![Element::from(q, 32u64)]
-            ]
+            &[vec![Element::from(q, 14u64)], vec![Element::from(q, 32u64)]]
         )
     }
 
@@ -390,37 +688,64 @@ pub mod tests {
     #[test]
     fn test_indices() {
         let q = gen_q();
-        let mut m = gen_matrix_3_2();
-        assert_eq!(m[0][0], Element::from(q, 1u64));
-        assert_eq!(m[0][1], Element::from(q, 2u64));
-        assert_eq!(m[1][1], Element::from(q, 5u64));
+        let mut m = gen_matrix_2_3();
+        assert_eq!(m.get(0, 0), Element::from(q, 1u64));
+        assert_eq!(m.get(0, 1), Element::from(q, 2u64));
+        assert_eq!(m[1][1], 5u64);
 
-        m[1][1] = Element::from(q, 0u64);
-        assert_eq!(m[1][1], Element::from(q, 0u64));
+        m.set(1, 1, Element::from(q, 0u64));
+        assert_eq!(m.get(1, 1), Element::from(q, 0u64));
+        m[1][1] = 3u64;
+        assert_eq!(m.get(1, 1), Element::from(q, 3u64));
+    }
+
+    #[test]
+    fn test_flat_layout() {
+        let m = gen_matrix_2_3();
+        assert_eq!(m.dimensions(), (2, 3));
+        assert_eq!(m.data(), &[1, 2, 3, 4, 5, 6]);
+        assert_eq!(m, Matrix::from_raw(gen_q(), 2, 3, vec![1, 2, 3, 4, 5, 6]));
+        assert_eq!(m.get_row(1), vec![
+            Element::from(gen_q(), 4u64),
+            Element::from(gen_q(), 5u64),
+            Element::from(gen_q(), 6u64),
+        ]);
+    }
+
+    #[test]
+    fn test_append_row() {
+        let mut m = Matrix::new();
+        m.append_row(gen_vec_3());
+        m.append_row(vec![
+            Element::from(gen_q(), 4u64),
+            Element::from(gen_q(), 5u64),
+            Element::from(gen_q(), 6u64),
+        ]);
+        assert_eq!(m, gen_matrix_2_3());
     }
 
     #[test]
     fn test_rotation() {
-        let m = gen_matrix_3_2();
-        let n = gen_matrix_2_3();
+        let m = gen_matrix_2_3();
+        let n = gen_matrix_3_2();
         assert_eq!(m.rotated(), n);
     }
 
     #[test]
     fn test_mul() {
-        // 3 rows, 2 cols
-        let m = gen_matrix_3_2();
         // 2 rows, 3 cols
-        let n = gen_matrix_2_3();
-        // Should have 3 rows and 3 columns
+        let m = gen_matrix_2_3();
+        // 3 rows, 2 cols
+        let n = gen_matrix_3_2();
+        // Should have 2 rows and 2 columns
         let o = gen_matrix_2_2();
         assert_eq!(m * n, o);
     }
 
     #[test]
     fn test_mul_assign() {
-        let mut m = gen_matrix_3_2();
-        let n = gen_matrix_2_3();
+        let mut m = gen_matrix_2_3();
+        let n = gen_matrix_3_2();
         let o = gen_matrix_2_2();
         m *= n;
         assert_eq!(m, o);
@@ -428,45 +753,45 @@ pub mod tests {
 
     #[test]
     fn test_mul_vec() {
-        let m = gen_matrix_3_2();
+        let m = gen_matrix_2_3();
         let v = gen_vec_3();
-        let r = gen_matrix_1_2();
+        let r = gen_matrix_2_1();
         assert_eq!(m.mul_vec(&v), r);
     }
 
     #[test]
     fn test_add() {
-        let m = gen_matrix_3_2();
-        let n = gen_matrix_3_2();
+        let m = gen_matrix_2_3();
+        let n = gen_matrix_2_3();
         let o = m.clone() + n.clone();
         
-        for (i, row) in o.data.iter().enumerate() {
-            for (j, val) in row.iter().enumerate() {
-                assert_eq!(*val, m[i][j].clone() + n[i][j].clone());
+        for i in 0..o.num_rows() {
+            for j in 0..o.num_cols() {
+                assert_eq!(o.get(i, j), m.get(i, j) + n.get(i, j));
             }
         }
     }
 
     #[test]
     fn test_add_assign() {
-        let mut m = gen_matrix_3_2();
-        let n = gen_matrix_3_2();
+        let mut m = gen_matrix_2_3();
+        let n = gen_matrix_2_3();
         let o = m.clone();
         m += n.clone();
         
-        for (i, row) in m.data.iter().enumerate() {
-            for (j, val) in row.iter().enumerate() {
-                assert_eq!(*val, o[i][j].clone() + n[i][j].clone());
+        for i in 0..m.num_rows() {
+            for j in 0..m.num_cols() {
+                assert_eq!(m.get(i, j), o.get(i, j) + n.get(i, j));
             }
         }
     }
 
     #[test]
     fn test_matrix_decomposition() {
-        let m = gen_matrix_3_2();
+        let m = gen_matrix_2_3();
         let decomposed = m.to_owned().decomposed(2);
-        assert_eq!(decomposed.num_cols(), 14);
-        assert_eq!(decomposed.num_rows(), 3);
+        assert_eq!(decomposed.num_rows(), 14);
+        assert_eq!(decomposed.num_cols(), 3);
         let expected = vec![
             vec![1, 0, 1],
             vec![0, 1, 1],
@@ -483,18 +808,192 @@ pub mod tests {
             vec![0, 0, 0],
             vec![0, 0, 0],
         ];
-        for (i, col) in decomposed.data.iter().enumerate() {
-            for j in 0..col.len() {
-                assert_eq!(col[j].uint, expected[i][j] as u64);
+        for (i, row) in expected.iter().enumerate() {
+            assert_eq!(decomposed[i], row[..]);
+        }
+    }
+
+    #[test]
+    fn test_mul_large_q() {
+        // The largest prime below 2^64
+        let q = u64::MAX - 58;
+        let m = Matrix::from(&[vec![Element::from(q, q - 1), Element::from(q, q - 2)]]);
+        let n = Matrix::from(&[vec![Element::from(q, q - 1)], vec![Element::from(q, q - 3)]]);
+        // (-1)(-1) + (-2)(-3) = 7
+        assert_eq!(m * n, Matrix::from_single(&Element::from(q, 7u64)));
+    }
+
+    #[test]
+    fn test_mul_power_of_two_q() {
+        let q = 1u64 << 32;
+        let m = Matrix::from(&[vec![Element::from(q, q - 1), Element::from(q, q - 2)]]);
+        let n = Matrix::from(&[vec![Element::from(q, q - 1)], vec![Element::from(q, q - 3)]]);
+        assert_eq!(m * n, Matrix::from_single(&Element::from(q, 7u64)));
+    }
+
+    // Compute the product entry by entry, for comparison with the tiled implementation
+    fn naive_mul(a: &Matrix, b: &Matrix) -> Matrix {
+        let mut result = Matrix::zeros(a.q(), a.num_rows(), b.num_cols());
+        for i in 0..a.num_rows() {
+            for j in 0..b.num_cols() {
+                let mut sum = Element::zero(a.q());
+                for k in 0..a.num_cols() {
+                    sum += a.get(i, k) * b.get(k, j);
+                }
+                result.set(i, j, sum);
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn test_mul_tiled() {
+        // Dimensions which are not multiples of the tile sizes
+        for q in [3329u64, 1 << 32, u64::MAX - 58] {
+            let a = Matrix::gen_uniform_rand(q, 37, 301);
+            let b = Matrix::gen_uniform_rand(q, 301, 259);
+            let expected = naive_mul(&a, &b);
+
+            let mut out = Matrix::zeros(q, 37, 259);
+            a.mul_into(&b, &mut out);
+            assert_eq!(out, expected);
+
+            // The output buffer can be reused
+            a.mul_into(&b, &mut out);
+            assert_eq!(out, expected);
+            assert_eq!(a.clone() * b, expected);
+
+            // Narrow right-hand sides, which are multiplied by dot products with their columns
+            for width in [1, 4, 7, super::NARROW_RHS - 1, super::NARROW_RHS] {
+                let b = Matrix::gen_uniform_rand(q, 301, width);
+                let mut out = Matrix::zeros(q, 37, width);
+                a.mul_into(&b, &mut out);
+                assert_eq!(out, naive_mul(&a, &b));
+            }
+        }
+    }
+
+    #[test]
+    fn test_append() {
+        let m = Matrix::gen_uniform_rand(3329, 3, 4);
+        let other = Matrix::gen_uniform_rand(3329, 2, 4);
+        let mut appended = m.clone();
+        appended.append_rows(&other);
+        assert_eq!(appended.dimensions(), (5, 4));
+        assert_eq!(appended[1], m[1]);
+        assert_eq!(appended[4], other[1]);
+
+        let other = Matrix::gen_uniform_rand(3329, 3, 2);
+        let mut appended = m.clone();
+        appended.append_cols(&other);
+        assert_eq!(appended.dimensions(), (3, 6));
+        assert_eq!(appended[2][..4], m[2]);
+        assert_eq!(appended[2][4..], other[2]);
+    }
+
+    #[test]
+    fn test_try_ops() {
+        use crate::error::Error;
+
+        let m = gen_matrix_2_3();
+        let q = m.q();
+        let n = Matrix::zeros(q, 3, 3);
+        assert_eq!(
+            m.clone().try_add(n.clone()),
+            Err(Error::DimensionMismatch { expected: (2, 3), found: (3, 3) }),
+        );
+        assert_eq!(
+            m.clone().try_sub(Matrix::zeros(q + 1, 2, 3)),
+            Err(Error::ModulusMismatch { expected: q, found: q + 1 }),
+        );
+        assert_eq!(m.clone().try_add(Matrix::zeros(q, 2, 3)), Ok(m.clone()));
+
+        assert_eq!(m.clone().try_mul(n).unwrap().dimensions(), (2, 3));
+        assert_eq!(
+            m.clone().try_mul(Matrix::zeros(q, 2, 3)),
+            Err(Error::DimensionMismatch { expected: (3, 3), found: (2, 3) }),
+        );
+
+        assert_eq!(
+            m.clone().try_mul_vec(&[Element::zero(q)]),
+            Err(Error::LengthMismatch { expected: 3, found: 1 }),
+        );
+
+        let mut m = m;
+        assert_eq!(m.try_append_row(vec![Element::zero(q)]), Err(Error::LengthMismatch { expected: 3, found: 1 }));
+        assert_eq!(m.try_set(2, 0, Element::zero(q)), Err(Error::IndexOutOfRange { index: 2, len: 2 }));
+        assert_eq!(m.num_rows(), 2);
+    }
+
+    #[test]
+    fn test_mul_acc_block() {
+        for q in [3329u64, 1 << 32] {
+            let a = Matrix::gen_uniform_rand(q, 37, 301);
+            let b = Matrix::gen_uniform_rand(q, 301, 70);
+            let expected = naive_mul(&a, &b);
+
+            // Split the inner dimension into uneven blocks
+            let mut out = Matrix::zeros(q, 37, 70);
+            let mut offset = 0;
+            for k in [100, 1, 0, 200] {
+                let block = Matrix::from_raw(q, k, 70, b.data()[offset * 70..(offset + k) * 70].to_vec());
+                a.mul_acc_block(offset, &block, &mut out);
+                offset += k;
+            }
+            assert_eq!(out, expected);
+        }
+    }
+
+    #[test]
+    fn test_mul_vec_into() {
+        for q in [3329u64, 1 << 32] {
+            let a = Matrix::gen_uniform_rand(q, 45, 70);
+            let v = Matrix::gen_uniform_rand(q, 1, 70);
+            let expected = naive_mul(&a, &v.clone().rotated());
+
+            let mut out = vec![0u64; 45];
+            a.mul_vec_into(v.data(), &mut out);
+            assert_eq!(out, expected.data());
+            assert_eq!(a.mul_vec(&v.get_row(0)), expected);
+        }
+    }
+
+    #[test]
+    fn test_decompose_into() {
+        for q in [3329u64, 1 << 32, 1 << 63, u64::MAX - 58] {
+            let m = Matrix::gen_uniform_rand(q, 6, 5);
+            for base in [2u64, 3, 16, 991, 1 << 16, 1 << 32] {
+                let k = Element::num_digits(q, base);
+                // Every entry of the output is overwritten
+                let mut out = Matrix::from_val(6 * k, 5, Element::from(q, 1));
+                m.decompose_into(base, &mut out);
+                for i in 0..m.num_rows() {
+                    for j in 0..m.num_cols() {
+                        let digits: Vec<u64> = (0..k).map(|d| out[i * k + d][j]).collect();
+                        assert_eq!(digits, m.get(i, j).decomposed(base), "q = {}, base = {}", q, base);
+                    }
+                }
+
+                let mut recomposed = Matrix::from_val(6, 5, Element::from(q, 1));
+                out.recompose_into(base, &mut recomposed);
+                assert_eq!(recomposed, m);
             }
         }
     }
 
     #[test]
     fn test_matrix_recomposition() {
-        let m = gen_matrix_3_2();
+        let m = gen_matrix_2_3();
         let decomposed = m.to_owned().decomposed(2);
-        let r = decomposed.recompose(2, m[0][0].q);
+        let r = decomposed.recompose(2, m.q());
         assert_eq!(m, r);
+
+        let m = Matrix::gen_uniform_rand(1 << 32, 5, 4);
+        for base in [2, 3, 7, 16, 991, 1 << 16] {
+            let decomposed = m.to_owned().decomposed(base);
+            assert_eq!(decomposed.num_rows(), 5 * Element::num_digits(1 << 32, base));
+            assert!(decomposed.data().iter().all(|d| *d < base));
+            assert_eq!(decomposed.recompose(base, m.q()), m);
+        }
     }
 }
@@ -0,0 +1,136 @@
+/// The modulus of an Element, along with the way elements are reduced by it.
+///
+/// Power-of-two moduli, such as the q = 2^32 used in the SimplePIR paper, are reduced by masking,
+/// so additions and multiplications are plain wrapping integer arithmetic. Any other modulus is
+/// reduced with `%`, widening products to 128 bits so that they cannot overflow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Modulus {
+    /// An arbitrary modulus q
+    Arbitrary(u64),
+    /// The modulus 2^k, for k < 64
+    PowerOfTwo(u32),
+}
+
+impl Modulus {
+    /// Select the representation for the modulus q.
+    pub fn new(q: u64) -> Self {
+        assert!(q > 0);
+        if q.is_power_of_two() {
+            Modulus::PowerOfTwo(q.trailing_zeros())
+        } else {
+            Modulus::Arbitrary(q)
+        }
+    }
+
+    /// The value of the modulus q
+    pub fn value(&self) -> u64 {
+        match *self {
+            Modulus::Arbitrary(q) => q,
+            Modulus::PowerOfTwo(k) => 1u64 << k,
+        }
+    }
+
+    pub fn is_power_of_two(&self) -> bool {
+        matches!(self, Modulus::PowerOfTwo(_))
+    }
+
+    /// Reduce any u64 mod q. For a power-of-two modulus, this also reduces the result of any
+    /// sequence of wrapping additions and multiplications.
+    pub fn reduce(&self, x: u64) -> u64 {
+        match *self {
+            Modulus::Arbitrary(q) => x % q,
+            Modulus::PowerOfTwo(k) => x & ((1u64 << k) - 1),
+        }
+    }
+
+    /// Compute `a + b mod q` for `a, b < q`.
+    pub fn add(&self, a: u64, b: u64) -> u64 {
+        match *self {
+            Modulus::Arbitrary(q) => {
+                // Avoid overflowing when q is close to u64::MAX
+                let (sum, overflowed) = a.overflowing_add(b);
+                if overflowed || sum >= q {
+                    sum.wrapping_sub(q)
+                } else {
+                    sum
+                }
+            }
+            Modulus::PowerOfTwo(_) => self.reduce(a.wrapping_add(b)),
+        }
+    }
+
+    /// Compute `a - b mod q` for `a, b < q`.
+    pub fn sub(&self, a: u64, b: u64) -> u64 {
+        match *self {
+            Modulus::Arbitrary(q) => {
+                if a < b {
+                    q - (b - a)
+                } else {
+                    a - b
+                }
+            }
+            Modulus::PowerOfTwo(_) => self.reduce(a.wrapping_sub(b)),
+        }
+    }
+
+    /// Compute `a * b mod q`.
+    pub fn mul(&self, a: u64, b: u64) -> u64 {
+        match *self {
+            Modulus::Arbitrary(q) => ((a as u128 * b as u128) % q as u128) as u64,
+            Modulus::PowerOfTwo(_) => self.reduce(a.wrapping_mul(b)),
+        }
+    }
+
+    /// Round `v * p / q` to the nearest integer, mod p. This maps a noisy value mod q back to the
+    /// plaintext space mod p.
+    pub fn round_to(&self, p: u64, v: u64) -> u64 {
+        let q = self.value() as u128;
+        (((v as u128 * p as u128) + q / 2) / q) as u64 % p
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::Modulus;
+
+    #[test]
+    fn test_new() {
+        assert_eq!(Modulus::new(3329), Modulus::Arbitrary(3329));
+        assert_eq!(Modulus::new(1 << 32), Modulus::PowerOfTwo(32));
+        assert_eq!(Modulus::new(1 << 32).value(), 1 << 32);
+        assert_eq!(Modulus::new(1 << 63).value(), 1 << 63);
+    }
+
+    #[test]
+    fn test_power_of_two_arithmetic() {
+        let q = Modulus::new(1 << 32);
+        let max = (1u64 << 32) - 1;
+        assert_eq!(q.add(max, 2), 1);
+        assert_eq!(q.sub(1, 2), max);
+        assert_eq!(q.mul(max, max), 1);
+        assert_eq!(q.mul(1 << 16, 1 << 16), 0);
+
+        // A chain of wrapping operations only needs to be reduced once
+        let x = max.wrapping_mul(max).wrapping_add(max.wrapping_mul(3));
+        assert_eq!(q.reduce(x), q.add(q.mul(max, max), q.mul(max, 3)));
+    }
+
+    #[test]
+    fn test_arbitrary_arithmetic() {
+        let q = Modulus::new(101);
+        assert_eq!(q.add(100, 2), 1);
+        assert_eq!(q.sub(1, 2), 100);
+        assert_eq!(q.mul(100, 100), 1);
+        assert_eq!(q.reduce(202), 0);
+    }
+
+    #[test]
+    fn test_round_to() {
+        let q = Modulus::new(1 << 32);
+        let delta = (1u64 << 32) / 991;
+        for m in [0u64, 1, 500, 990] {
+            assert_eq!(q.round_to(991, m * delta + 17), m);
+            assert_eq!(q.round_to(991, (m * delta).wrapping_sub(17) & 0xffff_ffff), m);
+        }
+    }
+}
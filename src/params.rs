@@ -0,0 +1,167 @@
+/// A set of LWE parameters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LweParams {
+    // The LWE secret length
+    pub n: usize,
+    // The integer modulus
+    pub q: u64,
+    // The plaintext modulus
+    pub p: u64,
+    // The standard deviation of the error
+    pub std_dev: f64,
+}
+
+impl LweParams {
+    /// Whether ciphertexts can be made and decrypted with these parameters: the plaintext modulus
+    /// must be at most q, and errors need a finite, positive standard deviation to be sampled
+    pub fn is_valid(&self) -> bool {
+        (2..=self.q).contains(&self.p) && self.std_dev.is_finite() && self.std_dev > 0.0
+    }
+}
+
+/// The probability of a decryption failure which the selected parameters allow
+pub const CORRECTNESS_FAILURE_LOG2: u32 = 40;
+
+/// The largest decryption failure probability, 2^-40, which params constructors accept unless told
+/// otherwise
+pub const DEFAULT_MAX_FAILURE: f64 = 1.0 / (1u64 << CORRECTNESS_FAILURE_LOG2) as f64;
+
+/// The integer modulus used at every security level, so that arithmetic mod q is wrapping u32
+/// arithmetic
+pub const Q: u64 = 1 << 32;
+
+/// The standard deviation of the error used at every security level
+pub const STD_DEV: f64 = 6.4;
+
+/// The LWE secret length for each supported security level, with q = 2^32 and std_dev = 6.4.
+///
+/// 128 bits is the choice of the SimplePIR paper. The larger levels use the secret lengths at
+/// which the HomomorphicEncryption.org standard allows a modulus of at least 2^32 with a smaller
+/// error (std_dev = 3.2).
+pub const SECURITY_LEVELS: [(u32, usize); 3] = [
+    (128, 1024),
+    (192, 2048),
+    (256, 4096),
+];
+
+/// The parameters recommended by the SimplePIR paper for 128-bit security: n = 1024, q = 2^32,
+/// std_dev = 6.4, and the largest plaintext modulus p for a database with 2^log_m columns.
+pub const PAPER_PARAMS: [(u32, u64); 8] = [
+    (13, 991),
+    (14, 833),
+    (15, 701),
+    (16, 589),
+    (17, 495),
+    (18, 416),
+    (19, 350),
+    (20, 294),
+];
+
+/// The LWE secret length which achieves `security_bits` bits of security with q = 2^32 and
+/// std_dev = 6.4, or None if no supported secret length does.
+pub fn secret_length(security_bits: u32) -> Option<usize> {
+    SECURITY_LEVELS
+        .iter()
+        .find(|(bits, _)| *bits >= security_bits)
+        .map(|(_, n)| *n)
+}
+
+/// The largest plaintext modulus p for which a sum of `num_additions` ciphertexts mod q, each
+/// multiplied by a value in [-p/2, p/2], decrypts correctly except with probability
+/// 2^-CORRECTNESS_FAILURE_LOG2.
+///
+/// Each error term is subgaussian with parameter std_dev, so the accumulated error is
+/// subgaussian with parameter std_dev * sqrt(num_additions) * p / 2. Decryption is correct while
+/// the error is below q / (2p), which happens except with probability at most
+/// 2 * exp(-t^2 / 2) for t = (q / (2p)) / (std_dev * sqrt(num_additions) * p / 2).
+pub fn max_plaintext_modulus(q: u64, std_dev: f64, num_additions: usize) -> u64 {
+    let t = correctness_sigmas(CORRECTNESS_FAILURE_LOG2);
+    let p_squared = q as f64 / (t * std_dev * (num_additions.max(1) as f64).sqrt());
+    p_squared.sqrt().floor() as u64
+}
+
+/// The probability that a sum of `num_additions` ciphertexts mod q, each multiplied by a value of
+/// magnitude at most `bound`, fails to decrypt to the correct plaintext mod p.
+///
+/// Decryption fails when the accumulated error reaches q / (2p). The error is subgaussian with
+/// parameter std_dev * sqrt(num_additions) * bound, so this happens with probability at most
+/// 2 * exp(-t^2 / 2), where t is the ratio of the two.
+pub fn failure_probability(q: u64, p: u64, std_dev: f64, num_additions: usize, bound: f64) -> f64 {
+    let error_std_dev = std_dev * (num_additions as f64).sqrt() * bound;
+    let t = q as f64 / (2.0 * p as f64) / error_std_dev;
+    (2.0 * (-t * t / 2.0).exp()).min(1.0)
+}
+
+/// The number of standard deviations t at which 2 * exp(-t^2 / 2) = 2^-failure_log2
+pub(crate) fn correctness_sigmas(failure_log2: u32) -> f64 {
+    (2.0 * ((failure_log2 + 1) as f64) * std::f64::consts::LN_2).sqrt()
+}
+
+/// Select LWE parameters with at least `security_bits` bits of security, for which a sum of
+/// `num_additions` ciphertexts scaled by plaintexts in [-p/2, p/2] decrypts correctly. Returns None
+/// if the security level is not supported or no plaintext modulus p >= 2 is correct.
+pub fn select_params(security_bits: u32, num_additions: usize) -> Option<LweParams> {
+    let n = secret_length(security_bits)?;
+
+    // Use the paper's table where it applies
+    let paper_p = PAPER_PARAMS
+        .iter()
+        .find(|(log_m, _)| n == 1024 && num_additions == 1 << log_m)
+        .map(|(_, p)| *p);
+    let p = paper_p.unwrap_or_else(|| max_plaintext_modulus(Q, STD_DEV, num_additions));
+
+    if p < 2 {
+        return None;
+    }
+    Some(LweParams { n, q: Q, p, std_dev: STD_DEV })
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn test_formula_matches_paper() {
+        for (log_m, p) in PAPER_PARAMS {
+            assert_eq!(max_plaintext_modulus(Q, STD_DEV, 1 << log_m), p);
+        }
+    }
+
+    #[test]
+    fn test_failure_probability() {
+        // The paper's parameters are just within 2^-40
+        for (log_m, p) in PAPER_PARAMS {
+            let pr = failure_probability(Q, p, STD_DEV, 1 << log_m, p as f64 / 2.0);
+            assert!(pr <= DEFAULT_MAX_FAILURE);
+            assert!(pr > DEFAULT_MAX_FAILURE / 2.0);
+
+            let pr = failure_probability(Q, p + 1, STD_DEV, 1 << log_m, (p + 1) as f64 / 2.0);
+            assert!(pr > DEFAULT_MAX_FAILURE);
+        }
+
+        // Far too much error
+        assert_eq!(failure_probability(3329, 2, 100.0, 1 << 20, 1.0), 1.0);
+        // No error at all
+        assert_eq!(failure_probability(3329, 2, 6.4, 8, 0.0), 0.0);
+    }
+
+    #[test]
+    fn test_select_params() {
+        let params = select_params(128, 1 << 16).unwrap();
+        assert_eq!(params, LweParams { n: 1024, q: 1 << 32, p: 589, std_dev: 6.4 });
+
+        // Lower security levels use the 128-bit parameters
+        assert_eq!(select_params(80, 1 << 16), Some(params));
+
+        // Between the rows of the table
+        let params = select_params(128, 3 << 15).unwrap();
+        assert!(params.p < 589 && params.p > 495);
+
+        assert_eq!(select_params(192, 1 << 16).unwrap().n, 2048);
+        assert_eq!(select_params(256, 1 << 16).unwrap().n, 4096);
+        assert_eq!(select_params(512, 1 << 16), None);
+
+        // Too many additions for even one bit of plaintext
+        assert_eq!(select_params(128, 1 << 50), None);
+    }
+}
@@ -1,8 +1,15 @@
 use crate::matrix::Matrix;
 use crate::element::Element;
+use crate::error::{check_len, check_modulus, Error, Result};
+use crate::modulus::Modulus;
+use crate::params::select_params;
+use crate::seeded::{gen_seed_with_rng, Seed, SeededMatrix};
+use rand::{rngs::StdRng, CryptoRng, RngCore, SeedableRng};
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Params {
+    // The seed from which A is expanded
+    pub seed: Seed,
     // Public A matrix
     pub a: Matrix,
     // The integer modulus
@@ -18,54 +25,111 @@ pub struct Params {
 }
 
 pub fn simple_params() -> Params {
+    simple_params_with_rng(&mut StdRng::from_entropy())
+}
+
+pub fn simple_params_with_rng<R: RngCore + CryptoRng>(rng: &mut R) -> Params {
     let m = 1;
     let n = 512;
     let q = 3329;
     let p = 2;
     let std_dev = 6.4;
 
-    let a = Matrix::gen_uniform_rand(q, m, n);
+    Params::from_seed(gen_seed_with_rng(rng), q, p, n, m, std_dev)
+}
+
+impl Params {
+    /// Regenerate parameters from the seed of A, which is all that needs to be sent along with
+    /// the moduli and dimensions
+    pub fn from_seed(seed: Seed, q: u64, p: u64, n: usize, m: usize, std_dev: f64) -> Self {
+        let a = SeededMatrix::new(seed, q, m, n).expand();
+        Params { seed, a, q, p, n, m, std_dev }
+    }
+}
+
+/// Generate parameters with at least `security_bits` bits of security, for which a sum of
+/// `num_additions` ciphertexts scaled by plaintexts mod p decrypts correctly. Returns None if the
+/// security level is not supported or no plaintext modulus is correct.
+pub fn gen_secure_params(security_bits: u32, num_additions: usize) -> Option<Params> {
+    gen_secure_params_with_rng(&mut StdRng::from_entropy(), security_bits, num_additions)
+}
+
+pub fn gen_secure_params_with_rng<R: RngCore + CryptoRng>(
+    rng: &mut R,
+    security_bits: u32,
+    num_additions: usize,
+) -> Option<Params> {
+    // Plaintexts lie in [0, p) rather than [-p/2, p/2), which accumulates as much error as four
+    // times as many additions
+    let lwe = select_params(security_bits, 4 * num_additions)?;
+    let m = 1;
 
-    Params { a, q, p, n, m, std_dev }
+    Some(Params::from_seed(gen_seed_with_rng(rng), lwe.q, lwe.p, lwe.n, m, lwe.std_dev))
 }
 
-fn check_secret_length(params: &Params, secret: &Vec<Element>) {
-    // Check that the secret has the correct number of elements
-    assert_eq!(secret.len(), params.n);
+fn check_secret(params: &Params, secret: &[Element]) -> Result<()> {
+    // Check that the secret has the correct number of elements mod q
+    check_len(params.n, secret.len())?;
+    for s in secret {
+        check_modulus(params.q, s.q)?;
+    }
+    Ok(())
 }
 
-fn check_plaintext_mod(params: &Params, plaintext: &Element) {
+fn check_plaintext_mod(params: &Params, plaintext: &Element) -> Result<()> {
     // Check that each element of the plaintext is within range
-    assert!(plaintext.uint < params.p);
-    assert_eq!(plaintext.q, params.p);
+    check_modulus(params.p, plaintext.q)?;
+    check_value(plaintext)
 }
 
-fn check_ciphertext_mod(params: &Params, ciphertext: &Element) {
+fn check_ciphertext_mod(params: &Params, ciphertext: &Element) -> Result<()> {
     // Check that the ciphertext is in range
-    assert!(ciphertext.uint < params.q);
+    check_modulus(params.q, ciphertext.q)?;
+    check_value(ciphertext)
+}
+
+fn check_value(e: &Element) -> Result<()> {
+    if e.uint >= e.q {
+        return Err(Error::ValueOutOfRange { value: e.uint, q: e.q });
+    }
+    Ok(())
 }
 
-fn check_error_length(params: &Params, error: &Vec<Element>) {
-    // Check that the error has the correct number of elements
-    assert_eq!(error.len(), params.m);
+fn check_error(params: &Params, error: &[Element]) -> Result<()> {
+    // Check that the error has the correct number of elements mod q
+    check_len(params.m, error.len())?;
+    for e in error {
+        check_modulus(params.q, e.q)?;
+    }
+    Ok(())
 }
 
 pub fn encrypt(
     params: &Params,
-    secret: &Vec<Element>,
-    e: &Vec<Element>,
+    secret: &[Element],
+    e: &[Element],
     plaintext: &Element,
 ) -> Element {
-    check_secret_length(params, secret);
-    check_plaintext_mod(params, plaintext);
-    check_error_length(params, e);
+    try_encrypt(params, secret, e, plaintext).unwrap()
+}
+
+/// Encrypt as in `encrypt`, or return an error if the inputs do not match the parameters
+pub fn try_encrypt(
+    params: &Params,
+    secret: &[Element],
+    e: &[Element],
+    plaintext: &Element,
+) -> Result<Element> {
+    check_secret(params, secret)?;
+    check_plaintext_mod(params, plaintext)?;
+    check_error(params, e)?;
     // TODO: check error range
 
     // Compute As
     let a_s = params.a.to_owned().mul_vec(secret);
 
     // Compute b = As + e
-    let b = a_s + Matrix::from(&vec![e.clone()]).rotated();
+    let b = a_s + Matrix::from(&[e.to_vec()]).rotated();
 
     let floor = params.q / params.p;
     let floor = Matrix::from_single(&Element::from(params.q, floor));
@@ -76,29 +140,35 @@ pub fn encrypt(
     // Compute the ciphertext As + e + round(q / p) * plaintext
     let c = b + (floor * plaintext_as_matrix);
     
-    c[0][0].clone()
+    Ok(c.get(0, 0))
 }
 
 pub fn decrypt(
     params: &Params,
-    secret: &Vec<Element>,
+    secret: &[Element],
     ciphertext: &Element,
 ) -> Element {
-    check_secret_length(params, secret);
-    check_ciphertext_mod(params, ciphertext);
-    // Compute As
-    let a_s = params.a.clone().mul_vec(secret);
+    try_decrypt(params, secret, ciphertext).unwrap()
+}
 
-    assert_eq!(ciphertext.q, params.q);
-    assert_eq!(a_s[0][0].q, params.q);
+/// Decrypt as in `decrypt`, or return an error if the inputs do not match the parameters
+pub fn try_decrypt(
+    params: &Params,
+    secret: &[Element],
+    ciphertext: &Element,
+) -> Result<Element> {
+    check_secret(params, secret)?;
+    check_ciphertext_mod(params, ciphertext)?;
+    // Compute As
+    let a_s = params.a.clone().try_mul_vec(secret)?;
 
     // Compute c - As
     let raw = Matrix::from_single(ciphertext) - a_s;
 
     // Round to the nearest q / p
-    let x = ((raw[0][0].uint * params.p) as f64 / params.q as f64).round() as u64 % params.p;
+    let x = Modulus::new(params.q).round_to(params.p, raw[0][0]);
 
-    Element::from(params.p, x)
+    Ok(Element::from(params.p, x))
 }
 
 pub fn gen_random_normal_matrix(
@@ -107,39 +177,54 @@ pub fn gen_random_normal_matrix(
     num_rows: usize,
     num_cols: usize,
 ) -> Matrix {
-    let v: Vec<Vec<Element>> = vec![
-        vec![Element::new(q); num_rows];
-        num_cols
-    ];
+    gen_random_normal_matrix_with_rng(&mut StdRng::from_entropy(), q, std_dev, num_rows, num_cols)
+}
 
-    let mut matrix = Matrix::from(&v);
+pub fn gen_random_normal_matrix_with_rng<R: RngCore + CryptoRng>(
+    rng: &mut R,
+    q: u64,
+    std_dev: f64,
+    num_rows: usize,
+    num_cols: usize,
+) -> Matrix {
+    let mut matrix = Matrix::zeros(q, num_rows, num_cols);
 
-    for i in 0..num_cols {
-        for j in 0..num_rows {
-            matrix[i][j] = Element::gen_normal_rand(q, std_dev);
+    for i in 0..num_rows {
+        for j in 0..num_cols {
+            matrix[i][j] = Element::gen_normal_rand_with_rng(rng, q, std_dev).uint;
         }
     }
     matrix
 }
 
 pub fn gen_secret(q: u64, n: usize) -> Vec<Element> {
+    gen_secret_with_rng(&mut StdRng::from_entropy(), q, n)
+}
+
+pub fn gen_secret_with_rng<R: RngCore + CryptoRng>(rng: &mut R, q: u64, n: usize) -> Vec<Element> {
     let mut secret = Vec::with_capacity(n);
     for _ in 0..n {
-        secret.push(Element::gen_uniform_rand(q));
+        secret.push(Element::gen_uniform_rand_with_rng(rng, q));
     }
     secret
 }
 
 
-pub fn gen_error_vec(q: u64, m: usize) -> Vec<Element> {
-    let sample_space = 6;
-    let half_sample_space = sample_space / 2;
+/// Generate m errors mod q from the discrete Gaussian distribution centred at 0 with standard
+/// deviation std_dev.
+pub fn gen_error_vec(q: u64, std_dev: f64, m: usize) -> Vec<Element> {
+    gen_error_vec_with_rng(&mut StdRng::from_entropy(), q, std_dev, m)
+}
+
+pub fn gen_error_vec_with_rng<R: RngCore + CryptoRng>(
+    rng: &mut R,
+    q: u64,
+    std_dev: f64,
+    m: usize,
+) -> Vec<Element> {
     let mut error_vec = Vec::with_capacity(m);
     for _ in 0..m {
-        let rand = Element::gen_uniform_rand(sample_space);
-        let mut e = Element::from(q, rand.uint);
-        e -= Element::from(q, half_sample_space);
-        error_vec.push(e);
+        error_vec.push(Element::gen_normal_rand_with_rng(rng, q, std_dev));
     }
     error_vec
 }
@@ -152,7 +237,7 @@ pub mod tests {
     fn test_gen_random_normal_matrix() {
         let num_rows = 9;
         let num_cols = 10;
-        let matrix = gen_random_normal_matrix(101u64, 6.4 as f64, num_rows, num_cols);
+        let matrix = gen_random_normal_matrix(101u64, 6.4_f64, num_rows, num_cols);
         assert_eq!(matrix.num_rows(), num_rows);
         assert_eq!(matrix.num_cols(), num_cols);
     }
@@ -160,7 +245,7 @@ pub mod tests {
     fn encrypt_and_decrypt_impl(pu: u64) {
         let params = simple_params();
         let secret = gen_secret(params.q, params.n);
-        let e = gen_error_vec(params.q, params.m);
+        let e = gen_error_vec(params.q, params.std_dev, params.m);
 
         let plaintext = Element::from(params.p, pu);
         let ciphertext = encrypt(&params, &secret, &e, &plaintext);
@@ -177,14 +262,14 @@ pub mod tests {
 
     fn homomorphic_addition_impl(params: &Params) {
         let secret = gen_secret(params.q, params.n);
-        let e_0 = gen_error_vec(params.q, params.m);
-        let e_1 = gen_error_vec(params.q, params.m);
+        let e_0 = gen_error_vec(params.q, params.std_dev, params.m);
+        let e_1 = gen_error_vec(params.q, params.std_dev, params.m);
 
         let plaintext_0 = Element::from(params.p, 0);
-        let ciphertext_0 = encrypt(&params, &secret, &e_0, &plaintext_0);
+        let ciphertext_0 = encrypt(params, &secret, &e_0, &plaintext_0);
 
         let plaintext_1 = Element::from(params.p, 1);
-        let ciphertext_1 = encrypt(&params, &secret, &e_1, &plaintext_1);
+        let ciphertext_1 = encrypt(params, &secret, &e_1, &plaintext_1);
 
         let a_n = params.a.clone() + params.a.clone();
         let mut params = params.clone();
@@ -194,6 +279,62 @@ pub mod tests {
         assert_eq!(plaintext_n, decrypt(&params, &secret, &ciphertext_n));
     }
 
+    #[test]
+    fn test_large_modulus() {
+        // q * p overflows 64 bits and q has more bits than an f64 mantissa, so decryption must
+        // round without forming the product
+        for q in [u64::MAX - 58, 1 << 63] {
+            let params = Params::from_seed([7; 32], q, 991, 64, 1, 6.4);
+            let secret = gen_secret(params.q, params.n);
+            for pu in [0, 1, 495, 990] {
+                let e = gen_error_vec(params.q, params.std_dev, params.m);
+                let plaintext = Element::from(params.p, pu);
+                let ciphertext = encrypt(&params, &secret, &e, &plaintext);
+                assert_eq!(decrypt(&params, &secret, &ciphertext), plaintext);
+            }
+        }
+    }
+
+    #[test]
+    fn test_try_encrypt_and_decrypt() {
+        let params = simple_params();
+        let secret = gen_secret(params.q, params.n);
+        let e = gen_error_vec(params.q, params.std_dev, params.m);
+        let plaintext = Element::from(params.p, 1);
+
+        assert_eq!(
+            try_encrypt(&params, &secret[1..], &e, &plaintext),
+            Err(Error::LengthMismatch { expected: params.n, found: params.n - 1 }),
+        );
+        assert_eq!(
+            try_encrypt(&params, &secret, &e, &Element::from(params.q, 1)),
+            Err(Error::ModulusMismatch { expected: params.p, found: params.q }),
+        );
+        assert_eq!(
+            try_decrypt(&params, &secret, &Element::from(params.p, 1)),
+            Err(Error::ModulusMismatch { expected: params.q, found: params.p }),
+        );
+
+        let ciphertext = try_encrypt(&params, &secret, &e, &plaintext).unwrap();
+        assert_eq!(try_decrypt(&params, &secret, &ciphertext), Ok(plaintext));
+    }
+
+    #[test]
+    fn test_from_seed() {
+        let params = simple_params();
+        let regenerated = Params::from_seed(params.seed, params.q, params.p, params.n, params.m, params.std_dev);
+        assert_eq!(regenerated, params);
+    }
+
+    #[test]
+    fn test_secure_params() {
+        let params = gen_secure_params(128, 2).unwrap();
+        assert_eq!((params.n, params.q), (1024, 1 << 32));
+        for _ in 0..10 {
+            homomorphic_addition_impl(&params);
+        }
+    }
+
     #[test]
     fn test_homomorphic_addition() {
         let params = simple_params();
@@ -206,7 +347,7 @@ pub mod tests {
         let mut params = simple_params();
         params.p = 3;
         let secret = gen_secret(params.q, params.n);
-        let e = gen_error_vec(params.q, params.m);
+        let e = gen_error_vec(params.q, params.std_dev, params.m);
 
         // Encrypt and decrypt the value 1 mod 3
         let plaintext_1 = Element::from(params.p, 1);
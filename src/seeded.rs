@@ -0,0 +1,181 @@
+use crate::element::Element;
+use crate::matrix::Matrix;
+use rand::{rngs::StdRng, CryptoRng, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+/// A seed from which a public matrix is expanded
+pub type Seed = [u8; 32];
+
+/// The number of rows expanded at a time when a seeded matrix is streamed
+const STREAM_ROWS: usize = 256;
+
+pub fn gen_seed() -> Seed {
+    gen_seed_with_rng(&mut StdRng::from_entropy())
+}
+
+pub fn gen_seed_with_rng<R: RngCore + CryptoRng>(rng: &mut R) -> Seed {
+    let mut seed = Seed::default();
+    rng.fill_bytes(&mut seed);
+    seed
+}
+
+/// A matrix of uniformly random elements mod q, expanded deterministically from a 32-byte seed
+/// with ChaCha20.
+///
+/// Only the seed and the dimensions need to be stored or sent, and anyone holding them expands
+/// the same matrix. Products with the matrix are computed by expanding STREAM_ROWS rows at a
+/// time, so the whole matrix is never held in memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeededMatrix {
+    seed: Seed,
+    q: u64,
+    rows: usize,
+    cols: usize,
+}
+
+impl SeededMatrix {
+    pub fn new(seed: Seed, q: u64, rows: usize, cols: usize) -> Self {
+        Self { seed, q, rows, cols }
+    }
+
+    /// The matrix expanded from the same seed with `rows` rows. Rows are expanded in order,
+    /// so the rows which both matrices have are the same.
+    pub fn with_rows(&self, rows: usize) -> Self {
+        Self { rows, ..*self }
+    }
+
+    /// A matrix with a fresh random seed
+    pub fn gen_with_rng<R: RngCore + CryptoRng>(rng: &mut R, q: u64, rows: usize, cols: usize) -> Self {
+        Self::new(gen_seed_with_rng(rng), q, rows, cols)
+    }
+
+    pub fn seed(&self) -> &Seed {
+        &self.seed
+    }
+
+    pub fn q(&self) -> u64 {
+        self.q
+    }
+
+    pub fn num_rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn num_cols(&self) -> usize {
+        self.cols
+    }
+
+    pub fn dimensions(&self) -> (usize, usize) {
+        (self.num_rows(), self.num_cols())
+    }
+
+    /// Expand the whole matrix
+    pub fn expand(&self) -> Matrix {
+        Matrix::gen_uniform_rand_with_rng(&mut ChaCha20Rng::from_seed(self.seed), self.q, self.rows, self.cols)
+    }
+
+    /// Expand the matrix in order, `block_rows` rows at a time, calling `f` with the index of
+    /// the first row of each block and the block itself.
+    pub fn for_each_block<F: FnMut(usize, &Matrix)>(&self, block_rows: usize, mut f: F) {
+        assert!(block_rows > 0);
+        let mut rng = ChaCha20Rng::from_seed(self.seed);
+        for start in (0..self.rows).step_by(block_rows) {
+            let rows = block_rows.min(self.rows - start);
+            let block = Matrix::gen_uniform_rand_with_rng(&mut rng, self.q, rows, self.cols);
+            f(start, &block);
+        }
+    }
+
+    /// Expand only the given rows, in the order given, as the rows of a new matrix. The
+    /// rows before the last one are still generated, but never all held at once, and the rows
+    /// after it are not generated at all.
+    pub fn expand_rows(&self, rows: &[usize]) -> Matrix {
+        assert!(rows.iter().all(|i| *i < self.rows));
+        let mut result = Matrix::zeros(self.q, rows.len(), self.cols);
+        let end = rows.iter().max().map_or(0, |i| i + 1);
+        self.with_rows(end).for_each_block(STREAM_ROWS, |start, block| {
+            for (t, i) in rows.iter().enumerate() {
+                if (start..start + block.num_rows()).contains(i) {
+                    result[t].copy_from_slice(&block[i - start]);
+                }
+            }
+        });
+        result
+    }
+
+    /// Compute `self.expand().mul_vec(rhs)` without expanding the whole matrix
+    pub fn mul_vec(&self, rhs: &[Element]) -> Matrix {
+        let rhs: Vec<u64> = rhs.iter().map(|e| {
+            assert_eq!(e.q, self.q);
+            e.uint
+        }).collect();
+
+        let mut result = Matrix::zeros(self.q, self.rows, 1);
+        self.for_each_block(STREAM_ROWS, |start, block| {
+            let out = &mut result.data_mut()[start..start + block.num_rows()];
+            block.mul_vec_into(&rhs, out);
+        });
+        result
+    }
+
+    /// Compute `lhs * self.expand()` without expanding the whole matrix
+    pub fn left_mul(&self, lhs: &Matrix) -> Matrix {
+        assert_eq!(lhs.num_cols(), self.rows);
+        assert_eq!(lhs.q(), self.q);
+
+        let mut result = Matrix::zeros(self.q, lhs.num_rows(), self.cols);
+        self.for_each_block(STREAM_ROWS, |start, block| {
+            lhs.mul_acc_block(start, block, &mut result);
+        });
+        result
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::regev::gen_secret;
+
+    #[test]
+    fn test_expand() {
+        let a = SeededMatrix::new([7; 32], 1 << 32, 600, 20);
+        let expanded = a.expand();
+        assert_eq!(expanded.dimensions(), (600, 20));
+        assert_eq!(expanded, a.expand());
+        assert_ne!(expanded, SeededMatrix::new([8; 32], 1 << 32, 600, 20).expand());
+
+        // Streaming expands the same rows
+        let mut streamed = Matrix::default();
+        a.for_each_block(256, |start, block| {
+            assert_eq!(start, streamed.num_rows());
+            for i in 0..block.num_rows() {
+                streamed.append_row(block.get_row(i));
+            }
+        });
+        assert_eq!(streamed, expanded);
+    }
+
+    #[test]
+    fn test_streamed_products() {
+        for q in [3329u64, 1 << 32] {
+            let a = SeededMatrix::new([1; 32], q, 700, 30);
+            let expanded = a.expand();
+
+            let s = gen_secret(q, 30);
+            assert_eq!(a.mul_vec(&s), expanded.clone().mul_vec(&s));
+
+            let lhs = Matrix::gen_uniform_rand(q, 5, 700);
+            assert_eq!(a.left_mul(&lhs), lhs * expanded.clone());
+
+            // Growing the matrix keeps its existing rows
+            let grown = a.with_rows(900).expand();
+            assert_eq!(&grown.data()[..expanded.data().len()], expanded.data());
+
+            let rows = a.expand_rows(&[699, 3, 256, 3]);
+            assert_eq!(rows.dimensions(), (4, 30));
+            for (t, i) in [699, 3, 256, 3].into_iter().enumerate() {
+                assert_eq!(rows[t], expanded[i]);
+            }
+        }
+    }
+}
@@ -1,192 +1,1457 @@
+use crate::database::RecordLayout;
 use crate::matrix::Matrix;
 use crate::element::Element;
-use crate::regev::gen_error_vec;
+use crate::error::{check_dimensions, check_elements, check_index, check_len, check_modulus, Error, Result};
+use crate::modulus::Modulus;
+use crate::params::{self, select_params, DEFAULT_MAX_FAILURE};
+use crate::regev::{gen_error_vec_with_rng, gen_secret_with_rng};
+use crate::seeded::SeededMatrix;
+use rand::{rngs::StdRng, CryptoRng, RngCore, SeedableRng};
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct SimplePIRParams {
-    // Public A matrix
-    pub a: Matrix,
+    // Public A matrix, expanded from a seed
+    pub a: SeededMatrix,
     // The integer modulus
     pub q: u64,
     // The plaintext modulus
     pub p: u64,
     // The LWE secret length
     pub n: usize,
-    // The number of samples or the width of the (square) database
+    // The number of rows of the database, which is also the length of an answer
+    pub l: usize,
+    // The number of columns of the database, which is also the number of samples and the length
+    // of a query
     pub m: usize,
     // The standard deviation for sampling random elements
     pub std_dev: f64,
 }
 
 pub fn gen_params() -> SimplePIRParams {
-    let m = 8;
-    let n = 64;
-    let q = 3329;
-    let p = 2;
-    let std_dev = 6.4;
-    let a = Matrix::gen_uniform_rand(q, m, n);
+    gen_params_with_q(3329)
+}
+
+/// Generate parameters with the integer modulus q. If q is a power of two, such as the q = 2^32
+/// used in the paper, all arithmetic mod q is wrapping integer arithmetic.
+pub fn gen_params_with_q(q: u64) -> SimplePIRParams {
+    gen_params_with(8, 8, 64, q, 2, 6.4)
+}
+
+/// Generate parameters for an l x m database (l rows of m columns) of elements mod p, with LWE
+/// secrets of length n mod q. Panics if an answer fails to decrypt with probability above
+/// DEFAULT_MAX_FAILURE.
+pub fn gen_params_with(l: usize, m: usize, n: usize, q: u64, p: u64, std_dev: f64) -> SimplePIRParams {
+    gen_params_with_rng(&mut StdRng::from_entropy(), l, m, n, q, p, std_dev)
+}
+
+/// Generate parameters as in `gen_params_with`, drawing randomness from `rng`.
+pub fn gen_params_with_rng<R: RngCore + CryptoRng>(
+    rng: &mut R,
+    l: usize,
+    m: usize,
+    n: usize,
+    q: u64,
+    p: u64,
+    std_dev: f64,
+) -> SimplePIRParams {
+    assert!(
+        failure_probability_with(m, q, p, std_dev) <= DEFAULT_MAX_FAILURE,
+        "answers fail to decrypt with too high a probability",
+    );
+    let a = SeededMatrix::gen_with_rng(rng, q, m, n);
+
+    SimplePIRParams { a, q, p, n, l, m, std_dev }
+}
+
+/// Generate parameters as in `gen_params_with`, or None if an answer fails to decrypt with
+/// probability above `max_failure`.
+pub fn gen_params_with_max_failure(
+    l: usize,
+    m: usize,
+    n: usize,
+    q: u64,
+    p: u64,
+    std_dev: f64,
+    max_failure: f64,
+) -> Option<SimplePIRParams> {
+    gen_params_with_max_failure_with_rng(&mut StdRng::from_entropy(), l, m, n, q, p, std_dev, max_failure)
+}
+
+/// Generate parameters as in `gen_params_with_max_failure`, drawing randomness from `rng`.
+#[allow(clippy::too_many_arguments)]
+pub fn gen_params_with_max_failure_with_rng<R: RngCore + CryptoRng>(
+    rng: &mut R,
+    l: usize,
+    m: usize,
+    n: usize,
+    q: u64,
+    p: u64,
+    std_dev: f64,
+    max_failure: f64,
+) -> Option<SimplePIRParams> {
+    if failure_probability_with(m, q, p, std_dev) > max_failure {
+        return None;
+    }
+    let a = SeededMatrix::gen_with_rng(rng, q, m, n);
+
+    Some(SimplePIRParams { a, q, p, n, l, m, std_dev })
+}
 
-    SimplePIRParams { a, q, p, n, m, std_dev }
+/// The probability that an entry of an answer fails to decrypt. Recovering a whole column of l
+/// entries fails with probability at most l times this.
+pub fn failure_probability(params: &SimplePIRParams) -> f64 {
+    failure_probability_with(params.m, params.q, params.p, params.std_dev)
+}
+
+fn failure_probability_with(m: usize, q: u64, p: u64, std_dev: f64) -> f64 {
+    // Each entry of an answer sums m ciphertexts, scaled by database entries centred in
+    // [-p/2, p/2)
+    params::failure_probability(q, p, std_dev, m, p as f64 / 2.0)
+}
+
+/// Generate parameters for an l x m database with at least `security_bits` bits of security, using
+/// the largest plaintext modulus for which answers decrypt correctly. Returns None if the security
+/// level is not supported or the database has too many columns for any plaintext modulus.
+pub fn gen_secure_params(security_bits: u32, l: usize, m: usize) -> Option<SimplePIRParams> {
+    gen_secure_params_with_rng(&mut StdRng::from_entropy(), security_bits, l, m)
+}
+
+pub fn gen_secure_params_with_rng<R: RngCore + CryptoRng>(
+    rng: &mut R,
+    security_bits: u32,
+    l: usize,
+    m: usize,
+) -> Option<SimplePIRParams> {
+    let lwe = select_params(security_bits, m)?;
+    Some(gen_params_with_rng(rng, l, m, lwe.n, lwe.q, lwe.p, lwe.std_dev))
 }
 
 /// Generate a database of random values mod the plaintext modulus p
 pub fn gen_db(params: &SimplePIRParams) -> Matrix {
-    Matrix::gen_uniform_rand(
+    gen_db_with_rng(&mut StdRng::from_entropy(), params)
+}
+
+pub fn gen_db_with_rng<R: RngCore + CryptoRng>(rng: &mut R, params: &SimplePIRParams) -> Matrix {
+    Matrix::gen_uniform_rand_with_rng(
+        rng,
         params.p,
-        params.m,
+        params.l,
         params.m,
     )
 }
 
+/// Pack byte records, which must all have the same length, into a database of elements mod p.
+/// Each record is stored within a single column, so it can be retrieved with one query.
+pub fn gen_db_from_records(params: &SimplePIRParams, records: &[Vec<u8>]) -> (Matrix, RecordLayout) {
+    assert!(!records.is_empty());
+    let layout = RecordLayout::new(records.len(), records[0].len(), params.p, params.l);
+    assert!(layout.num_cols() <= params.m, "the records do not fit in the database");
+    let db = layout.pack(records, params.p, params.l, params.m);
+    (db, layout)
+}
+
+/// The shape of a database of byte records, and the communication it costs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DbShape {
+    // The number of rows of the database
+    pub l: usize,
+    // The number of columns of the database
+    pub m: usize,
+    pub layout: RecordLayout,
+    // The size of the hint, which each client downloads once
+    pub hint_bytes: usize,
+    // The size of each query
+    pub query_bytes: usize,
+    // The size of each answer
+    pub answer_bytes: usize,
+}
+
+/// The number of bytes needed to send `count` elements mod q, packed into ceil(log2(q)) bits each
+pub fn bytes_mod_q(count: usize, q: u64) -> usize {
+    let bits = (u64::BITS - (q - 1).leading_zeros()) as usize;
+    (count * bits).div_ceil(8)
+}
+
+/// Choose the shape of a database holding `num_records` records of `record_size` bytes as elements
+/// mod p, for LWE secrets of length n mod q.
+///
+/// Each query uploads m elements mod q and downloads l, so the shape minimises l + m, which makes
+/// the database as close to square as the record size allows. Ties go to the smaller hint, which
+/// is l x n elements mod q.
+pub fn plan_shape(num_records: usize, record_size: usize, p: u64, n: usize, q: u64) -> DbShape {
+    assert!(num_records > 0);
+    // The number of digits per record does not depend on the width of the database
+    let digits = RecordLayout::new(1, record_size, p, usize::MAX).digits_per_record;
+
+    // With r records in each column, l = r * digits and m = ceil(num_records / r), so l + m is
+    // smallest close to r = sqrt(num_records / digits)
+    let r_opt = (num_records as f64 / digits as f64).sqrt();
+    let r_min = (r_opt.floor() as usize).saturating_sub(2).max(1);
+    let r_max = (r_opt.ceil() as usize + 2).min(num_records);
+
+    let (l, m) = (r_min..=r_max)
+        .map(|r| (r * digits, num_records.div_ceil(r)))
+        .min_by_key(|(l, m)| (l + m, *l))
+        .unwrap();
+
+    DbShape {
+        l,
+        m,
+        layout: RecordLayout::new(num_records, record_size, p, l),
+        hint_bytes: bytes_mod_q(l * n, q),
+        query_bytes: bytes_mod_q(m, q),
+        answer_bytes: bytes_mod_q(l, q),
+    }
+}
+
+/// Lift a database of elements mod p to elements mod q, centred in [-p/2, p/2) rather than
+/// [0, p). The error which the server accumulates while answering a query grows with the size of
+/// the database entries, so centring them halves it.
+pub fn lift_db(db: &Matrix, q: u64) -> Matrix {
+    let modulus = Modulus::new(q);
+    let lifted = db.data().iter().map(|v| lift(&modulus, db.q(), *v)).collect();
+    Matrix::from_raw(q, db.num_rows(), db.num_cols(), lifted)
+}
+
+/// Lift a single value mod p as in `lift_db`
+fn lift(modulus: &Modulus, p: u64, v: u64) -> u64 {
+    modulus.sub(v, p / 2)
+}
+
 /// Generates the client's hint, which is the database multiplied by A. Also known as the setup.
+/// A is expanded from its seed a block at a time, so it is never held in memory.
 pub fn gen_hint(params: &SimplePIRParams, db: &Matrix) -> Matrix {
-    let mut db_q = db.clone();
-    db_q.change_q(params.q);
-    db_q.to_owned() * params.a.to_owned()
+    params.a.left_mul(&lift_db(db, params.q))
 }
 
 /// Generate a query to be sent to the server.
 pub fn query(
     params: &SimplePIRParams,
     idx: usize,
-    s: &Vec<Element>,
+    s: &[Element],
+) -> Vec<Element> {
+    query_with_rng(&mut StdRng::from_entropy(), params, idx, s)
+}
+
+pub fn query_with_rng<R: RngCore + CryptoRng>(
+    rng: &mut R,
+    params: &SimplePIRParams,
+    idx: usize,
+    s: &[Element],
 ) -> Vec<Element> {
-    let db_size = params.m;
-    assert!(idx < db_size);
+    try_query_with_rng(rng, params, idx, s).unwrap()
+}
+
+/// Generate a query as in `query`, or an error if the column is out of range or the secret does
+/// not match the parameters.
+pub fn try_query(params: &SimplePIRParams, idx: usize, s: &[Element]) -> Result<Vec<Element>> {
+    try_query_with_rng(&mut StdRng::from_entropy(), params, idx, s)
+}
+
+pub fn try_query_with_rng<R: RngCore + CryptoRng>(
+    rng: &mut R,
+    params: &SimplePIRParams,
+    idx: usize,
+    s: &[Element],
+) -> Result<Vec<Element>> {
+    check_index(idx, params.m)?;
+    check_secret(params, s)?;
     // q / p
     let floor = params.q / params.p;
 
     // The error term
-    let e = gen_error_vec(params.q, params.m);
-    let err_matrix = Matrix::from_col(&e);
+    let e = gen_error_vec_with_rng(rng, params.q, params.std_dev, params.m);
+    let err_matrix = Matrix::from_row(&e);
 
     // query = A * s + e + q/p * u_i_col
-    let mut query = params.a.to_owned().mul_vec(s);
+    let mut query = params.a.mul_vec(s);
     query += err_matrix.rotated();
 
     // Add q/p * 1 only to the index corresponding to the desired column
-    query[idx][0] += Element::from(params.q, floor);
+    let with_floor = query.get(idx, 0) + Element::from(params.q, floor);
+    query.set(idx, 0, with_floor);
 
-    query.rotated()[0].to_owned()
+    Ok(query.rotated().get_row(0))
+}
+
+fn check_secret(params: &SimplePIRParams, s: &[Element]) -> Result<()> {
+    check_len(params.n, s.len())?;
+    for e in s {
+        check_modulus(params.q, e.q)?;
+    }
+    Ok(())
 }
 
-pub fn answer(query: &Vec<Element>, db: &Matrix) -> 
+pub fn answer(query: &[Element], db: &Matrix) -> 
     Matrix
 {
-    let mut db_q = db.clone();
-    db_q.change_q(query[0].q);
-    db_q.to_owned().mul_vec(query)
+    lift_db(db, query[0].q).mul_vec(query)
 }
 
-pub fn recover_row(
+/// Check a query received from an untrusted client: it must have an entry for every column of
+/// the database, and every entry must be reduced mod the q of the params.
+pub fn validate_query(params: &SimplePIRParams, query: &[Element]) -> Result<()> {
+    check_len(params.m, query.len())?;
+    check_elements(params.q, query)
+}
+
+/// Answer a query as in `answer`, or return an error if the query does not have an entry mod q
+/// for every column of the database. The modulus is taken from the query, so untrusted queries
+/// should be checked with `validate_query` first.
+pub fn try_answer(query: &[Element], db: &Matrix) -> Result<Matrix> {
+    check_len(db.num_cols(), query.len())?;
+    let q = query.first().map_or(db.q(), |e| e.q);
+    check_elements(q, query)?;
+    lift_db(db, q).try_mul_vec(query)
+}
+
+/// Undo the centring of `lift_db` on a decrypted element mod p
+fn uncentre(p: u64, x: u64) -> Element {
+    Element::from(p, (x + p / 2) % p)
+}
+
+pub fn recover_col(
     params: &SimplePIRParams,
-    s: &Vec<Element>,
+    s: &[Element],
     hint: &Matrix,
     answer: &Matrix,
 ) -> Vec<Element> {
+    try_recover_col(params, s, hint, answer).unwrap()
+}
+
+/// Recover a column as in `recover_col`, or return an error if the secret, hint or answer do not
+/// match the parameters.
+pub fn try_recover_col(
+    params: &SimplePIRParams,
+    s: &[Element],
+    hint: &Matrix,
+    answer: &Matrix,
+) -> Result<Vec<Element>> {
+    check_recover_inputs(params, s, hint, answer)?;
     let p = params.p;
-    let q = params.q as f64;
+    let q = Modulus::new(params.q);
 
     let interim = hint.to_owned().mul_vec(s);
     let mut ans = answer.to_owned();
     ans -= interim;
 
-    ans.data.iter().map(
-        |v| Element::from(p, ((v[0].uint * p) as f64 / q).round() as u64 % p)
-    ).collect()
+    Ok((0..ans.num_rows()).map(
+        |i| uncentre(p, q.round_to(p, ans[i][0]))
+    ).collect())
+}
+
+fn check_recover_inputs(params: &SimplePIRParams, s: &[Element], hint: &Matrix, answer: &Matrix) -> Result<()> {
+    check_secret(params, s)?;
+    check_modulus(params.q, hint.q())?;
+    check_dimensions((params.l, params.n), hint.dimensions())?;
+    check_modulus(params.q, answer.q())?;
+    check_dimensions((params.l, 1), answer.dimensions())?;
+    answer.check_reduced()
 }
 
 pub fn recover(
     params: &SimplePIRParams,
-    s: &Vec<Element>,
+    s: &[Element],
     idx: usize,
     hint: &Matrix,
     answer: &Matrix,
 ) -> Element {
+    try_recover(params, s, idx, hint, answer).unwrap()
+}
+
+/// Recover an item as in `recover`, or return an error if the index is out of range or the
+/// secret, hint or answer do not match the parameters.
+pub fn try_recover(
+    params: &SimplePIRParams,
+    s: &[Element],
+    idx: usize,
+    hint: &Matrix,
+    answer: &Matrix,
+) -> Result<Element> {
+    check_index(idx, params.l)?;
+    check_recover_inputs(params, s, hint, answer)?;
     let p = params.p;
-    let q = params.q as f64;
+    let q = Modulus::new(params.q);
 
     let interim = hint.to_owned().mul_vec(s);
     let mut ans = answer.to_owned();
     ans -= interim;
 
-    let x = ((ans[idx][0].uint * p) as f64 / q).round() as u64 % p;
-    Element::from(p, x)
+    let x = q.round_to(p, ans[idx][0]);
+    Ok(uncentre(p, x))
+}
+
+/// A query for one column of the database, as sent to the server
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimplePirQuery(pub Vec<Element>);
+
+/// The server's answer to a SimplePirQuery
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimplePirAnswer(pub Matrix);
+
+/// What the client remembers about a query it has sent. This includes the query's LWE secret, so
+/// it must never leave the client.
+#[derive(Debug)]
+pub struct SimplePirQueryState {
+    row: usize,
+    col: usize,
+    s: Vec<Element>,
+}
+
+impl SimplePirQueryState {
+    /// The (row, col) index of the item which was queried
+    pub fn index(&self) -> (usize, usize) {
+        (self.row, self.col)
+    }
+}
+
+/// Several queries sent at once, as the columns of a matrix with dimensions (m, b): entry [k][t]
+/// is entry k of query t.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimplePirBatchQuery(pub Matrix);
+
+/// The server's answers to a SimplePirBatchQuery, as the columns of a matrix with dimensions
+/// (l, b)
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimplePirBatchAnswer(pub Matrix);
+
+/// What the client remembers about a batch of queries: the index and LWE secret of each one, in
+/// the order they were made. The secrets decrypt the answer, so it must never leave the client.
+#[derive(Debug)]
+pub struct SimplePirBatchState {
+    queries: Vec<SimplePirQueryState>,
+}
+
+impl SimplePirBatchState {
+    /// The (row, col) indices of the items which were queried, in order
+    pub fn indices(&self) -> Vec<(usize, usize)> {
+        self.queries.iter().map(|s| s.index()).collect()
+    }
+}
+
+/// A change to the database held by a SimplePirServer
+#[derive(Debug, Clone, PartialEq)]
+pub enum SimplePirEdit {
+    /// Set the item at db[row][col]
+    Cell { row: usize, col: usize, value: Element },
+    /// Replace row `row`, i.e. db[row][col] for every col, which only changes the same row of the
+    /// hint
+    Row { row: usize, values: Vec<Element> },
+}
+
+/// The entries of the database which changed after it was edited: db[rows[i]][cols[i]] changed by
+/// diffs[i], so row rows[i] of the hint changes by diffs[i] times row cols[i] of A, which clients
+/// expand from its seed. The differences between lifted entries lie in (-p, p), so they are sent
+/// mod q, as mod p a difference could not be told apart from itself minus p. A delta adds to the
+/// hint, so it must be applied exactly once.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimplePirHintDelta {
+    pub rows: Vec<usize>,
+    pub cols: Vec<usize>,
+    pub diffs: Vec<Element>,
+}
+
+/// Add each difference in a delta times the matching row of A to the hint, expanding only the
+/// rows of A which are needed
+fn apply_diffs(a: &SeededMatrix, hint: &mut Matrix, delta: &SimplePirHintDelta) {
+    let mut cols = delta.cols.clone();
+    cols.sort_unstable();
+    cols.dedup();
+    let a_rows = a.expand_rows(&cols);
+    let modulus = Modulus::new(hint.q());
+    for ((row, col), diff) in delta.rows.iter().zip(&delta.cols).zip(&delta.diffs) {
+        let a_row = &a_rows[cols.binary_search(col).unwrap()];
+        for (h, a) in hint[*row].iter_mut().zip(a_row) {
+            *h = modulus.add(*h, modulus.mul(diff.uint, *a));
+        }
+    }
+}
+
+/// How the hint grows when the database is extended, as sent to clients
+#[derive(Debug, Clone, PartialEq)]
+pub enum SimplePirGrowth {
+    /// The new rows of the hint for rows appended to the database
+    Rows(Matrix),
+    /// The amount to add to every row of the hint after `cols` columns were appended to the
+    /// database. This is as large as the hint itself, so databases which grow often should grow
+    /// by rows.
+    Cols { cols: usize, hint_diff: Matrix },
+}
+
+/// The server side of SimplePIR. It holds the database and the hint that clients download once
+/// during the offline phase, and answers queries during the online phase.
+pub struct SimplePirServer {
+    params: SimplePIRParams,
+    // The database, with its entries lifted to elements mod q
+    db: Matrix,
+    hint: Matrix,
+}
+
+impl SimplePirServer {
+    /// Set up a server for the database of elements mod p, generating its hint.
+    pub fn new(params: SimplePIRParams, db: &Matrix) -> Self {
+        assert_eq!(db.q(), params.p);
+        assert_eq!(db.dimensions(), (params.l, params.m));
+        let db = lift_db(db, params.q);
+        let hint = params.a.left_mul(&db);
+        Self { params, db, hint }
+    }
+
+    pub fn params(&self) -> &SimplePIRParams {
+        &self.params
+    }
+
+    /// The hint which every client downloads during the offline phase
+    pub fn hint(&self) -> &Matrix {
+        &self.hint
+    }
+
+    pub fn answer(&self, query: &SimplePirQuery) -> SimplePirAnswer {
+        self.try_answer(query).unwrap()
+    }
+
+    /// Answer a query from an untrusted client, or reject it if it does not pass
+    /// `validate_query`.
+    pub fn try_answer(&self, query: &SimplePirQuery) -> Result<SimplePirAnswer> {
+        validate_query(&self.params, &query.0)?;
+        let query: Vec<u64> = query.0.iter().map(|e| e.uint).collect();
+        let mut ans = Matrix::zeros(self.params.q, self.db.num_rows(), 1);
+        self.db.mul_vec_into(&query, ans.data_mut());
+        Ok(SimplePirAnswer(ans))
+    }
+
+    /// Answer a batch of queries with a single matrix-matrix product, which reads the database
+    /// once rather than once per query
+    pub fn answer_batch(&self, query: &SimplePirBatchQuery) -> SimplePirBatchAnswer {
+        self.try_answer_batch(query).unwrap()
+    }
+
+    /// Answer a batch of queries from an untrusted client, or reject it if it does not have an
+    /// entry mod q for every column of the database in each query
+    pub fn try_answer_batch(&self, query: &SimplePirBatchQuery) -> Result<SimplePirBatchAnswer> {
+        check_modulus(self.params.q, query.0.q())?;
+        check_len(self.params.m, query.0.num_rows())?;
+        query.0.check_reduced()?;
+
+        let mut ans = Matrix::zeros(self.params.q, self.params.l, query.0.num_cols());
+        self.db.mul_into(&query.0, &mut ans);
+        Ok(SimplePirBatchAnswer(ans))
+    }
+
+    /// Apply a batch of edits to the database, and return the delta which brings a client's hint
+    /// up to date. Each changed entry adds a multiple of a single row of A to the hint, so only
+    /// those rows of A are expanded and nothing is recomputed.
+    pub fn update_rows(&mut self, edits: &[SimplePirEdit]) -> SimplePirHintDelta {
+        self.try_update_rows(edits).unwrap()
+    }
+
+    /// Apply edits as in `update_rows`, or return an error without changing the database if any
+    /// edit is outside the database or has values which are not mod p.
+    pub fn try_update_rows(&mut self, edits: &[SimplePirEdit]) -> Result<SimplePirHintDelta> {
+        let SimplePIRParams { l, m, p, q, .. } = self.params;
+        for edit in edits {
+            match edit {
+                SimplePirEdit::Cell { row, col, value } => {
+                    check_index(*row, l)?;
+                    check_index(*col, m)?;
+                    check_elements(p, std::slice::from_ref(value))?;
+                }
+                SimplePirEdit::Row { row, values } => {
+                    check_index(*row, l)?;
+                    check_len(m, values.len())?;
+                    check_elements(p, values)?;
+                }
+            }
+        }
+
+        let modulus = Modulus::new(q);
+        let mut delta = SimplePirHintDelta { rows: Vec::new(), cols: Vec::new(), diffs: Vec::new() };
+        let mut set = |row: usize, col: usize, value: &Element| {
+            let new = lift(&modulus, p, value.uint);
+            let diff = modulus.sub(new, self.db[row][col]);
+            self.db[row][col] = new;
+            if diff != 0 {
+                delta.rows.push(row);
+                delta.cols.push(col);
+                delta.diffs.push(Element::from(q, diff));
+            }
+        };
+        for edit in edits {
+            match edit {
+                SimplePirEdit::Cell { row, col, value } => set(*row, *col, value),
+                SimplePirEdit::Row { row, values } => {
+                    for (col, value) in values.iter().enumerate() {
+                        set(*row, col, value);
+                    }
+                }
+            }
+        }
+
+        apply_diffs(&self.params.a, &mut self.hint, &delta);
+        Ok(delta)
+    }
+
+    /// Append rows of elements mod p to the database, each with an entry for every column. A is
+    /// unchanged, so only the new rows of the hint are computed.
+    pub fn append_rows(&mut self, rows: &Matrix) -> SimplePirGrowth {
+        self.try_append_rows(rows).unwrap()
+    }
+
+    /// Append rows as in `append_rows`, or return an error if there are none or they do not match
+    /// the database
+    pub fn try_append_rows(&mut self, rows: &Matrix) -> Result<SimplePirGrowth> {
+        check_modulus(self.params.p, rows.q())?;
+        check_len(self.params.m, rows.num_cols())?;
+        check_growth(self.params.l, rows.num_rows())?;
+        rows.check_reduced()?;
+
+        let rows = lift_db(rows, self.params.q);
+        let hint_rows = self.params.a.left_mul(&rows);
+        self.db.append_rows(&rows);
+        self.hint.append_rows(&hint_rows);
+        self.params.l += rows.num_rows();
+        Ok(SimplePirGrowth::Rows(hint_rows))
+    }
+
+    /// Append columns of elements mod p to the database, given as a matrix with a row for each
+    /// row of the database. A is extended from its seed, and only the product of the new columns
+    /// with the new part of A is computed. Panics if the larger database makes answers fail to
+    /// decrypt with probability above both DEFAULT_MAX_FAILURE and the current probability.
+    pub fn append_cols(&mut self, cols: &Matrix) -> SimplePirGrowth {
+        self.try_append_cols(cols).unwrap()
+    }
+
+    /// Append columns as in `append_cols`, or return an error if there are none, they do not match
+    /// the database or they add too much noise
+    pub fn try_append_cols(&mut self, cols: &Matrix) -> Result<SimplePirGrowth> {
+        let SimplePIRParams { l, m, q, p, .. } = self.params;
+        check_modulus(p, cols.q())?;
+        check_len(l, cols.num_rows())?;
+        let new_m = check_col_growth(&self.params, cols.num_cols())?;
+        cols.check_reduced()?;
+
+        let a = self.params.a.with_rows(new_m);
+        let new_a = a.expand_rows(&(m..new_m).collect::<Vec<_>>());
+        let cols = lift_db(cols, q);
+        let hint_diff = cols.clone() * new_a;
+        self.db.append_cols(&cols);
+        self.hint += hint_diff.clone();
+        self.params.a = a;
+        self.params.m = new_m;
+        Ok(SimplePirGrowth::Cols { cols: cols.num_cols(), hint_diff })
+    }
+}
+
+/// The number of rows or columns after growing by `count` more, or an error if `count` is zero or
+/// too large to count
+fn check_growth(len: usize, count: usize) -> Result<usize> {
+    len.checked_add(count).filter(|_| count > 0).ok_or(Error::InvalidGrowth(count))
+}
+
+/// The number of columns after appending `cols` more, or an error if `check_growth` fails or
+/// answers would then fail to decrypt with probability above both DEFAULT_MAX_FAILURE and the
+/// current probability
+fn check_col_growth(params: &SimplePIRParams, cols: usize) -> Result<usize> {
+    let SimplePIRParams { m, q, p, std_dev, .. } = *params;
+    let new_m = check_growth(m, cols)?;
+    let max_failure = DEFAULT_MAX_FAILURE.max(failure_probability(params));
+    if failure_probability_with(new_m, q, p, std_dev) > max_failure {
+        return Err(Error::FailureProbabilityTooHigh);
+    }
+    Ok(new_m)
+}
+
+/// The client side of SimplePIR. It holds the hint downloaded from the server, creates queries
+/// with fresh secrets and decodes the answers to them.
+pub struct SimplePirClient {
+    params: SimplePIRParams,
+    hint: Matrix,
+}
+
+impl SimplePirClient {
+    pub fn new(params: SimplePIRParams, hint: Matrix) -> Self {
+        assert_eq!(hint.dimensions(), (params.l, params.n));
+        Self { params, hint }
+    }
+
+    pub fn params(&self) -> &SimplePIRParams {
+        &self.params
+    }
+
+    /// Extend the hint and parameters after the server's database grew, so that queries can be
+    /// made at its new size
+    pub fn apply_growth(&mut self, growth: &SimplePirGrowth) {
+        self.try_apply_growth(growth).unwrap()
+    }
+
+    /// Apply growth as in `apply_growth`, or return an error without changing the hint if it does
+    /// not match the parameters, or would grow the database by nothing or by more than the server
+    /// could have
+    pub fn try_apply_growth(&mut self, growth: &SimplePirGrowth) -> Result<()> {
+        let q = self.params.q;
+        match growth {
+            SimplePirGrowth::Rows(hint_rows) => {
+                check_modulus(q, hint_rows.q())?;
+                check_len(self.params.n, hint_rows.num_cols())?;
+                let new_l = check_growth(self.params.l, hint_rows.num_rows())?;
+                hint_rows.check_reduced()?;
+                self.hint.append_rows(hint_rows);
+                self.params.l = new_l;
+            }
+            SimplePirGrowth::Cols { cols, hint_diff } => {
+                check_modulus(q, hint_diff.q())?;
+                check_dimensions(self.hint.dimensions(), hint_diff.dimensions())?;
+                let new_m = check_col_growth(&self.params, *cols)?;
+                hint_diff.check_reduced()?;
+                self.hint += hint_diff.clone();
+                self.params.m = new_m;
+                self.params.a = self.params.a.with_rows(new_m);
+            }
+        }
+        Ok(())
+    }
+
+    /// Bring the hint up to date after the server's database was edited
+    pub fn apply_hint_delta(&mut self, delta: &SimplePirHintDelta) {
+        self.try_apply_hint_delta(delta).unwrap()
+    }
+
+    /// Apply a delta as in `apply_hint_delta`, or return an error without changing the hint if
+    /// the delta does not match the parameters.
+    pub fn try_apply_hint_delta(&mut self, delta: &SimplePirHintDelta) -> Result<()> {
+        check_len(delta.rows.len(), delta.cols.len())?;
+        check_len(delta.rows.len(), delta.diffs.len())?;
+        for (row, col) in delta.rows.iter().zip(&delta.cols) {
+            check_index(*row, self.params.l)?;
+            check_index(*col, self.params.m)?;
+        }
+        check_elements(self.params.q, &delta.diffs)?;
+
+        apply_diffs(&self.params.a, &mut self.hint, delta);
+        Ok(())
+    }
+
+    /// Create a query for the item at db[row][col]. The answer to the query reveals the whole of
+    /// column `col`. The returned state must be kept to recover the answer.
+    pub fn query(&self, row: usize, col: usize) -> (SimplePirQuery, SimplePirQueryState) {
+        self.query_with_rng(&mut StdRng::from_entropy(), row, col)
+    }
+
+    /// Create a query as in `query`, drawing the secret and errors from `rng`.
+    pub fn query_with_rng<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        row: usize,
+        col: usize,
+    ) -> (SimplePirQuery, SimplePirQueryState) {
+        assert!(row < self.params.l);
+        let s = gen_secret_with_rng(rng, self.params.q, self.params.n);
+        let q = query_with_rng(rng, &self.params, col, &s);
+        (SimplePirQuery(q), SimplePirQueryState { row, col, s })
+    }
+
+    /// Create a query as in `query`, or an error if the item is outside the database
+    pub fn try_query(&self, row: usize, col: usize) -> Result<(SimplePirQuery, SimplePirQueryState)> {
+        check_index(row, self.params.l)?;
+        let mut rng = StdRng::from_entropy();
+        let s = gen_secret_with_rng(&mut rng, self.params.q, self.params.n);
+        let q = try_query_with_rng(&mut rng, &self.params, col, &s)?;
+        Ok((SimplePirQuery(q), SimplePirQueryState { row, col, s }))
+    }
+
+    /// Recover the item which was queried
+    pub fn recover(&self, state: &SimplePirQueryState, answer: &SimplePirAnswer) -> Element {
+        recover(&self.params, &state.s, state.row, &self.hint, &answer.0)
+    }
+
+    /// Recover the item which was queried, or an error if the answer is malformed
+    pub fn try_recover(&self, state: &SimplePirQueryState, answer: &SimplePirAnswer) -> Result<Element> {
+        try_recover(&self.params, &state.s, state.row, &self.hint, &answer.0)
+    }
+
+    /// Recover every item in the column which was queried
+    pub fn recover_col(&self, state: &SimplePirQueryState, answer: &SimplePirAnswer) -> Vec<Element> {
+        recover_col(&self.params, &state.s, &self.hint, &answer.0)
+    }
+
+    /// Recover every item in the column which was queried, or an error if the answer is malformed
+    pub fn try_recover_col(&self, state: &SimplePirQueryState, answer: &SimplePirAnswer) -> Result<Vec<Element>> {
+        try_recover_col(&self.params, &state.s, &self.hint, &answer.0)
+    }
+
+    /// Create a batch of queries for the items at db[row][col] for each (row, col), which the
+    /// server answers in a single pass over the database. Each query has its own secret.
+    pub fn query_batch(&self, indices: &[(usize, usize)]) -> (SimplePirBatchQuery, SimplePirBatchState) {
+        self.query_batch_with_rng(&mut StdRng::from_entropy(), indices)
+    }
+
+    pub fn query_batch_with_rng<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        indices: &[(usize, usize)],
+    ) -> (SimplePirBatchQuery, SimplePirBatchState) {
+        self.try_query_batch_with_rng(rng, indices).unwrap()
+    }
+
+    /// Create a batch of queries as in `query_batch`, or an error if any item is outside the
+    /// database
+    pub fn try_query_batch(&self, indices: &[(usize, usize)]) -> Result<(SimplePirBatchQuery, SimplePirBatchState)> {
+        self.try_query_batch_with_rng(&mut StdRng::from_entropy(), indices)
+    }
+
+    fn try_query_batch_with_rng<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        indices: &[(usize, usize)],
+    ) -> Result<(SimplePirBatchQuery, SimplePirBatchState)> {
+        let mut batch = Matrix::zeros(self.params.q, self.params.m, indices.len());
+        let mut queries = Vec::with_capacity(indices.len());
+        for (t, (row, col)) in indices.iter().enumerate() {
+            check_index(*row, self.params.l)?;
+            let s = gen_secret_with_rng(rng, self.params.q, self.params.n);
+            let q = try_query_with_rng(rng, &self.params, *col, &s)?;
+            for (k, e) in q.into_iter().enumerate() {
+                batch[k][t] = e.uint;
+            }
+            queries.push(SimplePirQueryState { row: *row, col: *col, s });
+        }
+        Ok((SimplePirBatchQuery(batch), SimplePirBatchState { queries }))
+    }
+
+    /// Recover the items which were queried, in the order they were queried
+    pub fn recover_batch(&self, state: &SimplePirBatchState, answer: &SimplePirBatchAnswer) -> Vec<Element> {
+        self.try_recover_batch(state, answer).unwrap()
+    }
+
+    /// Recover the items as in `recover_batch`, or an error if the answer is malformed
+    pub fn try_recover_batch(&self, state: &SimplePirBatchState, answer: &SimplePirBatchAnswer) -> Result<Vec<Element>> {
+        let SimplePIRParams { l, n, q, p, .. } = self.params;
+        check_modulus(q, answer.0.q())?;
+        check_dimensions((l, state.queries.len()), answer.0.dimensions())?;
+        answer.0.check_reduced()?;
+
+        // Only the row of the hint for the queried row is needed to decrypt each answer
+        let modulus = Modulus::new(q);
+        let items = state.queries.iter().enumerate().map(|(t, query)| {
+            let mut x = answer.0[query.row][t];
+            for k in 0..n {
+                x = modulus.sub(x, modulus.mul(self.hint[query.row][k], query.s[k].uint));
+            }
+            uncentre(p, modulus.round_to(p, x))
+        });
+        Ok(items.collect())
+    }
+
+    /// Recover the whole column of the database which each query of a batch selected, in the order
+    /// they were queried
+    pub fn recover_batch_cols(&self, state: &SimplePirBatchState, answer: &SimplePirBatchAnswer) -> Vec<Vec<Element>> {
+        self.try_recover_batch_cols(state, answer).unwrap()
+    }
+
+    /// Recover the columns as in `recover_batch_cols`, or an error if the answer is malformed
+    pub fn try_recover_batch_cols(
+        &self,
+        state: &SimplePirBatchState,
+        answer: &SimplePirBatchAnswer,
+    ) -> Result<Vec<Vec<Element>>> {
+        let SimplePIRParams { l, q, .. } = self.params;
+        check_modulus(q, answer.0.q())?;
+        check_dimensions((l, state.queries.len()), answer.0.dimensions())?;
+        answer.0.check_reduced()?;
+
+        let cols = state.queries.iter().enumerate().map(|(t, query)| {
+            let column = Matrix::from_raw(q, l, 1, (0..l).map(|i| answer.0[i][t]).collect());
+            try_recover_col(&self.params, &query.s, &self.hint, &column)
+        });
+        cols.collect()
+    }
+
+    /// Create a query for record `idx` of a database built by `gen_db_from_records`
+    pub fn query_record(&self, layout: &RecordLayout, idx: usize) -> (SimplePirQuery, SimplePirQueryState) {
+        self.query_record_with_rng(&mut StdRng::from_entropy(), layout, idx)
+    }
+
+    pub fn query_record_with_rng<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        layout: &RecordLayout,
+        idx: usize,
+    ) -> (SimplePirQuery, SimplePirQueryState) {
+        let (row, col) = layout.position(idx);
+        self.query_with_rng(rng, row, col)
+    }
+
+    /// Recover the bytes of the record which was queried with `query_record`
+    pub fn recover_record(
+        &self,
+        layout: &RecordLayout,
+        state: &SimplePirQueryState,
+        answer: &SimplePirAnswer,
+    ) -> Vec<u8> {
+        self.try_recover_record(layout, state, answer).unwrap()
+    }
+
+    /// Recover a record as in `recover_record`, or an error if the answer is malformed, the
+    /// layout does not match the params or the record would run past the end of the column
+    pub fn try_recover_record(
+        &self,
+        layout: &RecordLayout,
+        state: &SimplePirQueryState,
+        answer: &SimplePirAnswer,
+    ) -> Result<Vec<u8>> {
+        layout.check(self.params.p, self.params.l)?;
+        let col = self.try_recover_col(state, answer)?;
+        let end = state.row + layout.digits_per_record;
+        check_index(end - 1, col.len())?;
+        let digits: Vec<u64> = col[state.row..end]
+            .iter()
+            .map(|e| e.uint)
+            .collect();
+        Ok(layout.decode(&digits))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::regev::gen_secret;
+    use crate::testing::gen_records;
     use super::*;
 
-    fn test_simplepir_impl(desired_col: usize, desired_row: usize) {
-        let params = gen_params();
-        let db = gen_db(&params);
+    fn test_simplepir_impl(params: &SimplePIRParams, desired_row: usize, desired_col: usize) {
+        let db = gen_db(params);
 
-        let db_item = &db[desired_col][desired_row];
+        let db_item = &db.get(desired_row, desired_col);
 
         let secret = gen_secret(params.q, params.n);
-        let hint = gen_hint(&params, &db);
+        let hint = gen_hint(params, &db);
 
-        let query = query(&params, desired_row, &secret);
+        let query = query(params, desired_col, &secret);
         let answer = answer(&query, &db);
-        let recovered_item = recover(&params, &secret, desired_col, &hint, &answer);
+        let recovered_item = recover(params, &secret, desired_row, &hint, &answer);
         assert_eq!(recovered_item, *db_item);
 
-        let recovered_row = recover_row(&params, &secret, &hint, &answer);
-        assert_eq!(recovered_row, db.rotated()[desired_row]);
+        let recovered_col = recover_col(params, &secret, &hint, &answer);
+        assert_eq!(recovered_col, db.rotated().get_row(desired_col));
     }
 
     #[test]
     pub fn test_simplepir() {
+        let params = gen_params();
         for i in 0..8 {
             for j in 0..8 {
-                test_simplepir_impl(i, j);
+                test_simplepir_impl(&params, i, j);
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_simplepir_power_of_two_q() {
+        let params = gen_params_with_q(1 << 32);
+        for i in 0..8 {
+            for j in 0..8 {
+                test_simplepir_impl(&params, i, j);
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_client_server() {
+        let params = gen_params();
+        let db = gen_db(&params);
+
+        let server = SimplePirServer::new(params, &db);
+        let client = SimplePirClient::new(server.params().clone(), server.hint().clone());
+
+        for i in 0..8 {
+            for j in 0..8 {
+                let (query, state) = client.query(i, j);
+                let answer = server.answer(&query);
+                assert_eq!(state.index(), (i, j));
+                assert_eq!(client.recover(&state, &answer), db.get(i, j));
+                assert_eq!(client.recover_col(&state, &answer), db.clone().rotated().get_row(j));
+            }
+        }
+    }
+
+    fn test_records_impl(params: SimplePIRParams, num_records: usize, record_size: usize) {
+        let records = gen_records(num_records, record_size);
+        let (db, layout) = gen_db_from_records(&params, &records);
+
+        let server = SimplePirServer::new(params, &db);
+        let client = SimplePirClient::new(server.params().clone(), server.hint().clone());
+
+        for (i, record) in records.iter().enumerate() {
+            let (query, state) = client.query_record(&layout, i);
+            let answer = server.answer(&query);
+            assert_eq!(client.recover_record(&layout, &state, &answer), *record);
+        }
+
+        // Layouts for other params, and records which would run past the end of the column
+        let (query, state) = client.query_record(&layout, 0);
+        let answer = server.answer(&query);
+        let other = RecordLayout { bits_per_digit: layout.bits_per_digit + 1, ..layout };
+        assert!(client.try_recover_record(&other, &state, &answer).is_err());
+        let l = client.params().l;
+        let (query, state) = client.query(l - 1, 0);
+        let answer = server.answer(&query);
+        let expected = Error::IndexOutOfRange { index: l + layout.digits_per_record - 2, len: l };
+        assert_eq!(client.try_recover_record(&layout, &state, &answer), Err(expected));
+    }
+
+    #[test]
+    pub fn test_records() {
+        // One bit per entry: each 2-byte record fills half of a column
+        test_records_impl(gen_params_with(32, 32, 64, 1 << 32, 2, 6.4), 50, 2);
+        // One byte per entry
+        test_records_impl(gen_params_with(16, 16, 64, 1 << 32, 256, 6.4), 40, 5);
+        // 9 bits per entry, so records do not line up with entries
+        test_records_impl(gen_params_with(16, 16, 64, 1 << 32, 991, 6.4), 30, 3);
+    }
+
+    #[test]
+    pub fn test_simplepir_rectangular() {
+        for (l, m) in [(3, 17), (16, 5), (1, 9)] {
+            let params = gen_params_with(l, m, 64, 1 << 32, 2, 6.4);
+            for i in 0..l {
+                for j in 0..m {
+                    test_simplepir_impl(&params, i, j);
+                }
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_secure_params() {
+        let params = gen_secure_params(128, 6, 10).unwrap();
+        assert_eq!((params.n, params.q), (1024, 1 << 32));
+        assert_eq!(params.a.dimensions(), (10, 1024));
+
+        for (i, j) in [(0, 0), (5, 9), (3, 4)] {
+            test_simplepir_impl(&params, i, j);
+        }
+
+        assert!(gen_secure_params(1024, 6, 10).is_none());
+    }
+
+    #[test]
+    pub fn test_try_variants() {
+        use crate::error::Error;
+
+        let params = gen_params();
+        let db = gen_db(&params);
+        let secret = gen_secret(params.q, params.n);
+        let hint = gen_hint(&params, &db);
+
+        assert_eq!(
+            try_query(&params, params.m, &secret),
+            Err(Error::IndexOutOfRange { index: params.m, len: params.m }),
+        );
+        assert_eq!(
+            try_query(&params, 0, &secret[1..]),
+            Err(Error::LengthMismatch { expected: params.n, found: params.n - 1 }),
+        );
+
+        let query = try_query(&params, 2, &secret).unwrap();
+        assert_eq!(
+            try_answer(&query[1..], &db),
+            Err(Error::LengthMismatch { expected: params.m, found: params.m - 1 }),
+        );
+        let mut mixed = query.clone();
+        mixed[3] = Element::from(params.q + 1, 0);
+        assert_eq!(
+            try_answer(&mixed, &db),
+            Err(Error::ModulusMismatch { expected: params.q, found: params.q + 1 }),
+        );
+
+        let ans = try_answer(&query, &db).unwrap();
+        assert_eq!(
+            try_recover(&params, &secret, params.l, &hint, &ans),
+            Err(Error::IndexOutOfRange { index: params.l, len: params.l }),
+        );
+        assert_eq!(
+            try_recover(&params, &secret, 0, &hint, &Matrix::zeros(params.q, 2, 1)),
+            Err(Error::DimensionMismatch { expected: (params.l, 1), found: (2, 1) }),
+        );
+        assert_eq!(try_recover(&params, &secret, 5, &hint, &ans), Ok(db.get(5, 2)));
+
+        let client = SimplePirClient::new(params.clone(), hint);
+        assert!(client.try_query(params.l, 0).is_err());
+        let (_, state) = client.try_query(1, 1).unwrap();
+        assert!(client.try_recover(&state, &SimplePirAnswer(Matrix::zeros(params.q, 1, 1))).is_err());
+
+        // Every way of recovering rejects entries which are not reduced mod q
+        let unreduced = Error::ValueOutOfRange { value: params.q, q: params.q };
+        let mut ans = Matrix::zeros(params.q, params.l, 1);
+        ans.data_mut()[params.l - 1] = params.q;
+        let ans = SimplePirAnswer(ans);
+        assert_eq!(client.try_recover(&state, &ans).unwrap_err(), unreduced);
+        assert_eq!(client.try_recover_col(&state, &ans).unwrap_err(), unreduced);
+        let (_, state) = client.query_batch(&[(0, 0), (1, 1)]);
+        let mut ans = Matrix::zeros(params.q, params.l, 2);
+        ans.data_mut()[1] = params.q;
+        let ans = SimplePirBatchAnswer(ans);
+        assert_eq!(client.try_recover_batch(&state, &ans).unwrap_err(), unreduced);
+        assert_eq!(client.try_recover_batch_cols(&state, &ans).unwrap_err(), unreduced);
+    }
+
+    #[test]
+    pub fn test_validate_query() {
+        use crate::error::Error;
+
+        let params = gen_params();
+        let q = params.q;
+        let db = gen_db(&params);
+        let server = SimplePirServer::new(params.clone(), &db);
+        let client = SimplePirClient::new(params.clone(), server.hint().clone());
+        let (query, state) = client.query(1, 2);
+
+        let reject = |query: Vec<Element>| server.try_answer(&SimplePirQuery(query)).unwrap_err();
+
+        assert_eq!(reject(vec![]), Error::LengthMismatch { expected: params.m, found: 0 });
+        let mut mixed = query.0.clone();
+        mixed[params.m - 1] = Element::from(params.p, 1);
+        assert_eq!(reject(mixed), Error::ModulusMismatch { expected: q, found: params.p });
+        // Every entry has modulus q, but the moduli may not have been checked when it was built
+        let mut unreduced = query.0.clone();
+        unreduced[0] = Element { q, uint: q };
+        assert_eq!(reject(unreduced), Error::ValueOutOfRange { value: q, q });
+
+        // A valid query is still answered
+        let answer = server.try_answer(&query).unwrap();
+        assert_eq!(client.recover(&state, &answer), db.get(1, 2));
+    }
+
+    #[test]
+    pub fn test_fuzz_queries() {
+        let params = gen_params();
+        let db = gen_db(&params);
+        let server = SimplePirServer::new(params.clone(), &db);
+        let mut rng = StdRng::seed_from_u64(15);
+
+        for _ in 0..2000 {
+            // Random lengths around m, with a random modulus and random values for each entry,
+            // which are sometimes the right modulus or left unreduced
+            let len = rng.next_u64() as usize % (2 * params.m + 1);
+            let query: Vec<Element> = (0..len).map(|_| {
+                let q = match rng.next_u64() % 4 {
+                    0 => rng.next_u64() % (1 << 40) + 1,
+                    _ => params.q,
+                };
+                let uint = match rng.next_u64() % 8 {
+                    0 => rng.next_u64(),
+                    _ => rng.next_u64() % q,
+                };
+                Element { q, uint }
+            }).collect();
+
+            let valid = len == params.m && query.iter().all(|e| e.q == params.q && e.uint < params.q);
+            let answer = server.try_answer(&SimplePirQuery(query));
+            assert_eq!(answer.is_ok(), valid);
+        }
+
+        // Random bytes never decode to a query which crashes the server
+        for _ in 0..2000 {
+            let mut bytes = vec![0u8; rng.next_u64() as usize % 200];
+            rng.fill_bytes(&mut bytes);
+            if rng.next_u64() % 2 < 1 && bytes.len() >= 2 {
+                // A valid header, so that the body is parsed
+                bytes[0] = crate::encoding::VERSION;
+                bytes[1] = <SimplePirQuery as crate::encoding::Encode>::TAG;
+            }
+            if rng.next_u64() % 4 < 1 && bytes.len() >= 18 {
+                // A degenerate modulus, with a length which could not be allocated
+                bytes[2..10].copy_from_slice(&(rng.next_u64() % 2).to_le_bytes());
+                bytes[10..18].copy_from_slice(&(rng.next_u64() | 1 << 60).to_le_bytes());
+            }
+            if let Ok(query) = <SimplePirQuery as crate::encoding::Encode>::decode(&bytes) {
+                let _ = server.try_answer(&query);
             }
         }
     }
 
-    fn test_simplepir_updates_impl(desired_col: usize, desired_row: usize) {
+    #[test]
+    pub fn test_seeded_rng() {
+        let gen = |seed: u64| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let params = gen_params_with_rng(&mut rng, 8, 8, 64, 1 << 32, 2, 6.4);
+            let db = gen_db_with_rng(&mut rng, &params);
+            let client = SimplePirClient::new(params.clone(), gen_hint(&params, &db));
+            let (query, state) = client.query_with_rng(&mut rng, 3, 5);
+            (params, db, query.0, state.s)
+        };
+
+        // The same seed reproduces every random value
+        assert_eq!(gen(1), gen(1));
+        assert_ne!(gen(1).0, gen(2).0);
+    }
+
+    #[test]
+    pub fn test_failure_probability() {
+        let params = gen_secure_params(128, 6, 10).unwrap();
+        assert!(failure_probability(&params) <= DEFAULT_MAX_FAILURE);
+
+        // The paper's parameters for 2^13 columns, with one more bit of plaintext
+        assert!(gen_params_with_max_failure(6, 1 << 13, 16, 1 << 32, 991, 6.4, DEFAULT_MAX_FAILURE).is_some());
+        assert!(gen_params_with_max_failure(6, 1 << 13, 16, 1 << 32, 1982, 6.4, DEFAULT_MAX_FAILURE).is_none());
+        assert!(gen_params_with_max_failure(6, 1 << 13, 16, 1 << 32, 1982, 6.4, 0.5).is_some());
+
+        // A seeded rng gives the same A every time
+        let gen = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            gen_params_with_max_failure_with_rng(&mut rng, 6, 10, 16, 1 << 32, 991, 6.4, DEFAULT_MAX_FAILURE).unwrap()
+        };
+        assert_eq!(gen(1), gen(1));
+        assert_ne!(gen(1), gen(2));
+    }
+
+    #[test]
+    pub fn test_plan_shape() {
+        // 1-byte records, one per entry, in a square database
+        let shape = plan_shape(1 << 20, 1, 256, 1024, 1 << 32);
+        assert_eq!((shape.l, shape.m), (1 << 10, 1 << 10));
+        assert_eq!(shape.hint_bytes, 4 * 1024 * 1024);
+        assert_eq!(shape.query_bytes, 4 * 1024);
+        assert_eq!(shape.answer_bytes, 4 * 1024);
+
+        // Larger records make the database wider, and the hint larger
+        let shape = plan_shape(1 << 16, 64, 256, 1024, 1 << 32);
+        assert_eq!((shape.l, shape.m), (2048, 2048));
+        assert_eq!(shape.layout.records_per_col, 32);
+
+        // An odd number of records which do not line up with entries
+        let shape = plan_shape(1000, 3, 991, 1024, 1 << 32);
+        assert_eq!(shape.layout.digits_per_record, 3);
+        assert!(shape.l * shape.m >= 3000);
+        assert!(shape.layout.num_cols() <= shape.m);
+        assert!(shape.l + shape.m <= 2 * 55 + 3);
+    }
+
+    #[test]
+    pub fn test_records_planned_shape() {
+        let (num_records, record_size) = (45, 3);
+        let shape = plan_shape(num_records, record_size, 991, 64, 1 << 32);
+        let params = gen_params_with(shape.l, shape.m, 64, 1 << 32, 991, 6.4);
+        test_records_impl(params, num_records, record_size);
+    }
+
+    fn test_simplepir_updates_impl(desired_row: usize, desired_col: usize) {
         let params = gen_params();
         let db = gen_db(&params);
         let hint = gen_hint(&params, &db);
 
         let secret = gen_secret(params.q, params.n);
 
-        let query = query(&params, desired_row, &secret);
+        let query = query(&params, desired_col, &secret);
         let ans = answer(&query, &db);
-        let recovered = recover(&params, &secret, desired_col, &hint, &ans);
+        let recovered = recover(&params, &secret, desired_row, &hint, &ans);
 
-        let db_item = &db[desired_col][desired_row];
+        let db_item = &db.get(desired_row, desired_col);
         assert_eq!(recovered, *db_item);
 
         // Flip all bits of one row
-        let row_to_flip = desired_col;
+        let row_to_flip = desired_row;
         let mut db = db.clone();
 
         let mut updated_row = Vec::with_capacity(params.m);
-        for i in 0..db.num_rows() {
+        for i in 0..db.num_cols() {
             // Flip the bits in the row
-            db[row_to_flip][i] -= Element::from(params.p, 1);
-            updated_row.push(
-                Element::from(params.q, db[row_to_flip][i].uint)
-            );
+            let flipped = db.get(row_to_flip, i) - Element::from(params.p, 1);
+            updated_row.push(flipped.clone());
+            db.set(row_to_flip, i, flipped);
         }
-        db.change_q(params.q);
 
         // Now update the hint
         let mut hint = hint.clone();
 
         // This operation is much more efficient than regenerating the whole hint matrix
-        let updated_hint_row = Matrix::from_col(&updated_row) * params.a.to_owned();
+        let updated_hint_row = params.a.left_mul(&lift_db(&Matrix::from_row(&updated_row), params.q));
 
-        for j in 0..hint.num_rows() {
-            hint[row_to_flip][j] = updated_hint_row[0][j].clone();
+        for j in 0..hint.num_cols() {
+            hint[row_to_flip][j] = updated_hint_row[0][j];
         }
 
         let ans = answer(&query, &db);
-        let recovered = recover(&params, &secret, desired_col, &hint, &ans);
-        let db_item = &db[desired_col][desired_row];
-        assert_eq!(recovered.uint, db_item.uint);
+        let recovered = recover(&params, &secret, desired_row, &hint, &ans);
+        let db_item = &db.get(desired_row, desired_col);
+        assert_eq!(recovered, *db_item);
+    }
+
+    #[test]
+    pub fn test_update_rows() {
+        use crate::error::Error;
+
+        let params = gen_params();
+        let mut db = gen_db(&params);
+        let mut server = SimplePirServer::new(params.clone(), &db);
+        let mut client = SimplePirClient::new(params.clone(), server.hint().clone());
+
+        let p = params.p;
+        let edits = vec![
+            SimplePirEdit::Cell { row: 2, col: 5, value: Element::from(p, 1) - db.get(2, 5) },
+            SimplePirEdit::Cell { row: 6, col: 0, value: Element::from(p, 1) - db.get(6, 0) },
+            SimplePirEdit::Cell { row: 2, col: 1, value: Element::from(p, 1) - db.get(2, 1) },
+        ];
+        let mut changed = Vec::new();
+        for edit in &edits {
+            if let SimplePirEdit::Cell { row, col, value } = edit {
+                if db.get(*row, *col) != *value {
+                    changed.push((*row, *col));
+                }
+                db.set(*row, *col, value.clone());
+            }
+        }
+        // Only the entries which changed are sent, each with the row of A it multiplies
+        let delta = server.update_rows(&edits);
+        assert_eq!(delta.rows.iter().copied().zip(delta.cols.iter().copied()).collect::<Vec<_>>(), changed);
+        client.apply_hint_delta(&delta);
+        assert_eq!(client.hint, *SimplePirServer::new(params.clone(), &db).hint());
+
+        // A whole row only changes the same row of the hint
+        let values: Vec<Element> = (0..params.m).map(|j| Element::from(p, j as u64 % p)).collect();
+        let changed = values.iter().enumerate().filter(|(col, value)| db.get(3, *col) != **value).count();
+        for (col, value) in values.iter().enumerate() {
+            db.set(3, col, value.clone());
+        }
+        let delta = server.update_rows(&[SimplePirEdit::Row { row: 3, values: values.clone() }]);
+        assert_eq!(delta.rows, vec![3; changed]);
+        client.apply_hint_delta(&delta);
+        assert_eq!(client.hint, *SimplePirServer::new(params.clone(), &db).hint());
+
+        // Writing the same values again changes nothing
+        let delta = server.update_rows(&[SimplePirEdit::Row { row: 3, values }]);
+        assert!(delta.rows.is_empty() && delta.diffs.is_empty());
+
+        for i in 0..params.l {
+            for j in 0..params.m {
+                let (query, state) = client.query(i, j);
+                assert_eq!(client.recover(&state, &server.answer(&query)), db.get(i, j));
+            }
+        }
+
+        // Invalid edits leave the database unchanged
+        let hint = server.hint().clone();
+        let bad = [
+            SimplePirEdit::Cell { row: 0, col: 0, value: Element::from(p, 0) },
+            SimplePirEdit::Cell { row: params.l, col: 0, value: Element::from(p, 0) },
+        ];
+        assert_eq!(server.try_update_rows(&bad), Err(Error::IndexOutOfRange { index: params.l, len: params.l }));
+        let bad = [SimplePirEdit::Row { row: 0, values: vec![Element::from(p, 0)] }];
+        assert!(server.try_update_rows(&bad).is_err());
+        let bad = [SimplePirEdit::Cell { row: 0, col: 0, value: Element::from(params.q, 0) }];
+        assert!(server.try_update_rows(&bad).is_err());
+        assert_eq!(server.hint(), &hint);
+
+        // Invalid deltas leave the hint unchanged
+        let hint = client.hint.clone();
+        let diff = Element::from(params.q, 1);
+        let bad = SimplePirHintDelta { rows: vec![params.l], cols: vec![0], diffs: vec![diff.clone()] };
+        assert!(client.try_apply_hint_delta(&bad).is_err());
+        let bad = SimplePirHintDelta { rows: vec![0], cols: vec![params.m], diffs: vec![diff.clone()] };
+        assert!(client.try_apply_hint_delta(&bad).is_err());
+        let bad = SimplePirHintDelta { rows: vec![0, 1], cols: vec![0, 1], diffs: vec![diff] };
+        assert!(client.try_apply_hint_delta(&bad).is_err());
+        let bad = SimplePirHintDelta { rows: vec![0], cols: vec![0], diffs: vec![Element::from(p, 1)] };
+        assert!(client.try_apply_hint_delta(&bad).is_err());
+        assert_eq!(client.hint, hint);
+    }
+
+    #[test]
+    pub fn test_batch() {
+        let params = gen_params_with(8, 16, 64, 1 << 32, 991, 6.4);
+        let db = gen_db(&params);
+        let server = SimplePirServer::new(params.clone(), &db);
+        let client = SimplePirClient::new(params.clone(), server.hint().clone());
+
+        // Including repeats, and several items of the same column
+        let indices = [(0, 0), (7, 15), (3, 9), (3, 9), (5, 9), (1, 2)];
+        let (query, state) = client.query_batch(&indices);
+        assert_eq!(query.0.dimensions(), (params.m, indices.len()));
+        assert_eq!(state.indices(), indices);
+
+        let answer = server.answer_batch(&query);
+        let items = client.recover_batch(&state, &answer);
+        let expected: Vec<Element> = indices.iter().map(|(row, col)| db.get(*row, *col)).collect();
+        assert_eq!(items, expected);
+
+        let cols = client.recover_batch_cols(&state, &answer);
+        for ((_, col), recovered) in indices.iter().zip(cols) {
+            assert_eq!(recovered, db.clone().rotated().get_row(*col));
+        }
+
+        // A batch of one is answered like a single query
+        let (query, state) = client.query_batch(&[(4, 4)]);
+        let single = server.answer(&SimplePirQuery(query.0.clone().rotated().get_row(0)));
+        assert_eq!(single.0.data(), server.answer_batch(&query).0.data());
+        assert_eq!(client.recover_batch(&state, &server.answer_batch(&query)), vec![db.get(4, 4)]);
+
+        // Empty batches, items outside the database and malformed queries
+        let (query, state) = client.query_batch(&[]);
+        assert!(client.recover_batch(&state, &server.answer_batch(&query)).is_empty());
+        assert!(client.try_query_batch(&[(0, 0), (params.l, 0)]).is_err());
+        assert!(client.try_query_batch(&[(0, params.m)]).is_err());
+        assert!(server.try_answer_batch(&SimplePirBatchQuery(Matrix::zeros(params.q, params.l, 2))).is_err());
+        let (_, state) = client.query_batch(&[(0, 0), (1, 1)]);
+        assert!(client.try_recover_batch(&state, &answer).is_err());
+        assert!(client.try_recover_batch_cols(&state, &answer).is_err());
+    }
+
+    #[test]
+    pub fn test_append() {
+        use crate::error::Error;
+
+        let params = gen_params();
+        let mut db = gen_db(&params);
+        let mut server = SimplePirServer::new(params.clone(), &db);
+        let mut client = SimplePirClient::new(params.clone(), server.hint().clone());
+
+        let rows = Matrix::gen_uniform_rand(params.p, 3, params.m);
+        client.apply_growth(&server.append_rows(&rows));
+        db.append_rows(&rows);
+
+        let cols = Matrix::gen_uniform_rand(params.p, params.l + 3, 5);
+        client.apply_growth(&server.append_cols(&cols));
+        db.append_cols(&cols);
+
+        // The client ends up with the same hint as for the whole database at once
+        assert_eq!(db.dimensions(), (params.l + 3, params.m + 5));
+        assert_eq!(client.params(), server.params());
+        let fresh = SimplePirServer::new(server.params().clone(), &db);
+        assert_eq!(client.hint, *fresh.hint());
+
+        for i in 0..db.num_rows() {
+            for j in 0..db.num_cols() {
+                let (query, state) = client.query(i, j);
+                assert_eq!(client.recover(&state, &server.answer(&query)), db.get(i, j));
+            }
+        }
+
+        // Each column adds noise to the answers, so too many are rejected
+        let cols = Matrix::zeros(params.p, db.num_rows(), 1000);
+        assert_eq!(server.try_append_cols(&cols), Err(Error::FailureProbabilityTooHigh));
+        // New rows need an entry for every column, including the appended ones, and new rows of
+        // the hint one for every entry of the secret
+        let rows = Matrix::zeros(params.p, 1, params.m);
+        let expected = Error::LengthMismatch { expected: params.m + 5, found: params.m };
+        assert_eq!(server.try_append_rows(&rows), Err(expected));
+        assert!(client.try_apply_growth(&SimplePirGrowth::Rows(Matrix::zeros(params.q, 1, 1))).is_err());
+
+        // Growth by nothing, or by more columns than the server could have added, is rejected
+        assert_eq!(server.try_append_rows(&Matrix::zeros(params.p, 0, db.num_cols())), Err(Error::InvalidGrowth(0)));
+        assert_eq!(server.try_append_cols(&Matrix::zeros(params.p, db.num_rows(), 0)), Err(Error::InvalidGrowth(0)));
+        let hint = client.hint.clone();
+        let empty = SimplePirGrowth::Rows(Matrix::zeros(params.q, 0, params.n));
+        assert_eq!(client.try_apply_growth(&empty), Err(Error::InvalidGrowth(0)));
+        let hint_diff = Matrix::zeros(params.q, hint.num_rows(), params.n);
+        for (cols, err) in [
+            (0, Error::InvalidGrowth(0)),
+            (usize::MAX, Error::InvalidGrowth(usize::MAX)),
+            (1000, Error::FailureProbabilityTooHigh),
+        ] {
+            let growth = SimplePirGrowth::Cols { cols, hint_diff: hint_diff.clone() };
+            assert_eq!(client.try_apply_growth(&growth), Err(err));
+        }
+        assert_eq!(client.hint, hint);
+        assert_eq!(client.params(), server.params());
     }
 
     #[test]
@@ -0,0 +1,39 @@
+//! Fixtures shared by the tests of several modules
+
+use crate::params::{LweParams, DEFAULT_MAX_FAILURE};
+use crate::simplepir::{self, SimplePIRParams};
+
+/// Small LWE parameters which keep tests fast, far from secure
+pub fn test_lwe() -> LweParams {
+    LweParams { n: 64, q: 1 << 32, p: 991, std_dev: 6.4 }
+}
+
+/// Record i of a test database, whose bytes differ between records and within each record
+pub fn gen_record(i: usize, record_size: usize) -> Vec<u8> {
+    (0..record_size).map(|j| (i * 31 + j * 7) as u8).collect()
+}
+
+pub fn gen_records(num_records: usize, record_size: usize) -> Vec<Vec<u8>> {
+    (0..num_records).map(|i| gen_record(i, record_size)).collect()
+}
+
+/// Check that every item placed in a bucket has that bucket among its candidates, and that the
+/// candidates of each item are distinct buckets
+pub fn check_buckets(buckets: &[Vec<usize>], candidates: impl Fn(usize) -> Vec<usize>) {
+    for (b, bucket) in buckets.iter().enumerate() {
+        for i in bucket {
+            let candidates = candidates(*i);
+            assert!(candidates.contains(&b));
+            for (k, c) in candidates.iter().enumerate() {
+                assert!(*c < buckets.len());
+                assert!(!candidates[..k].contains(c));
+            }
+        }
+    }
+}
+
+/// Check the SimplePIR parameters chosen for 128-bit security
+pub fn check_secure_params(params: &SimplePIRParams) {
+    assert_eq!(params.n, 1024);
+    assert!(simplepir::failure_probability(params) <= DEFAULT_MAX_FAILURE);
+}
@@ -1,21 +1,31 @@
 use crate::element::Element;
+use crate::error::{check_index, Result};
 use crate::matrix::Matrix;
 use crate::regev::{
     Params,
-    gen_error_vec,
-    encrypt,
+    gen_error_vec_with_rng,
+    try_encrypt,
 };
+use rand::{rngs::StdRng, CryptoRng, RngCore, SeedableRng};
 
 /// Generates a database of db_size item where each item is a bit.
 pub fn gen_db(db_size: usize, params: &Params) -> Vec<Element> {
     gen_db_q(db_size, params.p)
 }
 
+pub fn gen_db_with_rng<R: RngCore + CryptoRng>(rng: &mut R, db_size: usize, params: &Params) -> Vec<Element> {
+    gen_db_q_with_rng(rng, db_size, params.p)
+}
+
 /// Generates a database of db_size item where each item is an element mod q.
 pub fn gen_db_q(db_size: usize, q: u64) -> Vec<Element> {
+    gen_db_q_with_rng(&mut StdRng::from_entropy(), db_size, q)
+}
+
+pub fn gen_db_q_with_rng<R: RngCore + CryptoRng>(rng: &mut R, db_size: usize, q: u64) -> Vec<Element> {
     let mut db = Vec::with_capacity(db_size);
     for _ in 0..db_size {
-        let val = Element::gen_uniform_rand(q);
+        let val = Element::gen_uniform_rand_with_rng(rng, q);
         db.push(val);
     }
     db
@@ -24,10 +34,36 @@ pub fn gen_db_q(db_size: usize, q: u64) -> Vec<Element> {
 pub fn query(
     params: &Params,
     idx: usize,
-    s: &Vec<Element>,
+    s: &[Element],
     db_size: usize,
 ) -> Vec<Element> {
-    assert!(idx < db_size);
+    query_with_rng(&mut StdRng::from_entropy(), params, idx, s, db_size)
+}
+
+pub fn query_with_rng<R: RngCore + CryptoRng>(
+    rng: &mut R,
+    params: &Params,
+    idx: usize,
+    s: &[Element],
+    db_size: usize,
+) -> Vec<Element> {
+    try_query_with_rng(rng, params, idx, s, db_size).unwrap()
+}
+
+/// Generate a query as in `query`, or an error if the index is outside the database or the
+/// secret does not match the parameters.
+pub fn try_query(params: &Params, idx: usize, s: &[Element], db_size: usize) -> Result<Vec<Element>> {
+    try_query_with_rng(&mut StdRng::from_entropy(), params, idx, s, db_size)
+}
+
+pub fn try_query_with_rng<R: RngCore + CryptoRng>(
+    rng: &mut R,
+    params: &Params,
+    idx: usize,
+    s: &[Element],
+    db_size: usize,
+) -> Result<Vec<Element>> {
+    check_index(idx, db_size)?;
     let mut query = Vec::with_capacity(db_size);
     for i in 0..db_size {
         let bit = if i == idx {
@@ -35,16 +71,16 @@ pub fn query(
         } else {
             0
         };
-        let e = gen_error_vec(params.q, params.m);
-        let enc = encrypt(
+        let e = gen_error_vec_with_rng(rng, params.q, params.std_dev, params.m);
+        let enc = try_encrypt(
             params,
             s,
             &e,
             &Element::from(params.p, bit)
-        );
+        )?;
         query.push(enc);
     }
-    query
+    Ok(query)
 }
 
 /// The server returns the encrypted result of the query. The result is a single
@@ -120,27 +156,27 @@ pub mod tests {
 
     fn test_pir_impl(
         params: &Params,
-        s: &Vec<Element>,
+        s: &[Element],
     ) {
         let db_size = 50;
         let db = gen_db(db_size, params);
 
         let desired_idx = 24;
-        let query = query(&params, desired_idx, &s, db_size);
+        let query = query(params, desired_idx, s, db_size);
 
         // Test answer_q()
-        let ans = answer_q(&params, &query, &db);
+        let ans = answer_q(params, &query, &db);
 
         let mut p = params.clone();
         p.a = ans.0;
-        let result = decrypt(&p, &s, &ans.1);
+        let result = decrypt(&p, s, &ans.1);
         assert_eq!(result, db[desired_idx]);
 
         // Test answer()
-        let ans = answer(&params, &query, &db);
+        let ans = answer(params, &query, &db);
         let mut p = params.clone();
         p.a = ans.0;
-        let result = decrypt(&p, &s, &ans.1);
+        let result = decrypt(&p, s, &ans.1);
         assert_eq!(result, db[desired_idx]);
     }
 